@@ -0,0 +1,58 @@
+//! The simpleaf pipeline as a library: build a splici index with
+//! [`Pipeline::index`] and quantify samples against it with
+//! [`Pipeline::quant`] (or [`Pipeline::batch`] for a sample sheet). The
+//! `simpleaf` command line tool is a thin wrapper over this API.
+
+#[macro_use]
+extern crate log;
+
+mod pipeline;
+mod simpleaf_commands;
+mod utils;
+
+pub use pipeline::{
+    BatchConfig, BatchResult, FeatureIndexConfig, FeatureIndexResult, IndexConfig, IndexResult,
+    Pipeline, QuantConfig, QuantMetrics, QuantResult,
+};
+pub use utils::af_utils::CellFilterMethod;
+pub use utils::prog_utils::ReqProgs;
+
+// the types of the fields of the configurations and results
+pub use simpleaf_commands::batch::{parse_sample_sheet, SampleEntry};
+pub use simpleaf_commands::guides::{GuideCalling, DEFAULT_MIN_GUIDE_UMIS};
+pub use simpleaf_commands::indexing::IndexResources;
+pub use simpleaf_commands::quant::{
+    MappingMode, MappingOpts, QuantResources, QuantThreads, QuantTimes,
+};
+pub use utils::af_utils::{FeatureType, FilterChoice, Mapper};
+pub use utils::args_utils::{split_extra_args, ExtraArgsFor, QuantExtraArgs};
+pub use utils::compat_utils::CompatCheck;
+pub use utils::exec_utils::{install_cancel_handlers, StepResources};
+pub use utils::prog_utils::ProgInfo;
+pub use utils::resource_utils::ResourceLimits;
+pub use utils::script_utils::ShellScript;
+
+// the other commands
+pub use simpleaf_commands::aggr::{run_aggr, AggrOpts, BarcodeSuffix};
+pub use simpleaf_commands::collapse::{run_collapse, CollapseOpts, CountsSpec, UsaPart};
+pub use simpleaf_commands::hto_demux::{run_hto_demux, HtoDemuxOpts};
+pub use simpleaf_commands::index_info::run_index_info;
+pub use simpleaf_commands::paths::{paths_check, paths_refresh, paths_show};
+pub use utils::prog_utils::{get_required_progs_from_paths, write_required_progs};
+
+/// What the `simpleaf` command line tool needs beyond the API above; not
+/// part of the library API.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::utils::config_utils::*;
+    pub use crate::utils::exec_utils::LogWriter;
+    pub use crate::utils::index_utils::index_mapper;
+
+    use crate::utils::af_utils::*;
+    use anyhow::Result;
+
+    /// The default filtering of `chemistry`, without fetching its permit list.
+    pub fn default_filter(chemistry: &str) -> Result<CellFilterMethod> {
+        get_filter_method(chemistry, false, None)
+    }
+}
@@ -2,37 +2,46 @@ extern crate env_logger;
 #[macro_use]
 extern crate log;
 
-use anyhow::{anyhow, bail, Context, Result};
-use clap::{ArgGroup, Parser, Subcommand};
-use cmd_lib::run_fun;
+use anyhow::bail;
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
 use env_logger::Env;
-use serde_json::json;
-use time::Instant;
+use serde::Serialize;
 
 use std::env;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-mod utils;
-use utils::af_utils::*;
-use utils::prog_utils::*;
+use simpleaf::cli::*;
+use simpleaf::*;
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Subcommand, Serialize)]
+#[serde(rename_all = "kebab-case")]
+// parsed once, so the size of the largest variant does not matter
+#[allow(clippy::large_enum_variant)]
 enum Commands {
-    /// build the splici index
+    /// build the splici index, or the index of a feature reference
     #[clap(arg_required_else_help = true)]
     Index {
         /// reference genome
-        #[clap(short, long, value_parser)]
-        fasta: PathBuf,
+        #[clap(short, long, value_parser, required_unless_present = "feature-ref")]
+        fasta: Option<PathBuf>,
 
         /// reference GTF file
-        #[clap(short, long, value_parser)]
-        gtf: PathBuf,
+        #[clap(short, long, value_parser, required_unless_present = "feature-ref")]
+        gtf: Option<PathBuf>,
 
         /// the target read length the index will be built for
-        #[clap(short, long, value_parser)]
-        rlen: u32,
+        #[clap(short, long, value_parser, required_unless_present = "feature-ref")]
+        rlen: Option<u32>,
+
+        /// 10x Feature Reference CSV file (with `id`, `name`, `read`, `pattern`, `sequence`
+        /// and `feature_type` columns) whose feature barcodes are indexed, with an identity
+        /// transcript to gene map, instead of a splici reference
+        #[clap(
+            long,
+            value_parser,
+            conflicts_with_all = &["fasta", "gtf", "rlen", "spliced", "unspliced", "dedup", "sparse", "pyroe-args"]
+        )]
+        feature_ref: Option<PathBuf>,
 
         /// path to output directory (will be created if it doesn't exist)
         #[clap(short, long, value_parser)]
@@ -54,16 +63,51 @@ enum Commands {
         #[clap(short = 'p', long = "sparse", action)]
         sparse: bool,
 
-        /// number of threads to use when running [default: min(16, num cores)]"
-        #[clap(short, long, default_value_t = 16, value_parser)]
-        threads: u32,
+        /// the program that builds the index and later maps the reads to it
+        #[clap(long, default_value = "salmon", value_parser = clap::builder::PossibleValuesParser::new(["salmon", "piscem"]))]
+        mapper: String,
+
+        /// extra arguments for `pyroe make-splici`, as a single shell-quoted string
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        pyroe_args: Option<String>,
+
+        /// extra arguments for `salmon index`, as a single shell-quoted string
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        salmon_index_args: Option<String>,
+
+        /// number of threads to use when running, limited to the CPUs available to this
+        /// process (including SLURM and cgroup limits) [default: min(16, num cores)]
+        #[clap(short, long, value_parser)]
+        threads: Option<u32>,
+
+        /// write into the output directory even if it is not empty
+        #[clap(long, action)]
+        #[serde(skip)]
+        overwrite: bool,
+
+        /// print the fully resolved configuration of this run (for use with `simpleaf run`)
+        /// and exit without running it
+        #[clap(long, action)]
+        #[serde(skip)]
+        emit_config: bool,
+
+        /// resolve the run without executing anything, and print the external commands it
+        /// would run as a bash script
+        #[clap(long, action)]
+        #[serde(skip)]
+        dry_run: bool,
+
+        /// write the dry-run script to this file instead of printing it
+        #[clap(long, value_parser, requires = "dry-run")]
+        #[serde(skip)]
+        script: Option<PathBuf>,
     },
-    /// quantify a sample
+    /// quantify a sample, or every sample listed in a sample sheet
     #[clap(arg_required_else_help = true)]
     #[clap(group(
             ArgGroup::new("filter")
-            .required(true)
-            .args(&["knee", "unfiltered-pl", "forced-cells", "expect-cells"])
+            .args(&["knee", "unfiltered-pl", "explicit-pl", "forced-cells", "expect-cells", "gex-quant", "tissue-positions"])
+            .conflicts_with("sample-sheet")
             ))]
     Quant {
         /// path to index
@@ -71,16 +115,51 @@ enum Commands {
         index: PathBuf,
 
         /// path to read 1 files
-        #[clap(short = '1', long = "reads1", value_parser)]
+        #[clap(
+            short = '1',
+            long = "reads1",
+            value_parser,
+            conflicts_with = "sample-sheet"
+        )]
         reads1: Vec<PathBuf>,
 
         /// path to read 2 files
-        #[clap(short = '2', long = "reads2", value_parser)]
+        #[clap(
+            short = '2',
+            long = "reads2",
+            value_parser,
+            conflicts_with = "sample-sheet"
+        )]
         reads2: Vec<PathBuf>,
 
-        /// number of threads to use when running [default: min(16, num cores)]"
-        #[clap(short, long, default_value_t = 16, value_parser)]
-        threads: u32,
+        /// tab-separated sample sheet with `sample`, `reads1`, `reads2`, `chemistry` and
+        /// `filter` columns; every sample is quantified into `<output>/<sample>/`. The filter
+        /// is one of default, knee, unfiltered-pl, explicit-pl=<path>, forced-cells=<n> or
+        /// expect-cells=<n>
+        #[clap(long, value_parser)]
+        sample_sheet: Option<PathBuf>,
+
+        /// number of samples from the sample sheet to quantify at once; the threads are
+        /// divided evenly between them [default: max(1, threads / 8)]
+        #[clap(long, value_parser, requires = "sample-sheet")]
+        parallel_samples: Option<usize>,
+
+        /// number of threads to use when running, limited to the CPUs available to this
+        /// process (including SLURM and cgroup limits) [default: min(16, num cores)]
+        #[clap(short, long, value_parser)]
+        threads: Option<u32>,
+
+        /// number of threads for the mapping step [default: --threads]
+        #[clap(long, value_parser)]
+        map_threads: Option<u32>,
+
+        /// number of threads for the collate step [default: --threads]
+        #[clap(long, value_parser)]
+        collate_threads: Option<u32>,
+
+        /// number of threads for the quant step [default: --threads]
+        #[clap(long, value_parser)]
+        quant_threads: Option<u32>,
 
         /// use knee filtering mode
         #[clap(short, long, action)]
@@ -90,8 +169,8 @@ enum Commands {
         #[clap(short, long, action)]
         unfiltered_pl: bool,
 
-        /// use a filtered, explicit permit list
-        #[clap(short, long, value_parser)]
+        /// use a filtered, explicit permit list (short flag -x, as -e is --expect-cells)
+        #[clap(short = 'x', long, value_parser, conflicts_with = "sample-sheet")]
         explicit_pl: Option<PathBuf>,
 
         /// use forced number of cells
@@ -106,9 +185,23 @@ enum Commands {
         #[clap(short, long, value_parser = clap::builder::PossibleValuesParser::new(["cr-like", "cr-like-em", "parsimony", "parsimony-em", "parsimony-gene", "parsimony-gene-em"]))]
         resolution: String,
 
-        /// chemistry
-        #[clap(short, long, value_parser)]
-        chemistry: String,
+        /// chemistry: 10xv2, 10xv3, 10xv3.1, 10x5v2, 10x5v3 (5'), visium, dropseq, indropv2, indropv3,
+        /// celseq2, sciseq3, splitseqv1 or splitseqv2; any other name is passed to salmon alevin
+        /// as a flag (`--<name>`). The two 8 bp barcode reads of indropv3 must be concatenated
+        /// into read 1, ahead of its 6 bp UMI, for a read 1 of at least 22 bp. Without a
+        /// filtering option, the cells of the non-10x chemistries are found with --knee, except
+        /// those of celseq2, which keeps the 96 cells of a plate (--forced-cells 96).
+        /// simpleaf cannot download the 10x5v3 permit list of --unfiltered-pl: gunzip
+        /// lib/python/cellranger/barcodes/3M-5pgex-jan-2023.txt.gz of a Cell Ranger
+        /// installation to $ALEVIN_FRY_HOME/plist/10x_5v3_permit.txt
+        #[clap(
+            short,
+            long,
+            value_parser,
+            required_unless_present = "sample-sheet",
+            conflicts_with = "sample-sheet"
+        )]
+        chemistry: Option<String>,
 
         /// transcript to gene map
         #[clap(short = 'm', long, value_parser)]
@@ -117,6 +210,214 @@ enum Commands {
         /// output directory
         #[clap(short, long, value_parser)]
         output: PathBuf,
+
+        /// the program that maps the reads [default: the mapper that built the index]
+        #[clap(long, value_parser = clap::builder::PossibleValuesParser::new(["salmon", "piscem"]))]
+        mapper: Option<String>,
+
+        /// how salmon maps the reads: `sketch` (pseudoalignment) or `selective-alignment`,
+        /// which scores each mapping
+        #[clap(long, default_value = "sketch", value_parser = clap::builder::PossibleValuesParser::new(["sketch", "selective-alignment"]))]
+        mapping_mode: String,
+
+        /// the minimum score of a mapping, as a fraction of the best possible score of the
+        /// read (selective-alignment only) [default: salmon's]
+        #[clap(long, value_parser)]
+        min_score_fraction: Option<f64>,
+
+        /// allow soft-clipping of the reads (selective-alignment only)
+        #[clap(long, action)]
+        softclip: bool,
+
+        /// allow soft-clipping of the parts of the reads that overhang the targets
+        /// (selective-alignment only)
+        #[clap(long, action)]
+        softclip_overhangs: bool,
+
+        /// extra arguments for `salmon alevin`, as a single shell-quoted string
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        salmon_map_args: Option<String>,
+
+        /// extra arguments for `alevin-fry generate-permit-list`, as a single shell-quoted
+        /// string
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        gpl_args: Option<String>,
+
+        /// extra arguments for `alevin-fry collate`, as a single shell-quoted string
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        collate_args: Option<String>,
+
+        /// extra arguments for `alevin-fry quant`, as a single shell-quoted string
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        fry_quant_args: Option<String>,
+
+        /// the molecules the library captures: gene expression (`gex`), or the antibody-derived
+        /// tags (`adt`), hashtag oligos (`hto`) or CRISPR guides (`crispr`) of an index built
+        /// with `simpleaf index --feature-ref`
+        #[clap(long, default_value = "gex", value_parser = clap::builder::PossibleValuesParser::new(["gex", "adt", "hto", "crispr"]))]
+        feature_type: String,
+
+        /// how guides are assigned to cells with `--feature-type crispr` (written to
+        /// `<output>/guide_calls.tsv`): `mixture` fits a two-component mixture to the UMIs of
+        /// each guide, `umi-threshold` assigns every guide with --min-guide-umis UMIs
+        /// [default: mixture]
+        #[clap(long, value_parser = clap::builder::PossibleValuesParser::new(["mixture", "umi-threshold"]))]
+        guide_calling: Option<String>,
+
+        /// the fewest UMIs of a guide that a cell is assigned it with [default: 3]
+        #[clap(long, value_parser)]
+        min_guide_umis: Option<f64>,
+
+        /// the output directory of the `simpleaf quant` run of the GEX library of the same
+        /// cells; the features are only quantified for its cells, and their counts are also
+        /// written to `<output>/gex_aligned/` with the rows of the GEX matrix
+        #[clap(long, value_parser, conflicts_with = "sample-sheet")]
+        gex_quant: Option<PathBuf>,
+
+        /// two-column file translating the GEX barcode (first column) of each cell to its
+        /// feature barcode (second column), for chemistries where they differ
+        #[clap(long, value_parser, requires = "gex-quant")]
+        bc_translation: Option<PathBuf>,
+
+        /// the `tissue_positions.csv` (or `tissue_positions_list.csv`) of the Visium slide, with
+        /// the chemistry `visium`; the barcodes of all its spots are the permit list, and the
+        /// counts and positions of the spots under the tissue are written to
+        /// `<output>/in_tissue/`
+        #[clap(long, value_parser, conflicts_with = "sample-sheet")]
+        tissue_positions: Option<PathBuf>,
+
+        /// what to do when the index does not suit the configured mapper, the transcript to
+        /// gene map or the length of the reads
+        #[clap(long, default_value = "warn", value_parser = clap::builder::PossibleValuesParser::new(["error", "warn", "off"]))]
+        compat_check: String,
+
+        /// write into the output directory even if it is not empty
+        #[clap(long, action)]
+        #[serde(skip)]
+        overwrite: bool,
+
+        /// print the fully resolved configuration of this run (for use with `simpleaf run`)
+        /// and exit without running it
+        #[clap(long, action)]
+        #[serde(skip)]
+        emit_config: bool,
+
+        /// resolve the run without executing anything, and print the external commands it
+        /// would run as a bash script (not with --gex-quant, --tissue-positions or
+        /// --feature-type crispr, whose extra steps simpleaf runs itself)
+        #[clap(long, action)]
+        #[serde(skip)]
+        dry_run: bool,
+
+        /// write the dry-run script to this file instead of printing it
+        #[clap(long, value_parser, requires = "dry-run")]
+        #[serde(skip)]
+        script: Option<PathBuf>,
+    },
+    /// describe an index built by `simpleaf index` and check that it is intact
+    #[clap(arg_required_else_help = true)]
+    IndexInfo {
+        /// the output directory of `simpleaf index`, or the salmon index inside it
+        #[clap(value_parser)]
+        index: PathBuf,
+    },
+    /// combine the count matrices of several quantified samples
+    #[clap(arg_required_else_help = true)]
+    Aggr {
+        /// quantification directories to combine (the output directory of `simpleaf quant`
+        /// or the alevin-fry quantification directory inside it)
+        #[clap(short, long, value_parser, multiple_values = true, required = true)]
+        quant_dirs: Vec<PathBuf>,
+
+        /// sample names, one per quantification directory [default: the directory names]
+        #[clap(short, long, value_parser, multiple_values = true)]
+        names: Vec<String>,
+
+        /// suffix appended to the barcodes of each sample to keep them unique
+        #[clap(short, long, default_value = "name", value_parser = clap::builder::PossibleValuesParser::new(["name", "index"]))]
+        barcode_suffix: String,
+
+        /// downsample the UMIs of each sample so that all samples have the same mean number
+        /// of UMIs per cell; this equalizes UMI depth, not sequencing (read) depth
+        #[clap(short, long, action)]
+        downsample: bool,
+
+        /// seed of the random number generator used for downsampling
+        #[clap(long, default_value_t = 0, value_parser)]
+        seed: u64,
+
+        /// output directory
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+    },
+    /// sum the spliced (S), unspliced (U) and ambiguous (A) counts of a USA-mode
+    /// quantification into gene-level matrices
+    #[clap(arg_required_else_help = true)]
+    Collapse {
+        /// the output directory of `simpleaf quant`, or the alevin-fry quantification
+        /// directory inside it
+        #[clap(short, long, value_parser)]
+        quant_dir: PathBuf,
+
+        /// the counts summed into each gene-level matrix, as a `+`-separated subset of S, U and
+        /// A, optionally named as `<name>=<counts>` (e.g. `S+A`, `unspliced=U`); each is written
+        /// in the alevin-fry output layout to `<output>/<name>/` [default name: the letters of
+        /// the counts, e.g. `SA`]
+        #[clap(long, value_parser, multiple_values = true, default_value = "S+A")]
+        counts: Vec<String>,
+
+        /// output directory
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+    },
+    /// assign cells to samples from the counts of their hashtag oligos (HTODemux-style)
+    #[clap(arg_required_else_help = true)]
+    HtoDemux {
+        /// the output directory of the `simpleaf quant --feature-type hto` run of the
+        /// hashtags (or the alevin-fry quantification directory inside it)
+        #[clap(long, value_parser)]
+        hto_quant: PathBuf,
+
+        /// the cells to assign: the output directory of the GEX `simpleaf quant` run, or a
+        /// file listing one barcode per line
+        #[clap(short, long, value_parser)]
+        cells: PathBuf,
+
+        /// two-column file translating the GEX barcode (first column) of each cell to its
+        /// hashtag barcode (second column), for chemistries where they differ
+        #[clap(long, value_parser)]
+        bc_translation: Option<PathBuf>,
+
+        /// two-column file naming the sample (second column) of each hashtag (first column)
+        /// [default: the hashtag ids]
+        #[clap(short, long, value_parser)]
+        samples: Option<PathBuf>,
+
+        /// the quantile of the background distribution of each hashtag above which a cell
+        /// is positive for it
+        #[clap(long, default_value_t = 0.99, value_parser)]
+        positive_quantile: f64,
+
+        /// output directory
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+    },
+    /// run the `index` or `quant` command described by a TOML configuration file
+    #[clap(
+        arg_required_else_help = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    Run {
+        /// configuration file holding a single [index] or [quant] table, whose keys are the
+        /// arguments of that command (e.g. `threads = 16`, `reads1 = ["a.fq", "b.fq"]`)
+        #[clap(value_parser)]
+        config: PathBuf,
+
+        /// arguments of the configured command that override the values in the
+        /// configuration file (e.g. `--threads 32`)
+        #[clap(value_parser, multiple_values = true, allow_hyphen_values = true)]
+        overrides: Vec<String>,
     },
     /// set paths to the programs that simpleaf will use
     SetPaths {
@@ -129,6 +430,42 @@ enum Commands {
         /// path to pyroe to use
         #[clap(short, long, value_parser)]
         pyroe: Option<PathBuf>,
+        /// path to piscem to use (optional; only needed to map with piscem)
+        #[clap(long, value_parser)]
+        piscem: Option<PathBuf>,
+    },
+    /// show, check or update the paths of the programs simpleaf runs
+    Paths {
+        #[clap(subcommand)]
+        command: PathsCommand,
+    },
+}
+
+#[derive(Debug, Subcommand, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum PathsCommand {
+    /// print the configured programs and their versions
+    Show,
+    /// check that the configured programs still exist at the recorded versions
+    Check,
+    /// update the configured programs, leaving the others as they are
+    Refresh {
+        /// the programs to look up again in the PATH (salmon, alevin-fry, pyroe and/or
+        /// piscem) [default: all programs, unless some are given a path below]
+        #[clap(value_parser = clap::builder::PossibleValuesParser::new(["salmon", "alevin-fry", "pyroe", "piscem"]))]
+        tools: Vec<String>,
+        /// path to salmon to use
+        #[clap(short, long, value_parser)]
+        salmon: Option<PathBuf>,
+        /// path to alevin-fry to use
+        #[clap(short, long, value_parser)]
+        alevin_fry: Option<PathBuf>,
+        /// path to pyroe to use
+        #[clap(short, long, value_parser)]
+        pyroe: Option<PathBuf>,
+        /// path to piscem to use
+        #[clap(long, value_parser)]
+        piscem: Option<PathBuf>,
     },
 }
 
@@ -139,66 +476,109 @@ struct Cli {
     command: Commands,
 }
 
-enum Chemistry {
-    TenxV2,
-    TenxV3,
-    Other(String),
-}
-
-enum PermitListResult {
-    DownloadSuccessful(PathBuf),
-    AlreadyPresent(PathBuf),
-    UnregisteredChemistry,
+fn absolute(p: &mut PathBuf) -> anyhow::Result<()> {
+    *p = std::path::absolute(&p)?;
+    Ok(())
 }
 
-fn get_permit_if_absent(chem: Chemistry) -> Result<PermitListResult> {
-    let chem_file;
-    let dl_url;
-    match chem {
-        Chemistry::TenxV2 => {
-            chem_file = "10x_v2_permit.txt";
-            dl_url = "https://umd.box.com/shared/static/jbs2wszgbj7k4ic2hass9ts6nhqkwq1p";
-        }
-        Chemistry::TenxV3 => {
-            chem_file = "10x_v3_permit.txt";
-            dl_url = "https://umd.box.com/shared/static/eo0qlkfqf2v24ws6dfnxty6gqk1otf2h";
-        }
-        _ => {
-            return Ok(PermitListResult::UnregisteredChemistry);
+/// Fill in the values of `command` that are otherwise only resolved when it
+/// runs (the threads, the mapper of the index, the default filtering of the
+/// chemistry and absolute paths), so that its configuration file describes
+/// the same run wherever and on whichever machine it is read.
+fn resolve_for_config(command: &mut Commands, limits: &ResourceLimits) -> anyhow::Result<()> {
+    match command {
+        Commands::Index {
+            fasta,
+            gtf,
+            feature_ref,
+            output,
+            spliced,
+            unspliced,
+            threads,
+            ..
+        } => {
+            for p in [fasta, gtf, feature_ref, spliced, unspliced]
+                .into_iter()
+                .flatten()
+            {
+                absolute(p)?;
+            }
+            absolute(output)?;
+            *threads = Some(limits.resolve_threads(*threads));
         }
-    }
-    match env::var("ALEVIN_FRY_HOME") {
-        Ok(p) => {
-            let odir = PathBuf::from(p).join("plist");
-            if odir.join(chem_file).exists() {
-                Ok(PermitListResult::AlreadyPresent(odir.join(chem_file)))
-            } else {
-                run_fun!(mkdir -p $odir)?;
-                let mut dl_cmd = std::process::Command::new("wget");
-                dl_cmd
-                    .arg("-v")
-                    .arg("-O")
-                    .arg(odir.join(chem_file).to_string_lossy().to_string())
-                    .arg("-L")
-                    .arg(dl_url);
-                let r = dl_cmd.output()?;
-                if !r.status.success() {
-                    return Err(anyhow!("failed to download permit list {:?}", r.status));
+        Commands::Quant {
+            index,
+            reads1,
+            reads2,
+            sample_sheet,
+            threads,
+            knee,
+            unfiltered_pl,
+            explicit_pl,
+            forced_cells,
+            expect_cells,
+            t2g_map,
+            chemistry,
+            output,
+            mapper,
+            gex_quant,
+            bc_translation,
+            tissue_positions,
+            ..
+        } => {
+            let has_filter = *knee
+                || *unfiltered_pl
+                || explicit_pl.is_some()
+                || forced_cells.is_some()
+                || expect_cells.is_some();
+            if let (Some(chem), false, None, None, None) = (
+                chemistry.as_deref(),
+                has_filter,
+                &sample_sheet,
+                &gex_quant,
+                &tissue_positions,
+            ) {
+                match default_filter(chem)? {
+                    CellFilterMethod::KneeFinding => *knee = true,
+                    CellFilterMethod::ForceCells(n) => *forced_cells = Some(n),
+                    CellFilterMethod::ExpectCells(n) => *expect_cells = Some(n),
+                    CellFilterMethod::ExplicitList(l) => *explicit_pl = Some(PathBuf::from(l)),
+                    CellFilterMethod::UnfilteredExternalList(..) => *unfiltered_pl = true,
                 }
-                Ok(PermitListResult::DownloadSuccessful(odir.join(chem_file)))
             }
+            if mapper.is_none() {
+                *mapper = Some(index_mapper(index).name().to_string());
+            }
+            *threads = Some(limits.resolve_threads(*threads));
+            for p in reads1.iter_mut().chain(reads2.iter_mut()) {
+                absolute(p)?;
+            }
+            for p in [
+                sample_sheet,
+                explicit_pl,
+                gex_quant,
+                bc_translation,
+                tissue_positions,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                absolute(p)?;
+            }
+            absolute(index)?;
+            absolute(t2g_map)?;
+            absolute(output)?;
         }
-        Err(e) => {
-            return Err(anyhow!(
-                "could not resolve $ALEVIN_FRY_HOME environment variable : {}",
-                e
-            ));
-        }
+        _ => {}
     }
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // log messages share the terminal with the progress line of the running steps
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .target(env_logger::Target::Pipe(Box::new(LogWriter)))
+        .init();
     const AF_HOME: &str = "ALEVIN_FRY_HOME";
     let af_home_path = match env::var(AF_HOME) {
         Ok(p) => PathBuf::from(p),
@@ -213,13 +593,40 @@ fn main() -> anyhow::Result<()> {
 
     let cli_args = Cli::parse();
 
-    match cli_args.command {
+    // a configuration file is turned into the command line of the
+    // command it describes, which is then parsed as usual
+    let command = match cli_args.command {
+        Commands::Run { config, overrides } => {
+            let (name, fields) = read_run_config(&config)?;
+            let base = config.parent().unwrap_or_else(|| Path::new(""));
+            let args = config_to_args(&Cli::command(), &name, &fields, base, &overrides)?;
+            info!("running `{}` as configured in {}", name, config.display());
+            Cli::try_parse_from(args)
+                .unwrap_or_else(|e| e.exit())
+                .command
+        }
+        c => c,
+    };
+
+    let emit_config = match &command {
+        Commands::Index { emit_config, .. } | Commands::Quant { emit_config, .. } => *emit_config,
+        _ => false,
+    };
+    if emit_config {
+        let mut command = command;
+        resolve_for_config(&mut command, &ResourceLimits::detect())?;
+        print!("{}", command_to_config(&command)?);
+        return Ok(());
+    }
+
+    match command {
         Commands::SetPaths {
             salmon,
             alevin_fry,
             pyroe,
+            piscem,
         } => {
-            let rp = get_required_progs_from_paths(salmon, alevin_fry, pyroe)?;
+            let rp = get_required_progs_from_paths(salmon, alevin_fry, pyroe, piscem)?;
 
             if rp.salmon.is_none() {
                 bail!("Suitable salmon executable not found");
@@ -231,174 +638,161 @@ fn main() -> anyhow::Result<()> {
                 bail!("Suitable pyroe executable not found");
             }
 
-            let simpleaf_info_file = af_home_path.join("simpleaf_info.json");
-            let simpleaf_info = json!({ "prog_info": rp });
-
-            std::fs::write(
-                &simpleaf_info_file,
-                serde_json::to_string_pretty(&simpleaf_info).unwrap(),
-            )
-            .with_context(|| format!("could not write {}", simpleaf_info_file.display()))?;
+            write_required_progs(&af_home_path, &rp)?;
+        }
+        Commands::Paths { command } => match command {
+            PathsCommand::Show => paths_show(&af_home_path)?,
+            PathsCommand::Check => paths_check(&af_home_path)?,
+            PathsCommand::Refresh {
+                tools,
+                salmon,
+                alevin_fry,
+                pyroe,
+                piscem,
+            } => paths_refresh(&af_home_path, &tools, salmon, alevin_fry, pyroe, piscem)?,
+        },
+        Commands::Run { .. } => {
+            bail!("a configuration file cannot itself run another configuration file");
+        }
+        Commands::IndexInfo { index } => {
+            run_index_info(&index)?;
+        }
+        Commands::HtoDemux {
+            hto_quant,
+            cells,
+            bc_translation,
+            samples,
+            positive_quantile,
+            output,
+        } => {
+            let demux_opts = HtoDemuxOpts {
+                hto_quant,
+                cells,
+                bc_translation,
+                samples,
+                positive_quantile,
+                output,
+            };
+            run_hto_demux(&demux_opts)?;
+        }
+        Commands::Collapse {
+            quant_dir,
+            counts,
+            output,
+        } => {
+            let collapse_opts = CollapseOpts {
+                quant_dir,
+                specs: counts
+                    .iter()
+                    .map(|c| CountsSpec::parse(c))
+                    .collect::<anyhow::Result<Vec<CountsSpec>>>()?,
+                output,
+            };
+            run_collapse(&collapse_opts)?;
+        }
+        Commands::Aggr {
+            quant_dirs,
+            names,
+            barcode_suffix,
+            downsample,
+            seed,
+            output,
+        } => {
+            let suffix = match barcode_suffix.as_str() {
+                "index" => BarcodeSuffix::Index,
+                _ => BarcodeSuffix::Name,
+            };
+            let aggr_opts = AggrOpts {
+                quant_dirs,
+                names,
+                suffix,
+                downsample,
+                seed,
+                output,
+            };
+            run_aggr(&aggr_opts)?;
         }
         Commands::Index {
             fasta,
             gtf,
             rlen,
+            feature_ref,
             output,
             spliced,
             unspliced,
             dedup,
             sparse,
-            mut threads,
+            mapper,
+            pyroe_args,
+            salmon_index_args,
+            threads,
+            overwrite,
+            dry_run,
+            script,
+            ..
         } => {
-            // Open the file in read-only mode with buffer.
-            let af_info_p = af_home_path.join("simpleaf_info.json");
-            let simpleaf_info_file = std::fs::File::open(&af_info_p).with_context({
-                ||
-                format!("Could not open file {}; please run the set-paths command before using `index` or `quant`", af_info_p.display())
-            })?;
-
-            let simpleaf_info_reader = BufReader::new(simpleaf_info_file);
-
-            // Read the JSON contents of the file as an instance of `User`.
-            let v: serde_json::Value = serde_json::from_reader(simpleaf_info_reader)?;
-            let rp: ReqProgs = serde_json::from_value(v["prog_info"].clone())?;
-
-            run_fun!(mkdir -p $output)?;
-            let ref_file = format!("splici_fl{}.fa", rlen - 5);
-
-            let outref = output.join("ref");
-            run_fun!(mkdir -p $outref)?;
-
-            let t2g_file = outref.join(format!("splici_fl{}_t2g_3col.tsv", rlen - 5));
-            let info_file = output.join("index_info.json");
-            let index_info = json!({
-                "command" : "index",
-                "version_info" : rp,
-                "t2g_file" : t2g_file,
-                "args" : {
-                    "fasta" : fasta,
-                    "gtf" : gtf,
-                    "rlen" : rlen,
-                    "output" : output,
-                    "spliced" : spliced,
-                    "unspliced" : unspliced,
-                    "dedup" : dedup,
-                    "sparse" : sparse,
-                    "threads" : threads
-                }
-            });
-
-            std::fs::write(
-                &info_file,
-                serde_json::to_string_pretty(&index_info).unwrap(),
-            )
-            .with_context(|| format!("could not write {}", info_file.display()))?;
-
-            let mut cmd =
-                std::process::Command::new(format!("{}", rp.pyroe.unwrap().exe_path.display()));
-            // we will run the make-splici command
-            cmd.arg("make-splici");
-
-            // if the user wants to dedup output sequences
-            if dedup {
-                cmd.arg(String::from("--dedup-seqs"));
-            }
+            let pipeline = Pipeline::from_af_home(&af_home_path)?;
+            let index_args =
+                split_extra_args(ExtraArgsFor::SalmonIndex, salmon_index_args.as_deref())?;
 
-            // extra spliced sequence
-            match spliced {
-                Some(es) => {
-                    cmd.arg(String::from("--extra-spliced"));
-                    cmd.arg(format!("{}", es.display()));
+            if let Some(feature_ref) = feature_ref {
+                if Mapper::from_name(&mapper) != Mapper::Salmon {
+                    bail!("--feature-ref is only supported by the salmon mapper");
                 }
-                None => {}
-            }
-
-            // extra unspliced sequence
-            match unspliced {
-                Some(eu) => {
-                    cmd.arg(String::from("--extra-unspliced"));
-                    cmd.arg(format!("{}", eu.display()));
+                let feature_cfg = FeatureIndexConfig {
+                    feature_ref,
+                    output,
+                    threads,
+                    index_args,
+                    overwrite,
+                };
+                if dry_run {
+                    let mut sh = ShellScript::new("simpleaf index --feature-ref");
+                    pipeline.feature_index_script(&feature_cfg, &mut sh)?;
+                    sh.emit(script.as_deref())?;
+                } else {
+                    install_cancel_handlers();
+                    pipeline.feature_index(&feature_cfg)?;
                 }
-                None => {}
+                return Ok(());
             }
 
-            cmd.arg(fasta)
-                .arg(gtf)
-                .arg(format!("{}", rlen))
-                .arg(&outref);
-
-            let pyroe_start = Instant::now();
-            let cres = cmd.output()?;
-            let pyroe_duration = pyroe_start.elapsed();
-
-            if !cres.status.success() {
-                bail!("pyroe failed to return succesfully {:?}", cres.status);
-            }
-
-            let mut salmon_index_cmd =
-                std::process::Command::new(format!("{}", rp.salmon.unwrap().exe_path.display()));
-            let ref_seq = outref.join(ref_file);
-
-            let output_index_dir = output.join("index");
-            salmon_index_cmd
-                .arg("index")
-                .arg("-i")
-                .arg(&output_index_dir)
-                .arg("-t")
-                .arg(ref_seq);
-
-            // if the user requested a sparse index.
-            if sparse {
-                salmon_index_cmd.arg("--sparse");
-            }
+            // clap guarantees these are present without a feature reference
+            let index_cfg = IndexConfig {
+                fasta: fasta.unwrap(),
+                gtf: gtf.unwrap(),
+                rlen: rlen.unwrap(),
+                output,
+                spliced,
+                unspliced,
+                dedup,
+                sparse,
+                mapper: Mapper::from_name(&mapper),
+                threads,
+                pyroe_args: split_extra_args(ExtraArgsFor::Pyroe, pyroe_args.as_deref())?,
+                index_args,
+                overwrite,
+            };
 
-            // if the user requested more threads than can be used
-            if let Ok(max_threads_usize) = std::thread::available_parallelism() {
-                let max_threads = max_threads_usize.get() as u32;
-                if threads > max_threads {
-                    warn!(
-                        "The maximum available parallelism is {}, but {} threads were requested.",
-                        max_threads, threads
-                    );
-                    warn!("setting number of threads to {}", max_threads);
-                    threads = max_threads;
-                }
+            if dry_run {
+                let mut sh = ShellScript::new("simpleaf index");
+                pipeline.index_script(&index_cfg, &mut sh)?;
+                sh.emit(script.as_deref())?;
+            } else {
+                install_cancel_handlers();
+                pipeline.index(&index_cfg)?;
             }
-
-            salmon_index_cmd
-                .arg("--threads")
-                .arg(format!("{}", threads));
-
-            let index_start = Instant::now();
-            salmon_index_cmd
-                .output()
-                .expect("failed to run salmon index");
-            let index_duration = index_start.elapsed();
-
-            // copy over the t2g file to the index
-            let index_t2g_path = output_index_dir.join("t2g_3col.tsv");
-            std::fs::copy(t2g_file, index_t2g_path)?;
-
-            let index_log_file = output.join("simpleaf_index_log.json");
-            let index_log_info = json!({
-                "time_info" : {
-                    "pyroe_time" : pyroe_duration,
-                    "index_time" : index_duration
-                }
-            });
-
-            std::fs::write(
-                &index_log_file,
-                serde_json::to_string_pretty(&index_log_info).unwrap(),
-            )
-            .with_context(|| format!("could not write {}", index_log_file.display()))?;
         }
         Commands::Quant {
             index,
             reads1,
             reads2,
+            sample_sheet,
+            parallel_samples,
             threads,
+            map_threads,
+            collate_threads,
+            quant_threads,
             knee,
             unfiltered_pl,
             explicit_pl,
@@ -408,239 +802,130 @@ fn main() -> anyhow::Result<()> {
             t2g_map,
             chemistry,
             output,
+            mapper,
+            mapping_mode,
+            min_score_fraction,
+            softclip,
+            softclip_overhangs,
+            salmon_map_args,
+            gpl_args,
+            collate_args,
+            fry_quant_args,
+            feature_type,
+            guide_calling,
+            min_guide_umis,
+            gex_quant,
+            bc_translation,
+            tissue_positions,
+            compat_check,
+            overwrite,
+            dry_run,
+            script,
+            ..
         } => {
-            // Open the file in read-only mode with buffer.
-            let af_info_p = af_home_path.join("simpleaf_info.json");
-            let simpleaf_info_file = std::fs::File::open(&af_info_p).with_context({
-                ||
-                format!("Could not open file {}; please run the set-paths command before using `index` or `quant`", af_info_p.display())
-            })?;
-
-            let simpleaf_info_reader = BufReader::new(&simpleaf_info_file);
-
-            // Read the JSON contents of the file as an instance of `User`.
-            info!("deserializing from {:?}", simpleaf_info_file);
-            let v: serde_json::Value = serde_json::from_reader(simpleaf_info_reader)?;
-            let rp: ReqProgs = serde_json::from_value(v["prog_info"].clone())?;
-
-            info!("prog info = {:?}", rp);
-
-            let mut filter_meth_opt = None;
-            let chem = match chemistry.as_str() {
-                "10xv2" => Chemistry::TenxV2,
-                "10xv3" => Chemistry::TenxV3,
-                s => Chemistry::Other(s.to_string()),
+            let pipeline = Pipeline::from_af_home(&af_home_path)?;
+            info!("prog info = {:?}", pipeline.progs());
+            let compat_check = CompatCheck::from_name(&compat_check);
+            let feature_type = FeatureType::from_name(&feature_type);
+            if feature_type != FeatureType::Crispr
+                && (guide_calling.is_some() || min_guide_umis.is_some())
+            {
+                bail!("--guide-calling and --min-guide-umis require --feature-type crispr");
+            }
+            let guide_calling = GuideCalling::from_name(
+                guide_calling.as_deref().unwrap_or("mixture"),
+                min_guide_umis.unwrap_or(DEFAULT_MIN_GUIDE_UMIS),
+            );
+            let mapper = mapper.map(|m| Mapper::from_name(&m));
+            let mapping = MappingOpts::new(
+                MappingMode::from_name(&mapping_mode),
+                min_score_fraction,
+                softclip,
+                softclip_overhangs,
+            )?;
+            let extra_args = QuantExtraArgs {
+                map: split_extra_args(ExtraArgsFor::SalmonMap, salmon_map_args.as_deref())?,
+                gpl: split_extra_args(ExtraArgsFor::Gpl, gpl_args.as_deref())?,
+                collate: split_extra_args(ExtraArgsFor::Collate, collate_args.as_deref())?,
+                quant: split_extra_args(ExtraArgsFor::FryQuant, fry_quant_args.as_deref())?,
             };
 
-            // based on the filtering method
-            if unfiltered_pl {
-                // check the chemistry
-                let pl_res = get_permit_if_absent(chem)?;
-                let min_cells = 10usize;
-                match pl_res {
-                    PermitListResult::DownloadSuccessful(p)
-                    | PermitListResult::AlreadyPresent(p) => {
-                        filter_meth_opt = Some(CellFilterMethod::UnfilteredExternalList(
-                            p.to_string_lossy().into_owned(),
-                            min_cells,
-                        ));
-                    }
-                    PermitListResult::UnregisteredChemistry => {
-                        bail!(
-                            "Cannot use unrecognized chemistry {} with unfiltered permit list.",
-                            chemistry.as_str()
-                        );
-                    }
+            if let Some(sheet) = sample_sheet {
+                let batch_cfg = BatchConfig {
+                    index,
+                    samples: parse_sample_sheet(&sheet)?,
+                    resolution,
+                    t2g_map,
+                    output,
+                    mapper,
+                    mapping,
+                    parallel_samples,
+                    threads,
+                    map_threads,
+                    collate_threads,
+                    quant_threads,
+                    compat_check,
+                    extra_args,
+                    feature_type,
+                    guide_calling,
+                    overwrite,
+                };
+                if dry_run {
+                    let mut sh = ShellScript::new("simpleaf quant (sample sheet)");
+                    pipeline.batch_script(&batch_cfg, &mut sh)?;
+                    sh.emit(script.as_deref())?;
+                } else {
+                    install_cancel_handlers();
+                    pipeline.batch(&batch_cfg)?;
                 }
             } else {
-                match explicit_pl {
-                    Some(filtered_path) => {
-                        filter_meth_opt = Some(CellFilterMethod::ExplicitList(
-                            filtered_path.to_string_lossy().into_owned(),
-                        ));
-                    }
-                    None => {}
-                };
-                match forced_cells {
-                    Some(num_forced) => {
-                        filter_meth_opt = Some(CellFilterMethod::ForceCells(num_forced));
-                    }
-                    None => {}
-                };
-                match expect_cells {
-                    Some(num_expected) => {
-                        filter_meth_opt = Some(CellFilterMethod::ExpectCells(num_expected));
-                    }
-                    None => {}
+                // clap guarantees the chemistry is present without a sample
+                // sheet, and allows at most one of the filtering options
+                let filter = if knee {
+                    Some(FilterChoice::Knee)
+                } else if unfiltered_pl {
+                    Some(FilterChoice::UnfilteredPl)
+                } else if let Some(p) = explicit_pl {
+                    Some(FilterChoice::ExplicitPl(p))
+                } else if let Some(n) = forced_cells {
+                    Some(FilterChoice::ForcedCells(n))
+                } else {
+                    expect_cells.map(FilterChoice::ExpectCells)
                 };
-            }
-            // otherwise it must have been knee;
-            if knee {
-                filter_meth_opt = Some(CellFilterMethod::KneeFinding);
-            }
 
-            if filter_meth_opt.is_none() {
-                bail!("It seems no valid filtering strategy was provided!");
-            }
-
-            // here we must be safe to unwrap
-            let filter_meth = filter_meth_opt.unwrap();
-
-            let mut salmon_quant_cmd =
-                std::process::Command::new(format!("{}", rp.salmon.unwrap().exe_path.display()));
-
-            // set the input index and library type
-            let index_path = format!("{}", index.display());
-            salmon_quant_cmd
-                .arg("alevin")
-                .arg("--index")
-                .arg(index_path)
-                .arg("-l")
-                .arg("A");
-
-            // location of the reads
-            let r1_str = reads1
-                .iter()
-                .map(|x| format!("{}", x.display()))
-                .collect::<Vec<String>>()
-                .join(",");
-            let r2_str = reads2
-                .iter()
-                .map(|x| format!("{}", x.display()))
-                .collect::<Vec<String>>()
-                .join(",");
-            salmon_quant_cmd.arg("-1").arg(r1_str).arg("-2").arg(r2_str);
-
-            // location of outptu directory, number of threads
-            let map_output = output.join("af_map");
-            salmon_quant_cmd
-                .arg("--threads")
-                .arg(format!("{}", threads))
-                .arg("-o")
-                .arg(&map_output);
-            salmon_quant_cmd.arg("--sketch");
-
-            // setting the technology / chemistry
-            match chemistry.as_str() {
-                "10xv2" => {
-                    salmon_quant_cmd.arg("--chromium");
-                }
-                "10xv3" => {
-                    salmon_quant_cmd.arg("--chromiumV3");
-                }
-                s => {
-                    salmon_quant_cmd.arg(format!("--{}", s));
+                let quant_cfg = QuantConfig {
+                    index,
+                    reads1,
+                    reads2,
+                    chemistry: chemistry.unwrap(),
+                    filter,
+                    resolution,
+                    t2g_map,
+                    output,
+                    mapper,
+                    mapping,
+                    threads,
+                    map_threads,
+                    collate_threads,
+                    quant_threads,
+                    compat_check,
+                    extra_args,
+                    feature_type,
+                    gex_quant,
+                    bc_translation,
+                    guide_calling,
+                    tissue_positions,
+                    overwrite,
+                };
+                if dry_run {
+                    let mut sh = ShellScript::new("simpleaf quant");
+                    pipeline.quant_script(&quant_cfg, &mut sh)?;
+                    sh.emit(script.as_deref())?;
+                } else {
+                    install_cancel_handlers();
+                    pipeline.quant(&quant_cfg)?;
                 }
-            };
-
-            info!("cmd : {:?}", salmon_quant_cmd);
-            let map_start = Instant::now();
-            let map_proc_out = salmon_quant_cmd
-                .output()
-                .expect("failed to execute salmon alevin [mapping phase]");
-            let map_duration = map_start.elapsed();
-
-            if !map_proc_out.status.success() {
-                bail!("mapping failed with exit status {:?}", map_proc_out.status);
-            }
-
-            let alevin_fry = rp.alevin_fry.unwrap().exe_path;
-            // alevin-fry generate permit list
-            let mut alevin_gpl_cmd =
-                std::process::Command::new(format!("{}", &alevin_fry.display()));
-
-            alevin_gpl_cmd.arg("generate-permit-list");
-            alevin_gpl_cmd.arg("-i").arg(&map_output);
-            alevin_gpl_cmd.arg("-d").arg("fw");
-
-            // add the filter mode
-            add_to_args(&filter_meth, &mut alevin_gpl_cmd);
-
-            let gpl_output = output.join("af_quant");
-            alevin_gpl_cmd.arg("-o").arg(&gpl_output);
-
-            info!("cmd : {:?}", alevin_gpl_cmd);
-
-            let gpl_start = Instant::now();
-            let gpl_proc_out = alevin_gpl_cmd
-                .output()
-                .expect("could not execute [generate permit list]");
-            let gpl_duration = gpl_start.elapsed();
-
-            if !gpl_proc_out.status.success() {
-                bail!(
-                    "generate-permit-list failed with exit status {:?}",
-                    gpl_proc_out.status
-                );
-            }
-
-            //
-            // collate
-            //
-            let mut alevin_collate_cmd =
-                std::process::Command::new(format!("{}", &alevin_fry.display()));
-
-            alevin_collate_cmd.arg("collate");
-            alevin_collate_cmd.arg("-i").arg(&gpl_output);
-            alevin_collate_cmd.arg("-r").arg(&map_output);
-            alevin_collate_cmd.arg("-t").arg(format!("{}", threads));
-
-            info!("cmd : {:?}", alevin_collate_cmd);
-            let collate_start = Instant::now();
-            let collate_proc_out = alevin_collate_cmd
-                .output()
-                .expect("could not execute [collate]");
-            let collate_duration = collate_start.elapsed();
-
-            if !collate_proc_out.status.success() {
-                bail!(
-                    "collate failed with exit status {:?}",
-                    collate_proc_out.status
-                );
             }
-
-            //
-            // quant
-            //
-            let mut alevin_quant_cmd =
-                std::process::Command::new(format!("{}", &alevin_fry.display()));
-
-            alevin_quant_cmd
-                .arg("quant")
-                .arg("-i")
-                .arg(&gpl_output)
-                .arg("-o")
-                .arg(&gpl_output);
-            alevin_quant_cmd.arg("-t").arg(format!("{}", threads));
-            alevin_quant_cmd.arg("-m").arg(t2g_map);
-            alevin_quant_cmd.arg("-r").arg(resolution);
-
-            info!("cmd : {:?}", alevin_quant_cmd);
-            let quant_start = Instant::now();
-            let quant_proc_out = alevin_quant_cmd
-                .output()
-                .expect("could not execute [quant]");
-            let quant_duration = quant_start.elapsed();
-
-            if !quant_proc_out.status.success() {
-                bail!("quant failed with exit status {:?}", quant_proc_out.status);
-            }
-
-            let af_quant_info_file = output.join("simpleaf_quant_log.json");
-            let af_quant_info = json!({
-                "time_info" : {
-                "map_time" : map_duration,
-                "gpl_time" : gpl_duration,
-                "collate_time" : collate_duration,
-                "quant_time" : quant_duration
-                }
-            });
-
-            std::fs::write(
-                &af_quant_info_file,
-                serde_json::to_string_pretty(&af_quant_info).unwrap(),
-            )
-            .with_context(|| format!("could not write {}", af_quant_info_file.display()))?;
         }
     }
     Ok(())
@@ -0,0 +1,517 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::simpleaf_commands::batch::*;
+use crate::simpleaf_commands::features::*;
+use crate::simpleaf_commands::guides::*;
+use crate::simpleaf_commands::indexing::*;
+use crate::simpleaf_commands::quant::*;
+use crate::simpleaf_commands::spatial::*;
+use crate::utils::af_utils::*;
+use crate::utils::args_utils::*;
+use crate::utils::compat_utils::*;
+use crate::utils::exec_utils::*;
+use crate::utils::index_utils::*;
+use crate::utils::mtx_utils::*;
+use crate::utils::output_utils::*;
+use crate::utils::prog_utils::*;
+use crate::utils::resource_utils::*;
+use crate::utils::script_utils::*;
+
+/// The configuration of a splici index build, as done by `simpleaf index`.
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub fasta: PathBuf,
+    pub gtf: PathBuf,
+    pub rlen: u32,
+    pub output: PathBuf,
+    pub spliced: Option<PathBuf>,
+    pub unspliced: Option<PathBuf>,
+    pub dedup: bool,
+    pub sparse: bool,
+    pub mapper: Mapper,
+    /// the threads to use, limited to the available CPUs [default: min(16, CPUs)]
+    pub threads: Option<u32>,
+    pub pyroe_args: Vec<String>,
+    pub index_args: Vec<String>,
+    /// write into the output directory even if it is not empty
+    pub overwrite: bool,
+}
+
+impl IndexConfig {
+    /// The build of the index of `fasta` and `gtf` for reads of length
+    /// `rlen` into `output`, with the defaults of the other options.
+    pub fn new(fasta: PathBuf, gtf: PathBuf, rlen: u32, output: PathBuf) -> IndexConfig {
+        IndexConfig {
+            fasta,
+            gtf,
+            rlen,
+            output,
+            spliced: None,
+            unspliced: None,
+            dedup: false,
+            sparse: false,
+            mapper: Mapper::Salmon,
+            threads: None,
+            pyroe_args: Vec::new(),
+            index_args: Vec::new(),
+            overwrite: false,
+        }
+    }
+
+    /// The options of the build within the resource limits `limits`.
+    pub(crate) fn resolve(&self, limits: &ResourceLimits) -> IndexOpts {
+        IndexOpts {
+            fasta: self.fasta.clone(),
+            gtf: self.gtf.clone(),
+            rlen: self.rlen,
+            output: self.output.clone(),
+            spliced: self.spliced.clone(),
+            unspliced: self.unspliced.clone(),
+            dedup: self.dedup,
+            sparse: self.sparse,
+            threads: limits.resolve_threads(self.threads),
+            mapper: self.mapper,
+            pyroe_args: self.pyroe_args.clone(),
+            index_args: self.index_args.clone(),
+            limits: limits.clone(),
+        }
+    }
+}
+
+/// The configuration of the build of the index of a feature reference,
+/// as done by `simpleaf index --feature-ref`.
+#[derive(Debug, Clone)]
+pub struct FeatureIndexConfig {
+    /// the 10x Feature Reference CSV file
+    pub feature_ref: PathBuf,
+    pub output: PathBuf,
+    /// the threads to use, limited to the available CPUs [default: min(16, CPUs)]
+    pub threads: Option<u32>,
+    pub index_args: Vec<String>,
+    /// write into the output directory even if it is not empty
+    pub overwrite: bool,
+}
+
+impl FeatureIndexConfig {
+    /// The build of the index of `feature_ref` into `output`, with the
+    /// defaults of the other options.
+    pub fn new(feature_ref: PathBuf, output: PathBuf) -> FeatureIndexConfig {
+        FeatureIndexConfig {
+            feature_ref,
+            output,
+            threads: None,
+            index_args: Vec::new(),
+            overwrite: false,
+        }
+    }
+
+    /// The options of the build within the resource limits `limits`.
+    pub(crate) fn resolve(&self, limits: &ResourceLimits) -> FeatureIndexOpts {
+        FeatureIndexOpts {
+            feature_ref: self.feature_ref.clone(),
+            output: self.output.clone(),
+            threads: limits.resolve_threads(self.threads),
+            index_args: self.index_args.clone(),
+            limits: limits.clone(),
+        }
+    }
+}
+
+/// The configuration of the quantification of a single sample, as done
+/// by `simpleaf quant`.
+#[derive(Debug, Clone)]
+pub struct QuantConfig {
+    pub index: PathBuf,
+    pub reads1: Vec<PathBuf>,
+    pub reads2: Vec<PathBuf>,
+    pub chemistry: String,
+    /// the filtering of the cells, or `None` for the default of the chemistry
+    pub filter: Option<FilterChoice>,
+    pub resolution: String,
+    pub t2g_map: PathBuf,
+    pub output: PathBuf,
+    /// the mapper [default: the mapper that built the index]
+    pub mapper: Option<Mapper>,
+    pub mapping: MappingOpts,
+    /// the threads to use, limited to the available CPUs [default: min(16, CPUs)]
+    pub threads: Option<u32>,
+    pub map_threads: Option<u32>,
+    pub collate_threads: Option<u32>,
+    pub quant_threads: Option<u32>,
+    pub compat_check: CompatCheck,
+    pub extra_args: QuantExtraArgs,
+    pub feature_type: FeatureType,
+    /// the GEX quantification to restrict the cells of a feature
+    /// quantification to, in place of `filter`
+    pub gex_quant: Option<PathBuf>,
+    /// the GEX barcode and the feature barcode of each cell, if they differ
+    pub bc_translation: Option<PathBuf>,
+    /// how guides are assigned to cells with `FeatureType::Crispr`
+    pub guide_calling: GuideCalling,
+    /// the `tissue_positions.csv` of a Visium slide, whose spots take the
+    /// place of `filter`
+    pub tissue_positions: Option<PathBuf>,
+    /// write into the output directory even if it is not empty
+    pub overwrite: bool,
+}
+
+impl QuantConfig {
+    /// The quantification of the reads `reads1` and `reads2` of the
+    /// `chemistry` against `index` into `output`, with knee filtering,
+    /// cr-like resolution and the defaults of the other options.
+    pub fn new(
+        index: PathBuf,
+        reads1: Vec<PathBuf>,
+        reads2: Vec<PathBuf>,
+        chemistry: &str,
+        t2g_map: PathBuf,
+        output: PathBuf,
+    ) -> QuantConfig {
+        QuantConfig {
+            index,
+            reads1,
+            reads2,
+            chemistry: chemistry.to_string(),
+            filter: Some(FilterChoice::Knee),
+            resolution: String::from("cr-like"),
+            t2g_map,
+            output,
+            mapper: None,
+            mapping: MappingOpts::default(),
+            threads: None,
+            map_threads: None,
+            collate_threads: None,
+            quant_threads: None,
+            compat_check: CompatCheck::Warn,
+            extra_args: QuantExtraArgs::default(),
+            feature_type: FeatureType::Gex,
+            gex_quant: None,
+            bc_translation: None,
+            guide_calling: GuideCalling::default(),
+            tissue_positions: None,
+            overwrite: false,
+        }
+    }
+
+    /// The options of the quantification within the resource limits
+    /// `limits`. An unfiltered permit list is only fetched if `fetch_pl`
+    /// is true.
+    pub(crate) fn resolve(&self, limits: &ResourceLimits, fetch_pl: bool) -> Result<QuantOpts> {
+        let step_threads = ThreadOverrides::new(
+            self.map_threads,
+            self.collate_threads,
+            self.quant_threads,
+            limits,
+        );
+        Ok(QuantOpts {
+            index: self.index.clone(),
+            reads1: self.reads1.clone(),
+            reads2: self.reads2.clone(),
+            threads: step_threads.resolve(limits.resolve_threads(self.threads)),
+            filter_meth: match (&self.gex_quant, &self.tissue_positions) {
+                (Some(_), _) => gex_filter_method(&self.output),
+                (None, Some(_)) => spot_filter_method(&self.output),
+                (None, None) => get_filter_method(&self.chemistry, fetch_pl, self.filter.as_ref())?,
+            },
+            resolution: self.resolution.clone(),
+            chemistry: self.chemistry.clone(),
+            t2g_map: self.t2g_map.clone(),
+            output: self.output.clone(),
+            compat_check: self.compat_check,
+            mapper: self.mapper.unwrap_or_else(|| index_mapper(&self.index)),
+            mapping: self.mapping,
+            extra_args: self.extra_args.clone(),
+            feature_type: self.feature_type,
+            gex_quant: self.gex_quant.clone(),
+            bc_translation: self.bc_translation.clone(),
+            guide_calling: self.guide_calling,
+            tissue_positions: self.tissue_positions.clone(),
+            limits: limits.clone(),
+            sample: None,
+        })
+    }
+}
+
+/// The configuration of the quantification of the samples of a sample
+/// sheet against a shared index, as done by `simpleaf quant --sample-sheet`.
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    pub index: PathBuf,
+    /// see `parse_sample_sheet`
+    pub samples: Vec<SampleEntry>,
+    pub resolution: String,
+    pub t2g_map: PathBuf,
+    pub output: PathBuf,
+    /// the mapper [default: the mapper that built the index]
+    pub mapper: Option<Mapper>,
+    pub mapping: MappingOpts,
+    /// the samples to quantify at once [default: one per 8 threads]
+    pub parallel_samples: Option<usize>,
+    /// the threads shared by all the samples, limited to the available
+    /// CPUs [default: min(16, CPUs)]
+    pub threads: Option<u32>,
+    pub map_threads: Option<u32>,
+    pub collate_threads: Option<u32>,
+    pub quant_threads: Option<u32>,
+    pub compat_check: CompatCheck,
+    pub extra_args: QuantExtraArgs,
+    pub feature_type: FeatureType,
+    /// how guides are assigned to cells with `FeatureType::Crispr`
+    pub guide_calling: GuideCalling,
+    /// write into the output directory even if it is not empty
+    pub overwrite: bool,
+}
+
+impl BatchConfig {
+    /// The quantification of `samples` against `index` into `output`,
+    /// with cr-like resolution and the defaults of the other options.
+    pub fn new(
+        index: PathBuf,
+        samples: Vec<SampleEntry>,
+        t2g_map: PathBuf,
+        output: PathBuf,
+    ) -> BatchConfig {
+        BatchConfig {
+            index,
+            samples,
+            resolution: String::from("cr-like"),
+            t2g_map,
+            output,
+            mapper: None,
+            mapping: MappingOpts::default(),
+            parallel_samples: None,
+            threads: None,
+            map_threads: None,
+            collate_threads: None,
+            quant_threads: None,
+            compat_check: CompatCheck::Warn,
+            extra_args: QuantExtraArgs::default(),
+            feature_type: FeatureType::Gex,
+            guide_calling: GuideCalling::default(),
+            overwrite: false,
+        }
+    }
+
+    /// The options shared by the samples within the resource limits `limits`.
+    pub(crate) fn resolve(&self, limits: &ResourceLimits) -> BatchOpts {
+        BatchOpts {
+            index: self.index.clone(),
+            threads: limits.resolve_threads(self.threads),
+            step_threads: ThreadOverrides::new(
+                self.map_threads,
+                self.collate_threads,
+                self.quant_threads,
+                limits,
+            ),
+            limits: limits.clone(),
+            parallel_samples: self.parallel_samples,
+            resolution: self.resolution.clone(),
+            t2g_map: self.t2g_map.clone(),
+            output: self.output.clone(),
+            compat_check: self.compat_check,
+            mapper: self.mapper.unwrap_or_else(|| index_mapper(&self.index)),
+            mapping: self.mapping,
+            extra_args: self.extra_args.clone(),
+            feature_type: self.feature_type,
+            guide_calling: self.guide_calling,
+        }
+    }
+}
+
+/// What an index build produced.
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexResult {
+    pub output: PathBuf,
+    /// the splici reference and its transcript to gene map
+    pub reference: PathBuf,
+    /// the index of the mapper
+    pub index: PathBuf,
+    /// the transcript to gene map to quantify against the index with
+    pub t2g_map: PathBuf,
+    pub resources: IndexResources,
+}
+
+/// What the build of the index of a feature reference produced.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeatureIndexResult {
+    pub output: PathBuf,
+    /// the salmon index of the feature barcodes
+    pub index: PathBuf,
+    /// the identity transcript to gene map to quantify against the index with
+    pub t2g_map: PathBuf,
+    /// where the feature barcodes are in the reads (a salmon `--read-geometry`)
+    pub read_geometry: String,
+    pub resources: StepResources,
+}
+
+/// What the quantification of the samples of a sample sheet produced.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchResult {
+    pub output: PathBuf,
+    /// the status, time and resources of each sample
+    pub summary: PathBuf,
+}
+
+/// The metrics alevin-fry reports about a quantification, if it wrote them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QuantMetrics {
+    /// the number of cells that were quantified
+    pub num_cells: Option<u64>,
+    /// the contents of `generate_permit_list.json`
+    pub permit_list: Option<serde_json::Value>,
+    /// the contents of `quant.json`
+    pub quant: Option<serde_json::Value>,
+}
+
+/// What the quantification of a sample produced.
+#[derive(Debug, Clone)]
+pub struct QuantResult {
+    pub output: PathBuf,
+    /// the RAD file of the mapped reads
+    pub map_dir: PathBuf,
+    /// the permit list and the count matrix
+    pub quant_dir: PathBuf,
+    pub mapper: Mapper,
+    pub threads: QuantThreads,
+    pub times: QuantTimes,
+    pub metrics: QuantMetrics,
+}
+
+fn read_metrics(quant_dir: &Path) -> QuantMetrics {
+    let permit_list = read_json(&quant_dir.join("generate_permit_list.json")).ok();
+    let quant = QuantDir::locate(quant_dir)
+        .and_then(|q| q.read_quant_json())
+        .ok();
+    QuantMetrics {
+        num_cells: quant
+            .as_ref()
+            .and_then(|q| q["num_quantified_cells"].as_u64()),
+        permit_list,
+        quant,
+    }
+}
+
+/// Runs the `index` and `quant` steps with a set of external programs,
+/// within the resources available to this process. Cancelling a run with
+/// SIGINT or SIGTERM needs `install_cancel_handlers` to have been called.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    rp: ReqProgs,
+    limits: ResourceLimits,
+}
+
+impl Pipeline {
+    /// A pipeline running the programs `rp`.
+    pub fn new(rp: ReqProgs) -> Pipeline {
+        let limits = ResourceLimits::detect();
+        info!("resource limits = {:?}", limits);
+        Pipeline { rp, limits }
+    }
+
+    /// A pipeline running the programs recorded by `simpleaf set-paths`
+    /// in `af_home_path`.
+    pub fn from_af_home(af_home_path: &Path) -> Result<Pipeline> {
+        Ok(Pipeline::new(get_required_progs_from_info(af_home_path)?))
+    }
+
+    pub fn progs(&self) -> &ReqProgs {
+        &self.rp
+    }
+
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    /// Build the index described by `cfg`, holding the lock on its output
+    /// directory while it runs.
+    pub fn index(&self, cfg: &IndexConfig) -> Result<IndexResult> {
+        let opts = cfg.resolve(&self.limits);
+        let _lock = prepare_output_dir(&opts.output, cfg.overwrite)?;
+        let resources = run_index(&self.rp, &opts)?;
+        let index = opts.output.join("index");
+        Ok(IndexResult {
+            output: opts.output.clone(),
+            reference: opts.output.join("ref"),
+            t2g_map: index.join("t2g_3col.tsv"),
+            index,
+            resources,
+        })
+    }
+
+    /// Build the index of the feature reference described by `cfg`, holding
+    /// the lock on its output directory while it runs.
+    pub fn feature_index(&self, cfg: &FeatureIndexConfig) -> Result<FeatureIndexResult> {
+        let opts = cfg.resolve(&self.limits);
+        let _lock = prepare_output_dir(&opts.output, cfg.overwrite)?;
+        let resources = run_feature_index(&self.rp, &opts)?;
+        let index = opts.output.join("index");
+        Ok(FeatureIndexResult {
+            output: opts.output.clone(),
+            t2g_map: index.join("t2g.tsv"),
+            read_geometry: index_feature_geometry(&opts.output)?,
+            index,
+            resources,
+        })
+    }
+
+    /// Quantify the sample described by `cfg`, holding the lock on its
+    /// output directory while it runs.
+    pub fn quant(&self, cfg: &QuantConfig) -> Result<QuantResult> {
+        let opts = cfg.resolve(&self.limits, true)?;
+        info!("mapping the reads with {}", opts.mapper.name());
+        let _lock = prepare_output_dir(&opts.output, cfg.overwrite)?;
+        let times = run_quant(&self.rp, &opts)?;
+        let quant_dir = opts.output.join("af_quant");
+        Ok(QuantResult {
+            output: opts.output.clone(),
+            map_dir: opts.output.join("af_map"),
+            metrics: read_metrics(&quant_dir),
+            quant_dir,
+            mapper: opts.mapper,
+            threads: opts.threads,
+            times,
+        })
+    }
+
+    /// Quantify the samples described by `cfg`, holding the lock on the
+    /// output directory of the batch while they run. A failing sample does
+    /// not stop the others, but makes the batch fail once they are done.
+    pub fn batch(&self, cfg: &BatchConfig) -> Result<BatchResult> {
+        let opts = cfg.resolve(&self.limits);
+        info!("mapping the reads with {}", opts.mapper.name());
+        let _lock = prepare_output_dir(&opts.output, cfg.overwrite)?;
+        run_batch(&self.rp, &cfg.samples, &opts)?;
+        Ok(BatchResult {
+            output: opts.output.clone(),
+            summary: opts.output.join(BATCH_SUMMARY),
+        })
+    }
+
+    /// Add the commands that `index` would run for `cfg` to `script`.
+    pub fn index_script(&self, cfg: &IndexConfig, script: &mut ShellScript) -> Result<()> {
+        add_index_to_script(&self.rp, &cfg.resolve(&self.limits), script)
+    }
+
+    /// Add the commands that `feature_index` would run for `cfg` to `script`.
+    pub fn feature_index_script(
+        &self,
+        cfg: &FeatureIndexConfig,
+        script: &mut ShellScript,
+    ) -> Result<()> {
+        add_feature_index_to_script(&self.rp, &cfg.resolve(&self.limits), script)
+    }
+
+    /// Add the commands that `quant` would run for `cfg` to `script`.
+    pub fn quant_script(&self, cfg: &QuantConfig, script: &mut ShellScript) -> Result<()> {
+        add_quant_to_script(&self.rp, &cfg.resolve(&self.limits, false)?, script)
+    }
+
+    /// Add the commands that `batch` would run for `cfg` to `script`.
+    pub fn batch_script(&self, cfg: &BatchConfig, script: &mut ShellScript) -> Result<()> {
+        let opts = cfg.resolve(&self.limits);
+        info!("mapping the reads with {}", opts.mapper.name());
+        add_batch_to_script(&self.rp, &cfg.samples, &opts, script)
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{bail, Context, Result};
+use cmd_lib::run_fun;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Binomial, Distribution};
+use serde_json::json;
+use std::path::PathBuf;
+
+use crate::utils::mtx_utils::*;
+
+/// How the barcodes of each sample are made unique in the combined matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeSuffix {
+    /// `<barcode>-<sample name>`
+    Name,
+    /// `<barcode>-<1-based sample index>`
+    Index,
+}
+
+#[derive(Debug, Clone)]
+pub struct AggrOpts {
+    pub quant_dirs: Vec<PathBuf>,
+    pub names: Vec<String>,
+    pub suffix: BarcodeSuffix,
+    pub downsample: bool,
+    pub seed: u64,
+    pub output: PathBuf,
+}
+
+struct AggrSample {
+    name: String,
+    input: PathBuf,
+    qdir: QuantDir,
+    barcodes: Vec<String>,
+    total_counts: f64,
+    // fraction of UMIs retained when equalizing UMI depth
+    keep_frac: f64,
+}
+
+/// Retain each of the `v` UMIs with probability `p`. Fractional counts
+/// (produced by the EM resolution modes) keep their fractional part with
+/// probability `p`, so that the expected count is `v * p` either way.
+fn thin_count<R: Rng>(v: f64, p: f64, rng: &mut R) -> f64 {
+    if p >= 1.0 {
+        return v;
+    }
+    let whole = v.trunc();
+    let frac = v - whole;
+    // p is a valid probability, as it is below 1 and not negative
+    let mut kept = Binomial::new(whole as u64, p.max(0.0)).unwrap().sample(rng) as f64;
+    if frac > 0.0 && rng.gen_bool(p) {
+        kept += frac;
+    }
+    kept
+}
+
+/// Combine the count matrices of several quantified samples into a single
+/// matrix in the alevin-fry output layout below `opts.output`. Barcodes
+/// are suffixed to keep them unique across samples, the gene lists of all
+/// samples must be identical, and a `sample_metadata.tsv` table recording
+/// the origin and depth of each sample is written alongside the matrix.
+/// With `opts.downsample`, the UMIs of each sample are thinned so that all
+/// samples have the same mean number of UMIs per cell; this equalizes the
+/// UMI depth of the samples rather than their sequencing (read) depth, which
+/// the count matrices do not record.
+pub fn run_aggr(opts: &AggrOpts) -> Result<()> {
+    if opts.quant_dirs.is_empty() {
+        bail!("at least one quantification directory is required");
+    }
+    if !opts.names.is_empty() && opts.names.len() != opts.quant_dirs.len() {
+        bail!(
+            "{} sample names were given for {} quantification directories",
+            opts.names.len(),
+            opts.quant_dirs.len()
+        );
+    }
+
+    //
+    // collect and validate the inputs
+    //
+    let mut samples = Vec::with_capacity(opts.quant_dirs.len());
+    let mut genes: Option<Vec<String>> = None;
+    let mut quant_json: Option<serde_json::Value> = None;
+    for (i, input) in opts.quant_dirs.iter().enumerate() {
+        let qdir = QuantDir::locate(input)?;
+        let name = match opts.names.get(i) {
+            Some(n) => n.clone(),
+            None => input
+                .canonicalize()
+                .unwrap_or_else(|_| input.clone())
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| format!("{}", i + 1)),
+        };
+        if samples.iter().any(|s: &AggrSample| s.name == name) {
+            bail!(
+                "the sample name {:?} is used more than once; please provide unique names with --names",
+                name
+            );
+        }
+
+        let qj = qdir.read_quant_json()?;
+        let cols = qdir.read_cols()?;
+        match (&genes, &quant_json) {
+            (Some(g), Some(first_qj)) => {
+                if qj["usa_mode"] != first_qj["usa_mode"] {
+                    bail!(
+                        "sample {} has usa_mode = {}, but sample {} has usa_mode = {}; samples quantified in different modes cannot be combined",
+                        name,
+                        qj["usa_mode"],
+                        samples[0].name,
+                        first_qj["usa_mode"]
+                    );
+                }
+                if *g != cols {
+                    let first_diff = g.iter().zip(cols.iter()).position(|(a, b)| a != b);
+                    match first_diff {
+                        Some(j) => bail!(
+                            "the gene list of sample {} differs from that of sample {} at line {} ({:?} vs {:?}); all samples must be quantified against the same index",
+                            name,
+                            samples[0].name,
+                            j + 1,
+                            cols[j],
+                            g[j]
+                        ),
+                        None => bail!(
+                            "sample {} has {} genes, but sample {} has {}; all samples must be quantified against the same index",
+                            name,
+                            cols.len(),
+                            samples[0].name,
+                            g.len()
+                        ),
+                    }
+                }
+            }
+            _ => {
+                genes = Some(cols);
+                quant_json = Some(qj);
+            }
+        }
+
+        let barcodes = qdir.read_rows()?;
+        samples.push(AggrSample {
+            name,
+            input: input.clone(),
+            qdir,
+            barcodes,
+            total_counts: 0.0,
+            keep_frac: 1.0,
+        });
+    }
+    // there is at least one sample, so these must be set
+    let genes = genes.unwrap();
+    let mut quant_json = quant_json.unwrap();
+
+    // the UMI depth of each sample, which is used for the metadata table and
+    // (if requested) to equalize the mean UMIs per cell across samples.
+    for s in samples.iter_mut() {
+        let mut total = 0.0;
+        for_each_mtx_entry(
+            &s.qdir.matrix(),
+            s.barcodes.len(),
+            genes.len(),
+            |_, _, v| {
+                total += v;
+                Ok(())
+            },
+        )
+        .with_context(|| format!("could not read the matrix of sample {}", s.name))?;
+        s.total_counts = total;
+    }
+
+    if opts.downsample {
+        let mean_depth = |s: &AggrSample| {
+            if s.barcodes.is_empty() {
+                0.0
+            } else {
+                s.total_counts / s.barcodes.len() as f64
+            }
+        };
+        let target = samples
+            .iter()
+            .filter(|s| !s.barcodes.is_empty())
+            .map(mean_depth)
+            .fold(f64::INFINITY, f64::min);
+        for s in samples.iter_mut() {
+            let depth = mean_depth(s);
+            if depth > 0.0 && target.is_finite() {
+                s.keep_frac = (target / depth).min(1.0);
+            }
+            info!(
+                "sample {} has {:.2} UMIs per cell; retaining {:.4} of its UMIs",
+                s.name, depth, s.keep_frac
+            );
+        }
+    }
+
+    //
+    // write the combined matrix
+    //
+    let output = &opts.output;
+    let out_alevin = output.join("alevin");
+    run_fun!(mkdir -p $out_alevin)?;
+
+    let suffixes: Vec<String> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match opts.suffix {
+            BarcodeSuffix::Name => s.name.clone(),
+            BarcodeSuffix::Index => format!("{}", i + 1),
+        })
+        .collect();
+
+    let mut rng = StdRng::seed_from_u64(opts.seed);
+    let mut writer = MtxWriter::new(&out_alevin.join("quants_mat.mtx"))?;
+    let mut barcodes = Vec::new();
+    let mut kept_counts = Vec::with_capacity(samples.len());
+    for (s, suffix) in samples.iter().zip(suffixes.iter()) {
+        let row_offset = barcodes.len();
+        let mut kept = 0.0;
+        for_each_mtx_entry(
+            &s.qdir.matrix(),
+            s.barcodes.len(),
+            genes.len(),
+            |r, c, v| {
+                let v = thin_count(v, s.keep_frac, &mut rng);
+                if v > 0.0 {
+                    kept += v;
+                    writer.push(row_offset + r, c, v)?;
+                }
+                Ok(())
+            },
+        )
+        .with_context(|| format!("could not combine the matrix of sample {}", s.name))?;
+        kept_counts.push(kept);
+        barcodes.extend(s.barcodes.iter().map(|bc| format!("{}-{}", bc, suffix)));
+    }
+    let shape = writer.finish(barcodes.len(), genes.len())?;
+
+    write_lines(&out_alevin.join("quants_mat_rows.txt"), &barcodes)?;
+    write_lines(&out_alevin.join("quants_mat_cols.txt"), &genes)?;
+
+    // the combined quant.json keeps the fields of the first sample
+    // (usa_mode, num_genes etc.) so that the usual loaders work.
+    quant_json["num_quantified_cells"] = json!(barcodes.len());
+    let quant_json_file = output.join("quant.json");
+    std::fs::write(
+        &quant_json_file,
+        serde_json::to_string_pretty(&quant_json).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", quant_json_file.display()))?;
+
+    //
+    // the sample metadata table
+    //
+    let mut meta_lines = vec![String::from(
+        "sample_index\tsample\tbarcode_suffix\tquant_dir\tnum_cells\ttotal_counts\tdownsample_fraction\tretained_counts",
+    )];
+    for (i, s) in samples.iter().enumerate() {
+        meta_lines.push(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            i + 1,
+            s.name,
+            suffixes[i],
+            s.input.display(),
+            s.barcodes.len(),
+            s.total_counts,
+            s.keep_frac,
+            kept_counts[i]
+        ));
+    }
+    write_lines(&output.join("sample_metadata.tsv"), &meta_lines)?;
+
+    let aggr_log_file = output.join("simpleaf_aggr_log.json");
+    let aggr_log = json!({
+        "command" : "aggr",
+        "num_samples" : samples.len(),
+        "num_cells" : shape.nrows,
+        "num_genes" : shape.ncols,
+        "nnz" : shape.nnz,
+        "downsample" : opts.downsample,
+        "seed" : opts.seed,
+        "samples" : samples.iter().map(|s| json!({
+            "sample" : s.name,
+            "quant_dir" : s.qdir.root,
+            "num_cells" : s.barcodes.len()
+        })).collect::<Vec<_>>()
+    });
+    std::fs::write(
+        &aggr_log_file,
+        serde_json::to_string_pretty(&aggr_log).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", aggr_log_file.display()))?;
+
+    info!(
+        "combined {} samples into a {} x {} matrix in {}",
+        samples.len(),
+        shape.nrows,
+        shape.ncols,
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,439 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cmd_lib::run_fun;
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::simpleaf_commands::guides::*;
+use crate::simpleaf_commands::quant::*;
+use crate::utils::af_utils::*;
+use crate::utils::args_utils::*;
+use crate::utils::compat_utils::*;
+use crate::utils::exec_utils::*;
+use crate::utils::prog_utils::*;
+use crate::utils::resource_utils::*;
+use crate::utils::script_utils::*;
+
+/// One row of a sample sheet.
+#[derive(Debug, Clone)]
+pub struct SampleEntry {
+    pub name: String,
+    pub reads1: Vec<PathBuf>,
+    pub reads2: Vec<PathBuf>,
+    pub chemistry: String,
+    /// the filtering of the sample, or `None` for the default of its chemistry
+    pub filter: Option<FilterChoice>,
+}
+
+/// The options shared by every sample of a batch.
+#[derive(Debug, Clone)]
+pub struct BatchOpts {
+    pub index: PathBuf,
+    pub threads: u32,
+    pub step_threads: ThreadOverrides,
+    /// the resources available to the whole batch
+    pub limits: ResourceLimits,
+    pub parallel_samples: Option<usize>,
+    pub resolution: String,
+    pub t2g_map: PathBuf,
+    pub output: PathBuf,
+    pub compat_check: CompatCheck,
+    pub mapper: Mapper,
+    pub mapping: MappingOpts,
+    pub extra_args: QuantExtraArgs,
+    pub feature_type: FeatureType,
+    pub guide_calling: GuideCalling,
+}
+
+/// The summary of a batch, in its output directory.
+pub const BATCH_SUMMARY: &str = "simpleaf_batch_summary.json";
+
+const SHEET_COLUMNS: [&str; 5] = ["sample", "reads1", "reads2", "chemistry", "filter"];
+
+/// Parse a tab-separated sample sheet. The first non-comment line
+/// must be a header naming (in any order) the `sample`, `reads1`,
+/// `reads2`, `chemistry` and `filter` columns. Multiple read files
+/// for a sample are separated by commas, and relative read paths
+/// (and `explicit-pl` filter paths) are resolved against the directory
+/// containing the sheet. Empty lines and lines starting with `#` are
+/// ignored.
+pub fn parse_sample_sheet(sheet: &Path) -> Result<Vec<SampleEntry>> {
+    let contents = std::fs::read_to_string(sheet)
+        .with_context(|| format!("could not read sample sheet {}", sheet.display()))?;
+    let sheet_dir = sheet.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'));
+
+    let header: Vec<String> = match lines.next() {
+        Some((_, l)) => l.split('\t').map(|c| c.trim().to_lowercase()).collect(),
+        None => bail!("sample sheet {} is empty", sheet.display()),
+    };
+
+    // the position of each required column in the header
+    let mut col_idx = [0usize; SHEET_COLUMNS.len()];
+    for (i, col) in SHEET_COLUMNS.iter().enumerate() {
+        col_idx[i] = header.iter().position(|h| h == col).ok_or_else(|| {
+            anyhow!(
+                "sample sheet {} is missing the `{}` column; the header must name the columns {}",
+                sheet.display(),
+                col,
+                SHEET_COLUMNS.join(", ")
+            )
+        })?;
+    }
+
+    let resolve = |f: &str| -> PathBuf {
+        let p = PathBuf::from(f.trim());
+        if p.is_relative() {
+            sheet_dir.join(p)
+        } else {
+            p
+        }
+    };
+
+    let mut samples = Vec::new();
+    let mut seen = HashSet::new();
+    for (lnum, l) in lines {
+        let fields: Vec<&str> = l.split('\t').map(|f| f.trim()).collect();
+        if fields.len() != header.len() {
+            bail!(
+                "line {} of sample sheet {} has {} fields, but the header has {}",
+                lnum + 1,
+                sheet.display(),
+                fields.len(),
+                header.len()
+            );
+        }
+        let field = |c: usize| -> Result<&str> {
+            let f = fields[col_idx[c]];
+            if f.is_empty() {
+                bail!(
+                    "line {} of sample sheet {} has an empty `{}` field",
+                    lnum + 1,
+                    sheet.display(),
+                    SHEET_COLUMNS[c]
+                );
+            }
+            Ok(f)
+        };
+
+        let name = field(0)?.to_string();
+        if name.contains(std::path::is_separator) || name == "." || name == ".." {
+            bail!(
+                "sample name {:?} on line {} cannot be used as a directory name",
+                name,
+                lnum + 1
+            );
+        }
+        if !seen.insert(name.clone()) {
+            bail!(
+                "sample name {:?} appears more than once in sample sheet {}",
+                name,
+                sheet.display()
+            );
+        }
+
+        samples.push(SampleEntry {
+            name,
+            reads1: field(1)?.split(',').map(resolve).collect(),
+            reads2: field(2)?.split(',').map(resolve).collect(),
+            chemistry: field(3)?.to_string(),
+            filter: parse_filter_spec(field(4)?, sheet_dir).with_context(|| {
+                format!(
+                    "invalid filter on line {} of sample sheet {}",
+                    lnum + 1,
+                    sheet.display()
+                )
+            })?,
+        });
+    }
+
+    if samples.is_empty() {
+        bail!("sample sheet {} contains no samples", sheet.display());
+    }
+    Ok(samples)
+}
+
+/// Parse the `filter` field of a sample sheet row, which uses the names
+/// of the `quant` filtering flags (`knee`, `unfiltered-pl`,
+/// `explicit-pl=<path>`, `forced-cells=<n>` or `expect-cells=<n>`), or
+/// `default` (`None`) for the default filtering of the chemistry. A
+/// relative `explicit-pl` path is resolved against `sheet_dir`, like the
+/// reads.
+pub fn parse_filter_spec(spec: &str, sheet_dir: &Path) -> Result<Option<FilterChoice>> {
+    let (meth, val) = match spec.split_once('=') {
+        Some((m, v)) => (m.trim(), Some(v.trim())),
+        None => (spec.trim(), None),
+    };
+    let num_cells = |v: Option<&str>| -> Result<usize> {
+        let v = v.ok_or_else(|| anyhow!("filter `{}` requires a number of cells", meth))?;
+        v.parse::<usize>()
+            .with_context(|| format!("invalid number of cells {:?} for filter `{}`", v, meth))
+    };
+
+    match (meth, val) {
+        ("default", None) => Ok(None),
+        ("knee", None) => Ok(Some(FilterChoice::Knee)),
+        ("unfiltered-pl", None) => Ok(Some(FilterChoice::UnfilteredPl)),
+        ("explicit-pl", Some(p)) if !p.is_empty() => {
+            Ok(Some(FilterChoice::ExplicitPl(sheet_dir.join(p))))
+        }
+        ("forced-cells", v) => Ok(Some(FilterChoice::ForcedCells(num_cells(v)?))),
+        ("expect-cells", v) => Ok(Some(FilterChoice::ExpectCells(num_cells(v)?))),
+        _ => bail!(
+            "invalid filter {:?}; expected one of default, knee, unfiltered-pl, explicit-pl=<path>, forced-cells=<n> or expect-cells=<n>",
+            spec
+        ),
+    }
+}
+
+/// The number of samples to quantify at once and the
+/// number of threads to give each of them.
+fn batch_parallelism(opts: &BatchOpts, num_samples: usize) -> (usize, u32) {
+    let num_jobs = opts
+        .parallel_samples
+        .unwrap_or_else(|| (opts.threads as usize / 8).max(1))
+        .clamp(1, num_samples.max(1));
+    let threads_per_sample = (opts.threads / num_jobs as u32).max(1);
+    (num_jobs, threads_per_sample)
+}
+
+/// The threads of each step of a sample quantified alongside `num_jobs - 1`
+/// others, with `threads_per_sample` threads for the steps without an
+/// override.
+fn sample_threads(opts: &BatchOpts, num_jobs: usize, threads_per_sample: u32) -> QuantThreads {
+    opts.step_threads
+        .shared(num_jobs)
+        .resolve(threads_per_sample)
+}
+
+fn sample_quant_opts(
+    sample: &SampleEntry,
+    filter_meth: CellFilterMethod,
+    threads: QuantThreads,
+    limits: &ResourceLimits,
+    opts: &BatchOpts,
+) -> QuantOpts {
+    QuantOpts {
+        index: opts.index.clone(),
+        reads1: sample.reads1.clone(),
+        reads2: sample.reads2.clone(),
+        threads,
+        filter_meth,
+        resolution: opts.resolution.clone(),
+        chemistry: sample.chemistry.clone(),
+        t2g_map: opts.t2g_map.clone(),
+        output: opts.output.join(&sample.name),
+        compat_check: opts.compat_check,
+        mapper: opts.mapper,
+        mapping: opts.mapping,
+        extra_args: opts.extra_args.clone(),
+        feature_type: opts.feature_type,
+        gex_quant: None,
+        bc_translation: None,
+        guide_calling: opts.guide_calling,
+        tissue_positions: None,
+        limits: limits.clone(),
+        sample: Some(sample.name.clone()),
+    }
+}
+
+/// Quantify every sample in `samples` against the shared index, running
+/// up to `opts.parallel_samples` samples at once and splitting the global
+/// thread budget `opts.threads` (and the per-step thread overrides) evenly
+/// between them. Each sample is written
+/// to `<output>/<sample>/`, and a summary of the batch is written to
+/// `<output>/simpleaf_batch_summary.json`. A failing sample does not stop
+/// the others, but an error is returned once all samples have finished if
+/// any of them failed.
+pub fn run_batch(rp: &ReqProgs, samples: &[SampleEntry], opts: &BatchOpts) -> Result<()> {
+    let output = &opts.output;
+    run_fun!(mkdir -p $output)?;
+
+    // resolve the filtering method of every sample before starting any
+    // work, so that a malformed sheet fails early and permit lists are
+    // only fetched once.
+    let filter_meths = samples
+        .iter()
+        .map(|sample| {
+            get_filter_method(&sample.chemistry, true, sample.filter.as_ref())
+                .with_context(|| format!("invalid filter for sample {}", sample.name))
+        })
+        .collect::<Result<Vec<CellFilterMethod>>>()?;
+
+    let (num_jobs, threads_per_sample) = batch_parallelism(opts, samples.len());
+    let threads = sample_threads(opts, num_jobs, threads_per_sample);
+    let sample_limits = opts.limits.share(num_jobs);
+    info!(
+        "quantifying {} samples, {} at a time with {} threads each (mapping: {}, collate: {}, quant: {})",
+        samples.len(),
+        num_jobs,
+        threads_per_sample,
+        threads.map,
+        threads.collate,
+        threads.quant
+    );
+
+    let next_sample = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<QuantTimes>>>> =
+        Mutex::new((0..samples.len()).map(|_| None).collect());
+
+    std::thread::scope(|s| {
+        for _ in 0..num_jobs {
+            s.spawn(|| loop {
+                let i = next_sample.fetch_add(1, Ordering::SeqCst);
+                if i >= samples.len() {
+                    break;
+                }
+                // no new samples are started once the batch is cancelled
+                if cancel_signal().is_some() {
+                    break;
+                }
+                let sample = &samples[i];
+                info!("[{}] starting quantification", sample.name);
+
+                let qopts = sample_quant_opts(
+                    sample,
+                    filter_meths[i].clone(),
+                    threads,
+                    &sample_limits,
+                    opts,
+                );
+                let res = run_quant(rp, &qopts)
+                    .with_context(|| format!("quantification of sample {} failed", sample.name));
+
+                match &res {
+                    Ok(_) => info!("[{}] finished quantification", sample.name),
+                    Err(e) => error!("[{}] {:#}", sample.name, e),
+                }
+                results.lock().unwrap()[i] = Some(res);
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    let mut num_failed = 0usize;
+    let mut sample_summaries = Vec::with_capacity(samples.len());
+    for (sample, res) in samples.iter().zip(results) {
+        let sample_output = output.join(&sample.name);
+        match res {
+            Some(Ok(t)) => {
+                sample_summaries.push(json!({
+                    "sample" : sample.name,
+                    "status" : "succeeded",
+                    "output" : sample_output,
+                    "time_info" : {
+                        "map_time" : t.map_time,
+                        "gpl_time" : t.gpl_time,
+                        "collate_time" : t.collate_time,
+                        "quant_time" : t.quant_time
+                    },
+                    "resource_info" : t.resources
+                }));
+            }
+            Some(Err(e)) => {
+                num_failed += 1;
+                let status = if sample_output.join(INCOMPLETE_MARKER).exists() {
+                    "incomplete"
+                } else {
+                    "failed"
+                };
+                sample_summaries.push(json!({
+                    "sample" : sample.name,
+                    "status" : status,
+                    "output" : sample_output,
+                    "error" : format!("{:#}", e)
+                }));
+            }
+            // the batch was cancelled before this sample was started
+            None => {
+                num_failed += 1;
+                sample_summaries.push(json!({
+                    "sample" : sample.name,
+                    "status" : "not started",
+                    "output" : sample_output
+                }));
+            }
+        }
+    }
+
+    let summary_file = output.join(BATCH_SUMMARY);
+    let summary = json!({
+        "num_samples" : samples.len(),
+        "num_succeeded" : samples.len() - num_failed,
+        "num_failed" : num_failed,
+        "parallel_samples" : num_jobs,
+        "threads_per_sample" : threads_per_sample,
+        "resource_limits" : opts.limits,
+        "samples" : sample_summaries
+    });
+    std::fs::write(
+        &summary_file,
+        serde_json::to_string_pretty(&summary).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", summary_file.display()))?;
+
+    info!(
+        "batch finished: {} of {} samples succeeded; summary written to {}",
+        samples.len() - num_failed,
+        samples.len(),
+        summary_file.display()
+    );
+
+    if num_failed > 0 {
+        bail!(
+            "{} of {} samples failed to quantify; see {} for details",
+            num_failed,
+            samples.len(),
+            summary_file.display()
+        );
+    }
+    Ok(())
+}
+
+/// Add the commands that `run_batch` would run to `script`. The samples
+/// are quantified one after another in the script, each with the number
+/// of threads it would have been given by `run_batch`.
+pub fn add_batch_to_script(
+    rp: &ReqProgs,
+    samples: &[SampleEntry],
+    opts: &BatchOpts,
+    script: &mut ShellScript,
+) -> Result<()> {
+    let (num_jobs, threads_per_sample) = batch_parallelism(opts, samples.len());
+    let threads = sample_threads(opts, num_jobs, threads_per_sample);
+    let sample_limits = opts.limits.share(num_jobs);
+    let mut sample_opts = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let filter_meth = get_filter_method(&sample.chemistry, false, sample.filter.as_ref())
+            .with_context(|| format!("invalid filter for sample {}", sample.name))?;
+        sample_opts.push(sample_quant_opts(
+            sample,
+            filter_meth,
+            threads,
+            &sample_limits,
+            opts,
+        ));
+    }
+
+    // a permit list shared by several samples is downloaded once, up front
+    let mut downloads = HashSet::new();
+    for qopts in &sample_opts {
+        if let Some(loc) = permit_list_to_download(qopts)? {
+            if downloads.insert(loc.path.clone()) {
+                add_permit_list_download_to_script(&loc, script)?;
+            }
+        }
+    }
+    for (sample, qopts) in samples.iter().zip(&sample_opts) {
+        script.blank();
+        script.comment(format!("sample {}", sample.name));
+        add_quant_steps_to_script(rp, qopts, script)?;
+    }
+    Ok(())
+}
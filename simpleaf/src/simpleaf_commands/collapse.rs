@@ -0,0 +1,258 @@
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::utils::mtx_utils::*;
+
+/// The three blocks of columns of a USA-mode count matrix, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsaPart {
+    Spliced,
+    Unspliced,
+    Ambiguous,
+}
+
+impl UsaPart {
+    fn from_letter(l: &str) -> Option<UsaPart> {
+        match l {
+            "S" => Some(UsaPart::Spliced),
+            "U" => Some(UsaPart::Unspliced),
+            "A" => Some(UsaPart::Ambiguous),
+            _ => None,
+        }
+    }
+
+    fn letter(&self) -> &'static str {
+        match self {
+            UsaPart::Spliced => "S",
+            UsaPart::Unspliced => "U",
+            UsaPart::Ambiguous => "A",
+        }
+    }
+
+    // the position of the block among the columns
+    fn block(&self) -> usize {
+        match self {
+            UsaPart::Spliced => 0,
+            UsaPart::Unspliced => 1,
+            UsaPart::Ambiguous => 2,
+        }
+    }
+}
+
+/// A gene-level matrix to write: the sum of the `parts` of the counts
+/// of each gene, written to `<output>/<name>`.
+#[derive(Debug, Clone)]
+pub struct CountsSpec {
+    pub name: String,
+    pub parts: Vec<UsaPart>,
+}
+
+impl CountsSpec {
+    /// Parse `[<name>=]<counts>`, where the counts are a `+`-separated
+    /// subset of S, U and A (e.g. `S+A` or `unspliced=U`). The name
+    /// defaults to the letters of the counts (e.g. `SA`).
+    pub fn parse(spec: &str) -> Result<CountsSpec> {
+        let (name, counts) = match spec.split_once('=') {
+            Some((n, c)) => (Some(n.trim()), c.trim()),
+            None => (None, spec.trim()),
+        };
+        let mut parts = Vec::new();
+        for l in counts.split('+').map(str::trim) {
+            let part = match UsaPart::from_letter(l) {
+                Some(p) => p,
+                None => bail!(
+                    "invalid counts {:?}; expected a `+`-separated subset of S, U and A (e.g. S+A)",
+                    spec
+                ),
+            };
+            if parts.contains(&part) {
+                bail!("the counts {:?} include {} more than once", spec, l);
+            }
+            parts.push(part);
+        }
+        // the order of the counts does not change their sum
+        parts.sort_by_key(UsaPart::block);
+        let name = match name {
+            Some("") => bail!("the counts {:?} have an empty name", spec),
+            Some(n) if n.contains(std::path::is_separator) || n == "." || n == ".." => {
+                bail!(
+                    "the name {:?} of the counts {:?} is not a directory name",
+                    n,
+                    spec
+                )
+            }
+            Some(n) => n.to_string(),
+            None => parts.iter().map(UsaPart::letter).collect(),
+        };
+        Ok(CountsSpec { name, parts })
+    }
+
+    /// The counts, in the order S, U, A (e.g. `S+A`).
+    pub fn counts(&self) -> String {
+        self.parts
+            .iter()
+            .map(UsaPart::letter)
+            .collect::<Vec<&str>>()
+            .join("+")
+    }
+}
+
+// write the sums of the row `row` to each output and clear them
+fn flush_row(
+    row: usize,
+    sums: &mut [BTreeMap<usize, f64>],
+    writers: &mut [MtxWriter],
+) -> Result<()> {
+    for (w, s) in writers.iter_mut().zip(sums.iter_mut()) {
+        for (gene, v) in std::mem::take(s) {
+            w.push(row, gene, v)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct CollapseOpts {
+    pub quant_dir: PathBuf,
+    pub specs: Vec<CountsSpec>,
+    pub output: PathBuf,
+}
+
+/// Sum the spliced, unspliced and ambiguous counts of each gene of the
+/// USA-mode quantification in `opts.quant_dir` into one gene-level matrix
+/// per `CountsSpec`, each written in the alevin-fry output layout (with a
+/// `quant.json` whose `usa_mode` is false) below `opts.output`. The input
+/// matrix is read once, whatever the number of outputs.
+pub fn run_collapse(opts: &CollapseOpts) -> Result<()> {
+    if opts.specs.is_empty() {
+        bail!("at least one set of counts is required");
+    }
+    for (i, s) in opts.specs.iter().enumerate() {
+        if opts.specs[..i].iter().any(|o| o.name == s.name) {
+            bail!(
+                "more than one of the counts is named {}; name them with <name>=<counts>",
+                s.name
+            );
+        }
+    }
+
+    let qdir = QuantDir::locate(&opts.quant_dir)?;
+    let quant_json = qdir.read_quant_json()?;
+    if quant_json["usa_mode"] != json!(true) {
+        bail!(
+            "{} is not a USA-mode quantification (its usa_mode is {}), so its counts are already per gene",
+            qdir.root.display(),
+            quant_json["usa_mode"]
+        );
+    }
+    let cols = qdir.read_cols()?;
+    let num_cols = match quant_json["num_genes"].as_u64() {
+        Some(n) => n as usize,
+        None => cols.len(),
+    };
+    if num_cols % 3 != 0 || num_cols != cols.len() {
+        bail!(
+            "{} has num_genes = {} and {} columns, but a USA-mode matrix has 3 columns per gene",
+            qdir.root.display(),
+            num_cols,
+            cols.len()
+        );
+    }
+    let num_genes = num_cols / 3;
+    let genes = &cols[..num_genes];
+    let rows = qdir.read_rows()?;
+
+    // the outputs that each block of columns is summed into
+    let mut outputs_of_block: [Vec<usize>; 3] = Default::default();
+    let mut writers = Vec::with_capacity(opts.specs.len());
+    for (i, s) in opts.specs.iter().enumerate() {
+        for p in &s.parts {
+            outputs_of_block[p.block()].push(i);
+        }
+        let dir = opts.output.join(&s.name).join("alevin");
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        writers.push(MtxWriter::new(&dir.join("quants_mat.mtx"))?);
+    }
+
+    // alevin-fry writes the counts of each cell together, so the sums are
+    // kept for one row at a time
+    let mut row_sums: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); opts.specs.len()];
+    let mut current_row: Option<usize> = None;
+    let mut done_rows = vec![false; rows.len()];
+    let matrix = qdir.matrix();
+    for_each_mtx_entry(&matrix, rows.len(), num_cols, |r, c, v| {
+        if current_row != Some(r) {
+            if let Some(prev) = current_row {
+                flush_row(prev, &mut row_sums, &mut writers)?;
+                done_rows[prev] = true;
+            }
+            if done_rows[r] {
+                bail!(
+                    "the counts of row {} of {} are not stored together",
+                    r + 1,
+                    matrix.display()
+                );
+            }
+            current_row = Some(r);
+        }
+        for &o in &outputs_of_block[c / num_genes] {
+            *row_sums[o].entry(c % num_genes).or_insert(0.0) += v;
+        }
+        Ok(())
+    })?;
+    if let Some(prev) = current_row {
+        flush_row(prev, &mut row_sums, &mut writers)?;
+    }
+
+    let mut collapsed = Vec::with_capacity(opts.specs.len());
+    for (s, w) in opts.specs.iter().zip(writers) {
+        let out = opts.output.join(&s.name);
+        let out_shape = w.finish(rows.len(), num_genes)?;
+        write_lines(&out.join("alevin").join("quants_mat_rows.txt"), &rows)?;
+        write_lines(&out.join("alevin").join("quants_mat_cols.txt"), genes)?;
+
+        // the other fields of quant.json are kept for the usual loaders
+        let mut out_json = quant_json.clone();
+        out_json["usa_mode"] = json!(false);
+        out_json["num_genes"] = json!(num_genes);
+        out_json["collapsed_counts"] = json!(s.counts());
+        let out_json_file = out.join("quant.json");
+        std::fs::write(
+            &out_json_file,
+            serde_json::to_string_pretty(&out_json).unwrap(),
+        )
+        .with_context(|| format!("could not write {}", out_json_file.display()))?;
+
+        info!(
+            "wrote the {} counts of {} genes in {} cells to {}",
+            s.counts(),
+            num_genes,
+            rows.len(),
+            out.display()
+        );
+        collapsed.push(json!({
+            "name" : s.name,
+            "counts" : s.counts(),
+            "dir" : out,
+            "nnz" : out_shape.nnz
+        }));
+    }
+
+    let collapse_log_file = opts.output.join("simpleaf_collapse_log.json");
+    let collapse_log = json!({
+        "command" : "collapse",
+        "quant_dir" : qdir.root,
+        "num_cells" : rows.len(),
+        "num_genes" : num_genes,
+        "outputs" : collapsed
+    });
+    std::fs::write(
+        &collapse_log_file,
+        serde_json::to_string_pretty(&collapse_log).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", collapse_log_file.display()))?;
+    Ok(())
+}
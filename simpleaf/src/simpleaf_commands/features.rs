@@ -0,0 +1,522 @@
+use anyhow::{bail, Context, Result};
+use cmd_lib::run_fun;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::utils::af_utils::*;
+use crate::utils::args_utils::*;
+use crate::utils::exec_utils::*;
+use crate::utils::index_utils::*;
+use crate::utils::mtx_utils::*;
+use crate::utils::prog_utils::*;
+use crate::utils::resource_utils::*;
+use crate::utils::script_utils::*;
+
+/// A feature of a 10x Feature Reference CSV file. Its `name` is only
+/// shown by Cell Ranger, so it is not kept.
+#[derive(Debug, Clone)]
+pub struct Feature {
+    pub id: String,
+    pub read: String,
+    pub pattern: String,
+    pub sequence: String,
+    pub feature_type: String,
+}
+
+const FEATURE_REF_COLUMNS: [&str; 6] =
+    ["id", "name", "read", "pattern", "sequence", "feature_type"];
+
+/// Parse the 10x Feature Reference CSV file `p`, which must have (at least)
+/// the `id`, `name`, `read`, `pattern`, `sequence` and `feature_type` columns.
+pub fn parse_feature_ref(p: &Path) -> Result<Vec<Feature>> {
+    let contents = std::fs::read_to_string(p)
+        .with_context(|| format!("could not read feature reference {}", p.display()))?;
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'));
+
+    let header: Vec<&str> = match lines.next() {
+        Some((_, h)) => h.split(',').map(str::trim).collect(),
+        None => bail!("feature reference {} is empty", p.display()),
+    };
+    let mut cols = [0usize; 6];
+    for (i, name) in FEATURE_REF_COLUMNS.iter().enumerate() {
+        cols[i] = match header.iter().position(|h| h == name) {
+            Some(c) => c,
+            None => bail!("feature reference {} has no `{}` column", p.display(), name),
+        };
+    }
+
+    let mut features = Vec::new();
+    let mut ids = HashSet::new();
+    for (i, l) in lines {
+        let fields: Vec<&str> = l.split(',').map(str::trim).collect();
+        if fields.len() != header.len() {
+            bail!(
+                "line {} of {} has {} fields, but the header has {}",
+                i + 1,
+                p.display(),
+                fields.len(),
+                header.len()
+            );
+        }
+        let f = Feature {
+            id: fields[cols[0]].to_string(),
+            read: fields[cols[2]].to_string(),
+            pattern: fields[cols[3]].to_string(),
+            sequence: fields[cols[4]].to_ascii_uppercase(),
+            feature_type: fields[cols[5]].to_string(),
+        };
+        if f.sequence.is_empty() || !f.sequence.chars().all(|c| "ACGTN".contains(c)) {
+            bail!(
+                "feature {} on line {} of {} has an invalid sequence",
+                f.id,
+                i + 1,
+                p.display()
+            );
+        }
+        if !ids.insert(f.id.clone()) {
+            bail!("feature {} appears twice in {}", f.id, p.display());
+        }
+        features.push(f);
+    }
+    if features.is_empty() {
+        bail!("feature reference {} lists no features", p.display());
+    }
+    Ok(features)
+}
+
+// the constant sequences before and after `(BC)` in `pattern`, if the
+// barcode is found by them (as the protospacers of guide RNAs are) rather
+// than at a fixed position from the start of the read
+fn pattern_flanks(pattern: &str) -> Option<(&str, &str)> {
+    if pattern.starts_with('^') || pattern.starts_with("5P") {
+        return None;
+    }
+    let (before, after) = pattern.split_once("(BC)")?;
+    let constant = |s: &str| s.chars().all(|c| "ACGT".contains(c));
+    if constant(before) && constant(after) {
+        Some((before, after))
+    } else {
+        None
+    }
+}
+
+impl Feature {
+    /// The sequence indexed for the feature: its barcode, along with the
+    /// constant sequences around it if it is not at a fixed position.
+    pub fn indexed_sequence(&self) -> String {
+        match pattern_flanks(&self.pattern) {
+            Some((before, after)) => format!("{}{}{}", before, self.sequence, after),
+            None => self.sequence.clone(),
+        }
+    }
+}
+
+/// The salmon `--read-geometry` of the feature barcodes of `features`,
+/// which must all be found in the same read with the same pattern. If the
+/// pattern anchors the barcode at the start of the read (`^` or `5P`,
+/// followed by any `N`s and `(BC)`), only the barcode is mapped; if it
+/// places the barcode between constant sequences (such as the scaffold of
+/// a guide RNA), the whole read is mapped to the barcodes and their flanks.
+pub fn feature_read_geometry(features: &[Feature]) -> Result<String> {
+    let first = &features[0];
+    let flanked = pattern_flanks(&first.pattern).is_some();
+    for f in features {
+        if f.read != first.read || f.pattern != first.pattern {
+            bail!(
+                "features {} and {} are in different reads or patterns; please build a separate index for each",
+                first.id,
+                f.id
+            );
+        }
+        if !flanked && f.sequence.len() != first.sequence.len() {
+            bail!(
+                "features {} and {} have barcodes of different lengths",
+                first.id,
+                f.id
+            );
+        }
+    }
+    let read = match first.read.as_str() {
+        "R1" => 1,
+        "R2" => 2,
+        r => bail!("feature {} is in the unknown read {}", first.id, r),
+    };
+    if flanked {
+        return Ok(format!("{}[1-end]", read));
+    }
+    let anchored = first
+        .pattern
+        .strip_prefix('^')
+        .or_else(|| first.pattern.strip_prefix("5P"));
+    let offset = match anchored.and_then(|p| p.strip_suffix("(BC)")) {
+        Some(ns) if ns.chars().all(|c| c == 'N') => ns.len(),
+        _ => bail!(
+            "the pattern {} of feature {} is not supported; only patterns such as ^(BC), 5PNNNNNNNNNN(BC) or (BC)GTTTAAGAGC are",
+            first.pattern,
+            first.id
+        ),
+    };
+    Ok(format!(
+        "{}[{}-{}]",
+        read,
+        offset + 1,
+        offset + first.sequence.len()
+    ))
+}
+
+/// The salmon `--bc-geometry` and `--umi-geometry` of `chem`.
+pub fn barcode_umi_geometry(chem: &Chemistry) -> Result<(&'static str, &'static str)> {
+    match chem {
+        Chemistry::TenxV2 | Chemistry::Tenx5V2 => Ok(("1[1-16]", "1[17-26]")),
+        Chemistry::TenxV3 | Chemistry::TenxV3_1 | Chemistry::Tenx5V3 => Ok(("1[1-16]", "1[17-28]")),
+        _ => bail!(
+            "feature barcoding is not supported for the {} chemistry",
+            chem.name()
+        ),
+    }
+}
+
+/// The `--read-geometry` of the feature barcodes recorded in the index
+/// built from a feature reference in `index`.
+pub fn index_feature_geometry(index: &Path) -> Result<String> {
+    let info = IndexDir::locate(index)?.read_index_info()?;
+    match info.as_ref().and_then(|i| i["feature_geometry"].as_str()) {
+        Some(g) => Ok(g.to_string()),
+        None => bail!(
+            "{} was not built by simpleaf index --feature-ref, so it cannot be used to quantify features",
+            index.display()
+        ),
+    }
+}
+
+/// Everything needed to build the index of a feature reference.
+#[derive(Debug, Clone)]
+pub struct FeatureIndexOpts {
+    pub feature_ref: PathBuf,
+    pub output: PathBuf,
+    pub threads: u32,
+    pub index_args: Vec<String>,
+    /// the resources available to the run, which the peak memory of
+    /// each step is checked against
+    pub limits: ResourceLimits,
+}
+
+/// The index of a feature reference, and the files it is built from.
+pub struct FeatureIndexCommands {
+    pub index: std::process::Command,
+    pub features: Vec<Feature>,
+    pub geometry: String,
+    pub outref: PathBuf,
+    pub features_file: PathBuf,
+    pub t2g_file: PathBuf,
+    pub output_index_dir: PathBuf,
+}
+
+/// Construct (without running) the salmon index command of the feature
+/// reference of `opts`, which is validated first.
+pub fn build_feature_index_commands(
+    rp: &ReqProgs,
+    opts: &FeatureIndexOpts,
+) -> Result<FeatureIndexCommands> {
+    let salmon = &Mapper::Salmon.prog(rp)?.exe_path;
+    let features = parse_feature_ref(&opts.feature_ref)?;
+    let geometry = feature_read_geometry(&features)?;
+
+    let outref = opts.output.join("ref");
+    let features_file = outref.join("features.tsv");
+    let t2g_file = outref.join("t2g.tsv");
+    let output_index_dir = opts.output.join("index");
+
+    // the k-mer size must be odd and no longer than the indexed sequences;
+    // whole reads are mapped to flanked barcodes, which need longer k-mers
+    let max_k = if geometry.ends_with("-end]") { 19 } else { 7 };
+    let seq_len = features
+        .iter()
+        .map(|f| f.indexed_sequence().len())
+        .min()
+        .unwrap_or(1);
+    let k = if seq_len >= max_k {
+        max_k
+    } else {
+        (seq_len - 1) | 1
+    };
+
+    let mut index_cmd = std::process::Command::new(salmon);
+    index_cmd
+        .arg("index")
+        .arg("-i")
+        .arg(&output_index_dir)
+        .arg("-t")
+        .arg(&features_file)
+        .arg("--features")
+        .arg("-k")
+        .arg(format!("{}", k))
+        .arg("--threads")
+        .arg(format!("{}", opts.threads));
+    add_extra_args(&mut index_cmd, ExtraArgsFor::SalmonIndex, &opts.index_args)?;
+
+    Ok(FeatureIndexCommands {
+        index: index_cmd,
+        features,
+        geometry,
+        outref,
+        features_file,
+        t2g_file,
+        output_index_dir,
+    })
+}
+
+/// Build the index of the feature reference of `opts`: the barcodes are
+/// written to `ref/features.tsv`, along with an identity transcript to
+/// gene map, and indexed with salmon. The position of the barcodes in the
+/// reads is recorded in `index_info.json` for `quant --feature-type`.
+pub fn run_feature_index(rp: &ReqProgs, opts: &FeatureIndexOpts) -> Result<StepResources> {
+    let output = &opts.output;
+    let FeatureIndexCommands {
+        index: mut index_cmd,
+        features,
+        geometry,
+        outref,
+        features_file,
+        t2g_file,
+        output_index_dir,
+    } = build_feature_index_commands(rp, opts)?;
+    run_fun!(mkdir -p $outref)?;
+
+    let barcodes: Vec<String> = features
+        .iter()
+        .map(|f| format!("{}\t{}", f.id, f.indexed_sequence()))
+        .collect();
+    write_lines(&features_file, &barcodes)?;
+    let t2g: Vec<String> = features
+        .iter()
+        .map(|f| format!("{}\t{}", f.id, f.id))
+        .collect();
+    write_lines(&t2g_file, &t2g)?;
+
+    let mut feature_types: Vec<&str> = features.iter().map(|f| f.feature_type.as_str()).collect();
+    feature_types.sort_unstable();
+    feature_types.dedup();
+
+    let info_file = output.join("index_info.json");
+    let index_info = json!({
+        "command" : "index",
+        "version_info" : rp,
+        "t2g_file" : t2g_file,
+        "feature_geometry" : geometry,
+        "feature_types" : feature_types,
+        "args" : {
+            "feature_ref" : opts.feature_ref,
+            "output" : output,
+            "threads" : opts.threads,
+            "mapper" : Mapper::Salmon.name(),
+            "index_args" : opts.index_args
+        }
+    });
+    std::fs::write(
+        &info_file,
+        serde_json::to_string_pretty(&index_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", info_file.display()))?;
+
+    let mut pipeline = PipelineRun::new(output, "simpleaf_index_log.json", &opts.limits);
+    let index_res = pipeline.run_step(
+        &mut index_cmd,
+        "index",
+        &StepEcho {
+            label: String::from("index"),
+            log_file: Some(output.join("logs").join("index.log")),
+        },
+        "failed to run salmon index",
+        "salmon index",
+    )?;
+
+    std::fs::copy(&t2g_file, output_index_dir.join("t2g.tsv"))?;
+
+    pipeline.check_cancelled("log")?;
+    let index_log_file = output.join("simpleaf_index_log.json");
+    let index_log_info = json!({
+        "time_info" : {
+            "index_time" : time::Duration::seconds_f64(index_res.wall_time_s)
+        },
+        "resource_info" : {
+            "index" : index_res
+        },
+        "resource_limits" : opts.limits
+    });
+    std::fs::write(
+        &index_log_file,
+        serde_json::to_string_pretty(&index_log_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", index_log_file.display()))?;
+    Ok(index_res)
+}
+
+/// Add the commands that `run_feature_index` would run for `opts` to `script`.
+pub fn add_feature_index_to_script(
+    rp: &ReqProgs,
+    opts: &FeatureIndexOpts,
+    script: &mut ShellScript,
+) -> Result<()> {
+    let cmds = build_feature_index_commands(rp, opts)?;
+    script.mkdir(&cmds.outref);
+    script.comment(format!(
+        "simpleaf writes the {} barcodes of {} to {}\nand an identity transcript to gene map to {}",
+        cmds.features.len(),
+        opts.feature_ref.display(),
+        cmds.features_file.display(),
+        cmds.t2g_file.display()
+    ));
+    script.comment("build the salmon index of the feature barcodes");
+    script.command(&cmds.index);
+    script.comment("copy the transcript to gene map into the index");
+    script.args(&[
+        OsStr::new("cp"),
+        cmds.t2g_file.as_os_str(),
+        cmds.output_index_dir.join("t2g.tsv").as_os_str(),
+    ]);
+    Ok(())
+}
+
+/// The permit list of a feature quantification into `output` that
+/// restricts it to the cells of a GEX quantification (see `write_gex_permit_list`).
+pub fn gex_permit_list_path(output: &Path) -> PathBuf {
+    output.join("gex_permit_list.txt")
+}
+
+/// The filtering of a feature quantification into `output` restricted
+/// to the cells of a GEX quantification.
+pub fn gex_filter_method(output: &Path) -> CellFilterMethod {
+    CellFilterMethod::ExplicitList(gex_permit_list_path(output).to_string_lossy().into_owned())
+}
+
+/// The barcode translation table `p` from the GEX barcode (first column)
+/// to the feature barcode (second column) of each cell.
+pub fn read_bc_translation(p: &Path) -> Result<HashMap<String, String>> {
+    let mut table = HashMap::new();
+    for (i, l) in read_lines(p)?.iter().enumerate() {
+        let mut fields = l.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(gex), Some(feature)) => {
+                table.insert(gex.to_string(), feature.to_string());
+            }
+            (None, _) => continue,
+            _ => bail!(
+                "line {} of {} does not have two columns",
+                i + 1,
+                p.display()
+            ),
+        }
+    }
+    Ok(table)
+}
+
+/// Write the barcodes of the cells quantified in `gex_quant`, as they
+/// appear in the feature library (translated with `bc_translation`, if
+/// given), to the permit list of the feature quantification into `output`.
+pub fn write_gex_permit_list(
+    gex_quant: &Path,
+    bc_translation: Option<&Path>,
+    output: &Path,
+) -> Result<()> {
+    let gex_barcodes = QuantDir::locate(gex_quant)?.read_rows()?;
+    let barcodes = match bc_translation {
+        Some(t) => {
+            let table = read_bc_translation(t)?;
+            let mut missing = 0usize;
+            let translated: Vec<String> = gex_barcodes
+                .iter()
+                .filter_map(|b| {
+                    let f = table.get(b).cloned();
+                    missing += f.is_none() as usize;
+                    f
+                })
+                .collect();
+            if missing > 0 {
+                warn!(
+                    "{} of the {} GEX barcodes are not in {}",
+                    missing,
+                    gex_barcodes.len(),
+                    t.display()
+                );
+            }
+            translated
+        }
+        None => gex_barcodes,
+    };
+    write_lines(&gex_permit_list_path(output), &barcodes)
+}
+
+/// Write the count matrix of the feature quantification in `output` with
+/// the rows of the GEX quantification in `gex_quant` (translating the
+/// feature barcodes with `bc_translation`, if given) to `<output>/gex_aligned`.
+/// Cells without features get empty rows. Returns the directory written to.
+pub fn align_to_gex(
+    output: &Path,
+    gex_quant: &Path,
+    bc_translation: Option<&Path>,
+) -> Result<PathBuf> {
+    let gex = QuantDir::locate(gex_quant)?;
+    let features = QuantDir::locate(output)?;
+    let gex_rows = gex.read_rows()?;
+    let gex_index: HashMap<&str, usize> = gex_rows
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.as_str(), i))
+        .collect();
+
+    // from the feature barcodes back to the GEX ones
+    let untranslate: Option<HashMap<String, String>> = match bc_translation {
+        Some(t) => Some(
+            read_bc_translation(t)?
+                .into_iter()
+                .map(|(g, f)| (f, g))
+                .collect(),
+        ),
+        None => None,
+    };
+    let row_map: Vec<Option<usize>> = features
+        .read_rows()?
+        .iter()
+        .map(|b| {
+            let b = match &untranslate {
+                Some(u) => u.get(b).map(String::as_str),
+                None => Some(b.as_str()),
+            };
+            b.and_then(|b| gex_index.get(b).copied())
+        })
+        .collect();
+
+    let dest = output.join("gex_aligned");
+    std::fs::create_dir_all(&dest)
+        .with_context(|| format!("could not create {}", dest.display()))?;
+    let cols = features.read_cols()?;
+    let mut writer = MtxWriter::new(&dest.join("quants_mat.mtx"))?;
+    for_each_mtx_entry(
+        &features.matrix(),
+        row_map.len(),
+        cols.len(),
+        |r, c, v| match row_map[r] {
+            Some(gr) => writer.push(gr, c, v),
+            None => Ok(()),
+        },
+    )?;
+    writer.finish(gex_rows.len(), cols.len())?;
+    write_lines(&dest.join("quants_mat_rows.txt"), &gex_rows)?;
+    write_lines(&dest.join("quants_mat_cols.txt"), &cols)?;
+
+    let matched = row_map.iter().filter(|r| r.is_some()).count();
+    info!(
+        "{} of the {} cells with features are among the {} GEX cells; their counts are in {}",
+        matched,
+        row_map.len(),
+        gex_rows.len(),
+        dest.display()
+    );
+    Ok(dest)
+}
@@ -0,0 +1,217 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::utils::mtx_utils::*;
+
+/// How the guides of a CRISPR screen are assigned to the cells.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "method", content = "min_umis")]
+pub enum GuideCalling {
+    /// every guide with at least this many UMIs in the cell
+    UmiThreshold(f64),
+    /// the guides whose UMIs in the cell are in the upper component of a
+    /// two-component Gaussian mixture of the log UMIs of the guide across
+    /// the cells, and at least this many
+    Mixture(f64),
+}
+
+impl Default for GuideCalling {
+    fn default() -> GuideCalling {
+        GuideCalling::Mixture(DEFAULT_MIN_GUIDE_UMIS)
+    }
+}
+
+/// The fewest UMIs of a guide that a cell is assigned it with, by default.
+pub const DEFAULT_MIN_GUIDE_UMIS: f64 = 3.0;
+
+impl GuideCalling {
+    pub fn from_name(name: &str, min_umis: f64) -> GuideCalling {
+        match name {
+            "umi-threshold" => GuideCalling::UmiThreshold(min_umis),
+            _ => GuideCalling::Mixture(min_umis),
+        }
+    }
+}
+
+/// The UMI threshold of each guide and the number of cells assigned guides.
+#[derive(Debug, Clone, Serialize)]
+pub struct GuideCallSummary {
+    pub calling: GuideCalling,
+    pub calls: PathBuf,
+    /// the fewest UMIs a cell is assigned each guide with, or `None`
+    /// if no cell is assigned it
+    pub thresholds: Vec<(String, Option<f64>)>,
+    pub num_cells: usize,
+    pub num_cells_with_guides: usize,
+    /// the number of cells assigned 1, 2, ... guides
+    pub cells_by_num_guides: Vec<usize>,
+}
+
+fn normal_density(x: f64, mean: f64, var: f64) -> f64 {
+    (-(x - mean).powi(2) / (2.0 * var)).exp() / (2.0 * std::f64::consts::PI * var).sqrt()
+}
+
+// the posterior probability that `x` belongs to the upper of the two
+// components of a mixture with the weight `w1`, means and variances
+fn upper_posterior(x: f64, w1: f64, means: [f64; 2], vars: [f64; 2]) -> f64 {
+    let lo = (1.0 - w1) * normal_density(x, means[0], vars[0]);
+    let hi = w1 * normal_density(x, means[1], vars[1]);
+    if lo + hi > 0.0 {
+        hi / (lo + hi)
+    } else {
+        // far beyond both components; on the side of the nearer mean
+        ((x - means[0]).abs() > (x - means[1]).abs()) as u8 as f64
+    }
+}
+
+/// The fewest UMIs (of at least `min_umis`) at which the upper component
+/// of a two-component Gaussian mixture, fitted by EM to the log UMIs
+/// `umis` of a guide in the cells that have it, is the more likely one.
+pub fn mixture_threshold(umis: &[f64], min_umis: f64) -> Option<f64> {
+    let xs: Vec<f64> = umis.iter().map(|u| u.ln_1p()).collect();
+    let n = xs.len() as f64;
+    let lo = xs.iter().cloned().fold(f64::INFINITY, f64::min);
+    let hi = xs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if xs.len() < 2 || hi - lo < 1e-9 {
+        // a single component, which is taken to be the guide
+        return umis
+            .iter()
+            .cloned()
+            .filter(|&u| u >= min_umis)
+            .reduce(f64::min);
+    }
+
+    let mean = xs.iter().sum::<f64>() / n;
+    let var = (xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).max(1e-3);
+    let mut means = [lo, hi];
+    let mut vars = [var, var];
+    let mut w1 = 0.5;
+    for _ in 0..500 {
+        let post: Vec<f64> = xs
+            .iter()
+            .map(|&x| upper_posterior(x, w1, means, vars))
+            .collect();
+        let n1: f64 = post.iter().sum();
+        let n0 = n - n1;
+        if n1 < 1e-9 || n0 < 1e-9 {
+            break;
+        }
+        let m1 = xs.iter().zip(&post).map(|(x, p)| x * p).sum::<f64>() / n1;
+        let m0 = xs
+            .iter()
+            .zip(&post)
+            .map(|(x, p)| x * (1.0 - p))
+            .sum::<f64>()
+            / n0;
+        let v1 = xs
+            .iter()
+            .zip(&post)
+            .map(|(x, p)| p * (x - m1).powi(2))
+            .sum::<f64>()
+            / n1;
+        let v0 = xs
+            .iter()
+            .zip(&post)
+            .map(|(x, p)| (1.0 - p) * (x - m0).powi(2))
+            .sum::<f64>()
+            / n0;
+        let converged = (m0 - means[0]).abs() < 1e-9 && (m1 - means[1]).abs() < 1e-9;
+        means = [m0, m1];
+        vars = [v0.max(1e-3), v1.max(1e-3)];
+        w1 = n1 / n;
+        if converged {
+            break;
+        }
+    }
+
+    let mut candidates: Vec<f64> = umis.iter().cloned().filter(|&u| u >= min_umis).collect();
+    candidates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    candidates
+        .into_iter()
+        .find(|u| means[1] > means[0] && upper_posterior(u.ln_1p(), w1, means, vars) > 0.5)
+}
+
+/// Assign guides to the cells of the count matrix `matrix`, whose rows are
+/// the cells `rows` and whose columns the guides `cols`, with `calling`.
+/// The guides of every cell assigned any are written to `dest` (the
+/// `guide_calls.tsv` file), with the UMIs of each.
+pub fn call_guides(
+    matrix: &Path,
+    rows: &[String],
+    cols: &[String],
+    calling: GuideCalling,
+    dest: &Path,
+) -> Result<GuideCallSummary> {
+    // the UMIs of each guide in each cell that has it
+    let mut by_guide: Vec<Vec<(usize, f64)>> = vec![Vec::new(); cols.len()];
+    for_each_mtx_entry(matrix, rows.len(), cols.len(), |r, c, v| {
+        if v > 0.0 {
+            by_guide[c].push((r, v));
+        }
+        Ok(())
+    })?;
+
+    let mut thresholds = Vec::with_capacity(cols.len());
+    let mut by_cell: Vec<Vec<(usize, f64)>> = vec![Vec::new(); rows.len()];
+    for (g, umis) in by_guide.iter().enumerate() {
+        let threshold = match calling {
+            GuideCalling::UmiThreshold(m) => Some(m),
+            GuideCalling::Mixture(m) => {
+                let u: Vec<f64> = umis.iter().map(|(_, v)| *v).collect();
+                mixture_threshold(&u, m)
+            }
+        };
+        if let Some(t) = threshold {
+            for &(cell, v) in umis.iter().filter(|(_, v)| *v >= t) {
+                by_cell[cell].push((g, v));
+            }
+        }
+        thresholds.push((cols[g].clone(), threshold));
+    }
+
+    let mut lines = vec![String::from("barcode\tnum_guides\tguides\tumis")];
+    let mut cells_by_num_guides = Vec::new();
+    for (cell, guides) in by_cell.iter_mut().enumerate() {
+        if guides.is_empty() {
+            continue;
+        }
+        guides.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        lines.push(format!(
+            "{}\t{}\t{}\t{}",
+            rows[cell],
+            guides.len(),
+            guides
+                .iter()
+                .map(|(g, _)| cols[*g].as_str())
+                .collect::<Vec<&str>>()
+                .join("|"),
+            guides
+                .iter()
+                .map(|(_, v)| format!("{}", v))
+                .collect::<Vec<String>>()
+                .join("|")
+        ));
+        if cells_by_num_guides.len() < guides.len() {
+            cells_by_num_guides.resize(guides.len(), 0);
+        }
+        cells_by_num_guides[guides.len() - 1] += 1;
+    }
+    write_lines(dest, &lines)?;
+
+    let num_cells_with_guides = lines.len() - 1;
+    info!(
+        "{} of the {} cells were assigned guides; the calls are in {}",
+        num_cells_with_guides,
+        rows.len(),
+        dest.display()
+    );
+    Ok(GuideCallSummary {
+        calling,
+        calls: dest.to_path_buf(),
+        thresholds,
+        num_cells: rows.len(),
+        num_cells_with_guides,
+        cells_by_num_guides,
+    })
+}
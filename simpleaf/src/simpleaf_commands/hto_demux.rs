@@ -0,0 +1,389 @@
+use anyhow::{bail, Context, Result};
+use cmd_lib::run_fun;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::simpleaf_commands::features::*;
+use crate::utils::mtx_utils::*;
+
+#[derive(Debug, Clone)]
+pub struct HtoDemuxOpts {
+    /// the `simpleaf quant --feature-type hto` run
+    pub hto_quant: PathBuf,
+    /// the GEX cells, as a quantification directory or a list of barcodes
+    pub cells: PathBuf,
+    pub bc_translation: Option<PathBuf>,
+    /// the sample of each hashtag, if not named after it
+    pub samples: Option<PathBuf>,
+    /// the quantile of the background distribution of each hashtag
+    /// above which a cell is positive for it
+    pub positive_quantile: f64,
+    pub output: PathBuf,
+}
+
+/// How many hashtags a cell was found positive for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtoClass {
+    Singlet,
+    Doublet,
+    Negative,
+}
+
+impl HtoClass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HtoClass::Singlet => "Singlet",
+            HtoClass::Doublet => "Doublet",
+            HtoClass::Negative => "Negative",
+        }
+    }
+}
+
+/// The negative binomial background of the counts of a hashtag,
+/// fitted by the method of moments (a Poisson if they are not
+/// overdispersed).
+#[derive(Debug, Clone, Copy)]
+pub struct Background {
+    pub mean: f64,
+    /// the size (dispersion) parameter, `None` for a Poisson
+    pub size: Option<f64>,
+}
+
+impl Background {
+    pub fn fit(counts: &[f64]) -> Background {
+        let n = counts.len().max(1) as f64;
+        let mean = counts.iter().sum::<f64>() / n;
+        let var = counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / n;
+        let size = if var > mean {
+            Some(mean * mean / (var - mean))
+        } else {
+            None
+        };
+        Background { mean, size }
+    }
+
+    /// The smallest count whose cumulative probability is at least `q`.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.mean <= 0.0 {
+            return 0;
+        }
+        // the probability of each count follows from that of the previous
+        // one; they are kept as logarithms, as they underflow for large means
+        let mut log_p = match self.size {
+            Some(r) => r * (r / (r + self.mean)).ln(),
+            None => -self.mean,
+        };
+        let mut cdf = log_p.exp();
+        let mut k = 0u64;
+        let limit = (self.mean * 100.0) as u64 + 1000;
+        while cdf < q && k < limit {
+            let kf = k as f64;
+            log_p += match self.size {
+                Some(r) => ((kf + r) / (kf + 1.0) * (self.mean / (r + self.mean))).ln(),
+                None => (self.mean / (kf + 1.0)).ln(),
+            };
+            cdf += log_p.exp();
+            k += 1;
+        }
+        k
+    }
+}
+
+// the fewest cells a cluster needs to serve as the background of a hashtag
+const MIN_BACKGROUND_CELLS: usize = 5;
+
+// the centered log-ratio of the counts of each hashtag (column) across
+// the cells, as in Seurat's HTODemux
+fn clr(counts: &[Vec<f64>], num_htos: usize) -> Vec<Vec<f64>> {
+    let n = counts.len().max(1) as f64;
+    let geo_means: Vec<f64> = (0..num_htos)
+        .map(|h| (counts.iter().map(|c| c[h].ln_1p()).sum::<f64>() / n).exp())
+        .collect();
+    counts
+        .iter()
+        .map(|c| {
+            c.iter()
+                .zip(geo_means.iter())
+                .map(|(x, g)| (x / g).ln_1p())
+                .collect()
+        })
+        .collect()
+}
+
+fn sq_dist(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+// cluster the `points` into `num_htos + 1` groups with k-means, starting
+// from the cell with the highest value of each hashtag and the cell with
+// the lowest total (so that the result does not depend on a seed)
+fn kmeans(points: &[Vec<f64>], num_htos: usize) -> Vec<usize> {
+    let by = |f: &dyn Fn(&Vec<f64>) -> f64, max: bool| {
+        let mut best = 0;
+        for (i, p) in points.iter().enumerate() {
+            let better = if max {
+                f(p) > f(&points[best])
+            } else {
+                f(p) < f(&points[best])
+            };
+            if better {
+                best = i;
+            }
+        }
+        points[best].clone()
+    };
+    let mut centers: Vec<Vec<f64>> = (0..num_htos).map(|h| by(&|p| p[h], true)).collect();
+    centers.push(by(&|p| p.iter().sum(), false));
+
+    let mut assignment = vec![usize::MAX; points.len()];
+    for _ in 0..100 {
+        let mut changed = false;
+        for (i, p) in points.iter().enumerate() {
+            let nearest = (0..centers.len())
+                .min_by(|&a, &b| {
+                    sq_dist(p, &centers[a])
+                        .partial_cmp(&sq_dist(p, &centers[b]))
+                        .unwrap()
+                })
+                .unwrap();
+            if assignment[i] != nearest {
+                assignment[i] = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        for (c, center) in centers.iter_mut().enumerate() {
+            let members: Vec<&Vec<f64>> = points
+                .iter()
+                .zip(assignment.iter())
+                .filter(|(_, &a)| a == c)
+                .map(|(p, _)| p)
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            for (h, x) in center.iter_mut().enumerate() {
+                *x = members.iter().map(|m| m[h]).sum::<f64>() / members.len() as f64;
+            }
+        }
+    }
+    assignment
+}
+
+// the barcodes in `cells`: the rows of a quantification directory, or a
+// file listing one barcode per line
+fn read_cells(cells: &Path) -> Result<Vec<String>> {
+    if cells.is_dir() {
+        QuantDir::locate(cells)?.read_rows()
+    } else {
+        Ok(read_lines(cells)?
+            .into_iter()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect())
+    }
+}
+
+/// Assign the GEX cells of `opts.cells` to samples from the counts of the
+/// hashtags quantified in `opts.hto_quant`, in the manner of Seurat's
+/// HTODemux: the cells are clustered on the centered log-ratio of their
+/// counts, a negative binomial is fitted to the counts of each hashtag in
+/// the cluster where it is lowest, and a cell is positive for a hashtag if
+/// its count is above the `opts.positive_quantile` of that background.
+/// Cells positive for one hashtag are singlets, for several doublets and
+/// for none negatives. The calls are written to `hto_calls.tsv` and the
+/// fitted backgrounds to `hto_demux_summary.json` below `opts.output`.
+pub fn run_hto_demux(opts: &HtoDemuxOpts) -> Result<()> {
+    if !(opts.positive_quantile > 0.0 && opts.positive_quantile < 1.0) {
+        bail!(
+            "--positive-quantile must be in (0, 1), but is {}",
+            opts.positive_quantile
+        );
+    }
+    let qdir = QuantDir::locate(&opts.hto_quant)?;
+    let htos = qdir.read_cols()?;
+    if htos.len() < 2 {
+        bail!(
+            "{} quantifies {} hashtags, but at least 2 are needed to demultiplex",
+            opts.hto_quant.display(),
+            htos.len()
+        );
+    }
+    let cells = read_cells(&opts.cells)?;
+    if cells.is_empty() {
+        bail!("no cells are listed in {}", opts.cells.display());
+    }
+    let cell_index: HashMap<&str, usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.as_str(), i))
+        .collect();
+
+    // the HTO barcodes are those of the GEX cells, unless translated
+    let untranslate: Option<HashMap<String, String>> = match &opts.bc_translation {
+        Some(t) => Some(
+            read_bc_translation(t)?
+                .into_iter()
+                .map(|(g, f)| (f, g))
+                .collect(),
+        ),
+        None => None,
+    };
+    let row_map: Vec<Option<usize>> = qdir
+        .read_rows()?
+        .iter()
+        .map(|b| {
+            let b = match &untranslate {
+                Some(u) => u.get(b).map(String::as_str),
+                None => Some(b.as_str()),
+            };
+            b.and_then(|b| cell_index.get(b).copied())
+        })
+        .collect();
+    let matched = row_map.iter().filter(|r| r.is_some()).count();
+    if matched == 0 {
+        bail!(
+            "none of the barcodes of {} are cells of {}; are the HTO barcodes translated with --bc-translation?",
+            opts.hto_quant.display(),
+            opts.cells.display()
+        );
+    }
+    info!(
+        "{} of the {} cells have hashtag counts",
+        matched,
+        cells.len()
+    );
+
+    let mut counts = vec![vec![0.0; htos.len()]; cells.len()];
+    for_each_mtx_entry(&qdir.matrix(), row_map.len(), htos.len(), |r, c, v| {
+        if let Some(cell) = row_map[r] {
+            counts[cell][c] += v;
+        }
+        Ok(())
+    })?;
+
+    let names: Vec<String> = match &opts.samples {
+        Some(p) => {
+            let mut table = HashMap::new();
+            for l in read_lines(p)? {
+                let mut fields = l.split_whitespace();
+                if let (Some(h), Some(s)) = (fields.next(), fields.next()) {
+                    table.insert(h.to_string(), s.to_string());
+                }
+            }
+            htos.iter()
+                .map(|h| table.get(h).cloned().unwrap_or_else(|| h.clone()))
+                .collect()
+        }
+        None => htos.clone(),
+    };
+
+    // the background of each hashtag is the cluster where it is lowest,
+    // among those large enough to fit a distribution to
+    let norm = clr(&counts, htos.len());
+    let clusters = kmeans(&norm, htos.len());
+    let num_clusters = htos.len() + 1;
+    let mut thresholds = Vec::with_capacity(htos.len());
+    let mut summary = Vec::with_capacity(htos.len());
+    for (h, hto) in htos.iter().enumerate() {
+        let mut sums = vec![0.0; num_clusters];
+        let mut sizes = vec![0usize; num_clusters];
+        for (n, &c) in norm.iter().zip(clusters.iter()) {
+            sums[c] += n[h];
+            sizes[c] += 1;
+        }
+        let min_size = if sizes.iter().any(|&s| s >= MIN_BACKGROUND_CELLS) {
+            MIN_BACKGROUND_CELLS
+        } else {
+            1
+        };
+        let background_cluster = (0..num_clusters)
+            .filter(|&c| sizes[c] >= min_size)
+            .min_by(|&a, &b| {
+                (sums[a] / sizes[a] as f64)
+                    .partial_cmp(&(sums[b] / sizes[b] as f64))
+                    .unwrap()
+            })
+            .unwrap();
+        let background: Vec<f64> = counts
+            .iter()
+            .zip(clusters.iter())
+            .filter(|(_, &c)| c == background_cluster)
+            .map(|(x, _)| x[h])
+            .collect();
+        let fit = Background::fit(&background);
+        let threshold = fit.quantile(opts.positive_quantile);
+        thresholds.push(threshold as f64);
+        summary.push(json!({
+            "hto" : hto,
+            "sample" : names[h],
+            "background_cells" : background.len(),
+            "background_mean" : fit.mean,
+            "background_size" : fit.size,
+            "threshold" : threshold
+        }));
+    }
+
+    let output = &opts.output;
+    run_fun!(mkdir -p $output)?;
+    let mut lines = vec![String::from(
+        "barcode\tclassification\tcall\thto_max\thto_second\tcount_max\tcount_second",
+    )];
+    let mut num_class: HashMap<&str, usize> = HashMap::new();
+    let mut num_sample: HashMap<&str, usize> = HashMap::new();
+    for (cell, c) in cells.iter().zip(counts.iter()) {
+        let positive: Vec<usize> = (0..htos.len()).filter(|&h| c[h] > thresholds[h]).collect();
+        let class = match positive.len() {
+            0 => HtoClass::Negative,
+            1 => HtoClass::Singlet,
+            _ => HtoClass::Doublet,
+        };
+        let call = match class {
+            HtoClass::Singlet => names[positive[0]].as_str(),
+            _ => class.name(),
+        };
+        let mut order: Vec<usize> = (0..htos.len()).collect();
+        order.sort_by(|&a, &b| c[b].partial_cmp(&c[a]).unwrap());
+        lines.push(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            cell,
+            class.name(),
+            call,
+            htos[order[0]],
+            htos[order[1]],
+            c[order[0]],
+            c[order[1]]
+        ));
+        *num_class.entry(class.name()).or_insert(0) += 1;
+        *num_sample.entry(call).or_insert(0) += 1;
+    }
+    write_lines(&output.join("hto_calls.tsv"), &lines)?;
+
+    let summary_file = output.join("hto_demux_summary.json");
+    let summary = json!({
+        "hto_quant" : opts.hto_quant,
+        "cells" : opts.cells,
+        "num_cells" : cells.len(),
+        "num_cells_with_counts" : matched,
+        "positive_quantile" : opts.positive_quantile,
+        "hashtags" : summary,
+        "classifications" : num_class,
+        "calls" : num_sample
+    });
+    std::fs::write(
+        &summary_file,
+        serde_json::to_string_pretty(&summary).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", summary_file.display()))?;
+    info!(
+        "{} singlets, {} doublets and {} negatives; the calls are in {}",
+        num_class.get("Singlet").unwrap_or(&0),
+        num_class.get("Doublet").unwrap_or(&0),
+        num_class.get("Negative").unwrap_or(&0),
+        output.join("hto_calls.tsv").display()
+    );
+    Ok(())
+}
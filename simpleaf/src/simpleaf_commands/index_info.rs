@@ -0,0 +1,137 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use crate::utils::af_utils::*;
+use crate::utils::index_utils::*;
+
+// the outcome of the checks of an index, printed as they are made
+struct CheckReport {
+    num_problems: usize,
+}
+
+impl CheckReport {
+    fn ok<S: AsRef<str>>(&mut self, what: S) {
+        println!("  ok       {}", what.as_ref());
+    }
+    fn problem<S: AsRef<str>>(&mut self, what: S) {
+        self.num_problems += 1;
+        println!("  PROBLEM  {}", what.as_ref());
+    }
+}
+
+fn print_json_file(title: &str, p: Option<&Path>, report: &mut CheckReport) {
+    let p = match p {
+        Some(p) => p,
+        None => return,
+    };
+    println!("== {} ({}) ==", title, p.display());
+    match read_json(p) {
+        Ok(v) => println!("{}", serde_json::to_string_pretty(&v).unwrap()),
+        Err(e) => report.problem(format!("{:#}", e)),
+    }
+    println!();
+}
+
+/// Describe the index in `dir` (the output directory of `simpleaf index`
+/// or the salmon or piscem index inside it) and check that it is intact:
+/// the files of the index must exist, and the transcript to gene map must cover
+/// every target of the index. An error is
+/// returned if any check fails.
+pub fn run_index_info(dir: &Path) -> Result<()> {
+    let idx = IndexDir::locate(dir)?;
+    let mut report = CheckReport { num_problems: 0 };
+
+    if idx.root.is_none() {
+        println!(
+            "{} was not built by simpleaf index; only the index itself is checked\n",
+            idx.index.display()
+        );
+    }
+    print_json_file(
+        "build information",
+        idx.index_info().as_deref(),
+        &mut report,
+    );
+    print_json_file("build log", idx.index_log().as_deref(), &mut report);
+
+    println!("== checks ==");
+    let mapper = idx.mapper().unwrap_or(Mapper::Salmon);
+    let name = mapper.name();
+    if idx.index.is_dir() {
+        report.ok(format!("{} index {}", name, idx.index.display()));
+    } else {
+        report.problem(format!("{} index {} is missing", name, idx.index.display()));
+    }
+    for f in &idx.files(mapper) {
+        let p = idx.index.join(f);
+        if !p.is_file() {
+            report.problem(format!("{} index file {} is missing", name, f));
+        } else if let (true, Err(e)) = (f.ends_with(".json"), read_json(&p)) {
+            report.problem(format!("{:#}", e));
+        } else {
+            report.ok(format!("{} index file {}", name, f));
+        }
+    }
+
+    let t2g = idx.t2g();
+    if !t2g.is_file() {
+        report.problem(format!(
+            "the transcript to gene map {} is missing",
+            t2g.display()
+        ));
+    } else {
+        let ncols = if idx.is_feature_index() { 2 } else { 3 };
+        let (targets, malformed) = read_t2g_targets(&t2g, ncols)?;
+        if malformed.is_empty() {
+            report.ok(format!(
+                "{} lists {} transcripts in {} columns",
+                t2g.display(),
+                targets.len(),
+                ncols
+            ));
+        } else {
+            report.problem(format!(
+                "{} has {} lines without {} columns (the first is line {})",
+                t2g.display(),
+                malformed.len(),
+                ncols,
+                malformed[0]
+            ));
+        }
+
+        // the targets are read from the index itself, as its reference
+        // is not always copied along with it
+        match idx.target_names(mapper) {
+            Ok(names) => {
+                let uncovered: Vec<&String> =
+                    names.iter().filter(|n| !targets.contains(*n)).collect();
+                if uncovered.is_empty() {
+                    report.ok(format!(
+                        "{} covers all {} targets of the index",
+                        t2g.display(),
+                        names.len()
+                    ));
+                } else {
+                    report.problem(format!(
+                        "{} of the {} targets of the index are not in {} (e.g. {})",
+                        uncovered.len(),
+                        names.len(),
+                        t2g.display(),
+                        uncovered[0]
+                    ));
+                }
+            }
+            Err(e) => report.problem(format!("{:#}", e)),
+        }
+    }
+
+    if report.num_problems > 0 {
+        bail!(
+            "found {} problems with the index in {}",
+            report.num_problems,
+            dir.display()
+        );
+    }
+    println!("\nthe index in {} is consistent", dir.display());
+    Ok(())
+}
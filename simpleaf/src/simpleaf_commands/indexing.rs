@@ -0,0 +1,268 @@
+use anyhow::{bail, Context, Result};
+use cmd_lib::run_fun;
+use serde::Serialize;
+use serde_json::json;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use time::Duration;
+
+use crate::utils::af_utils::*;
+use crate::utils::args_utils::*;
+use crate::utils::exec_utils::*;
+use crate::utils::prog_utils::*;
+use crate::utils::resource_utils::*;
+use crate::utils::script_utils::*;
+
+/// Everything needed to build a splici index.
+#[derive(Debug, Clone)]
+pub struct IndexOpts {
+    pub fasta: PathBuf,
+    pub gtf: PathBuf,
+    pub rlen: u32,
+    pub output: PathBuf,
+    pub spliced: Option<PathBuf>,
+    pub unspliced: Option<PathBuf>,
+    pub dedup: bool,
+    pub sparse: bool,
+    pub threads: u32,
+    pub mapper: Mapper,
+    pub pyroe_args: Vec<String>,
+    pub index_args: Vec<String>,
+    /// the resources available to the run, which the peak memory of
+    /// each step is checked against
+    pub limits: ResourceLimits,
+}
+
+/// Resources used by each step of an `index` run.
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexResources {
+    pub pyroe: StepResources,
+    pub index: StepResources,
+}
+
+/// The external commands run by `index`, and the
+/// files they produce.
+pub struct IndexCommands {
+    pub pyroe: std::process::Command,
+    pub index: std::process::Command,
+    pub outref: PathBuf,
+    pub t2g_file: PathBuf,
+    pub output_index_dir: PathBuf,
+}
+
+/// Construct (without running) the splici reference and index
+/// commands for `opts`.
+pub fn build_index_commands(rp: &ReqProgs, opts: &IndexOpts) -> Result<IndexCommands> {
+    let pyroe = match &rp.pyroe {
+        Some(p) => &p.exe_path,
+        None => bail!("no pyroe executable is registered; please run the set-paths command"),
+    };
+    let mapper = &opts.mapper.prog(rp)?.exe_path;
+
+    let output = &opts.output;
+    let ref_file = format!("splici_fl{}.fa", opts.rlen - 5);
+    let outref = output.join("ref");
+    let t2g_file = outref.join(format!("splici_fl{}_t2g_3col.tsv", opts.rlen - 5));
+
+    let mut cmd = std::process::Command::new(format!("{}", pyroe.display()));
+    // we will run the make-splici command
+    cmd.arg("make-splici");
+
+    // if the user wants to dedup output sequences
+    if opts.dedup {
+        cmd.arg(String::from("--dedup-seqs"));
+    }
+
+    // extra spliced sequence
+    if let Some(es) = &opts.spliced {
+        cmd.arg(String::from("--extra-spliced"));
+        cmd.arg(format!("{}", es.display()));
+    }
+
+    // extra unspliced sequence
+    if let Some(eu) = &opts.unspliced {
+        cmd.arg(String::from("--extra-unspliced"));
+        cmd.arg(format!("{}", eu.display()));
+    }
+
+    cmd.arg(&opts.fasta)
+        .arg(&opts.gtf)
+        .arg(format!("{}", opts.rlen))
+        .arg(&outref);
+    add_extra_args(&mut cmd, ExtraArgsFor::Pyroe, &opts.pyroe_args)?;
+
+    let mut index_cmd = std::process::Command::new(format!("{}", mapper.display()));
+    let ref_seq = outref.join(ref_file);
+
+    let output_index_dir = output.join("index");
+    match opts.mapper {
+        Mapper::Salmon => {
+            index_cmd
+                .arg("index")
+                .arg("-i")
+                .arg(&output_index_dir)
+                .arg("-t")
+                .arg(ref_seq);
+
+            // if the user requested a sparse index.
+            if opts.sparse {
+                index_cmd.arg("--sparse");
+            }
+
+            index_cmd.arg("--threads").arg(format!("{}", opts.threads));
+            add_extra_args(&mut index_cmd, ExtraArgsFor::SalmonIndex, &opts.index_args)?;
+        }
+        Mapper::Piscem => {
+            if opts.sparse {
+                bail!("--sparse is only supported by the salmon mapper");
+            }
+            if !opts.index_args.is_empty() {
+                bail!("--salmon-index-args cannot be used with the piscem mapper");
+            }
+            // piscem writes the files of the index next to the given prefix
+            index_cmd
+                .arg("build")
+                .arg("-s")
+                .arg(ref_seq)
+                .arg("-k")
+                .arg("31")
+                .arg("-m")
+                .arg("19")
+                .arg("-t")
+                .arg(format!("{}", opts.threads))
+                .arg("-o")
+                .arg(output_index_dir.join(PISCEM_INDEX_PREFIX));
+        }
+    }
+
+    Ok(IndexCommands {
+        pyroe: cmd,
+        index: index_cmd,
+        outref,
+        t2g_file,
+        output_index_dir,
+    })
+}
+
+/// Build the splici reference and its index as described by
+/// `opts`, recording the run in `index_info.json` and
+/// `simpleaf_index_log.json` below `opts.output`.
+pub fn run_index(rp: &ReqProgs, opts: &IndexOpts) -> Result<IndexResources> {
+    let output = &opts.output;
+    run_fun!(mkdir -p $output)?;
+
+    let IndexCommands {
+        pyroe: mut cmd,
+        index: mut index_cmd,
+        outref,
+        t2g_file,
+        output_index_dir,
+    } = build_index_commands(rp, opts)?;
+
+    run_fun!(mkdir -p $outref)?;
+    run_fun!(mkdir -p $output_index_dir)?;
+
+    let info_file = output.join("index_info.json");
+    let index_info = json!({
+        "command" : "index",
+        "version_info" : rp,
+        "t2g_file" : t2g_file,
+        "args" : {
+            "fasta" : opts.fasta,
+            "gtf" : opts.gtf,
+            "rlen" : opts.rlen,
+            "output" : output,
+            "spliced" : opts.spliced,
+            "unspliced" : opts.unspliced,
+            "dedup" : opts.dedup,
+            "sparse" : opts.sparse,
+            "threads" : opts.threads,
+            "mapper" : opts.mapper.name(),
+            "pyroe_args" : opts.pyroe_args,
+            "index_args" : opts.index_args
+        }
+    });
+
+    std::fs::write(
+        &info_file,
+        serde_json::to_string_pretty(&index_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", info_file.display()))?;
+
+    let mut pipeline = PipelineRun::new(output, "simpleaf_index_log.json", &opts.limits);
+
+    let pyroe_res = pipeline.run_step(
+        &mut cmd,
+        "pyroe",
+        &StepEcho {
+            label: String::from("pyroe"),
+            log_file: Some(output.join("logs").join("pyroe.log")),
+        },
+        "could not execute pyroe",
+        "pyroe",
+    )?;
+    let pyroe_duration = Duration::seconds_f64(pyroe_res.wall_time_s);
+
+    let index_res = pipeline.run_step(
+        &mut index_cmd,
+        "index",
+        &StepEcho {
+            label: String::from("index"),
+            log_file: Some(output.join("logs").join("index.log")),
+        },
+        &format!("failed to run {} index", opts.mapper.name()),
+        &format!("{} index", opts.mapper.name()),
+    )?;
+    let index_duration = Duration::seconds_f64(index_res.wall_time_s);
+
+    // copy over the t2g file to the index
+    let index_t2g_path = output_index_dir.join("t2g_3col.tsv");
+    std::fs::copy(t2g_file, index_t2g_path)?;
+
+    pipeline.check_cancelled("log")?;
+    let index_log_file = output.join("simpleaf_index_log.json");
+    let index_log_info = json!({
+        "time_info" : {
+            "pyroe_time" : pyroe_duration,
+            "index_time" : index_duration
+        },
+        "resource_info" : {
+            "pyroe" : pyroe_res,
+            "index" : index_res
+        },
+        "resource_limits" : opts.limits
+    });
+
+    std::fs::write(
+        &index_log_file,
+        serde_json::to_string_pretty(&index_log_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", index_log_file.display()))?;
+    Ok(IndexResources {
+        pyroe: pyroe_res,
+        index: index_res,
+    })
+}
+
+/// Add the commands that `run_index` would run for `opts` to `script`.
+pub fn add_index_to_script(
+    rp: &ReqProgs,
+    opts: &IndexOpts,
+    script: &mut ShellScript,
+) -> Result<()> {
+    let cmds = build_index_commands(rp, opts)?;
+
+    script.mkdir(&cmds.outref);
+    script.mkdir(&cmds.output_index_dir);
+    script.comment("build the splici reference");
+    script.command(&cmds.pyroe);
+    script.comment(format!("build the {} index", opts.mapper.name()));
+    script.command(&cmds.index);
+    script.comment("copy the transcript to gene map into the index");
+    script.args(&[
+        OsStr::new("cp"),
+        cmds.t2g_file.as_os_str(),
+        cmds.output_index_dir.join("t2g_3col.tsv").as_os_str(),
+    ]);
+    Ok(())
+}
@@ -0,0 +1,11 @@
+pub mod aggr;
+pub mod batch;
+pub mod collapse;
+pub mod features;
+pub mod guides;
+pub mod hto_demux;
+pub mod index_info;
+pub mod indexing;
+pub mod paths;
+pub mod quant;
+pub mod spatial;
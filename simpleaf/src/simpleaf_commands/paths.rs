@@ -0,0 +1,168 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::utils::prog_utils::*;
+
+/// Print the programs recorded in `simpleaf_info.json`.
+pub fn paths_show(af_home_path: &Path) -> Result<()> {
+    let rp = get_required_progs_from_info(af_home_path)?;
+    println!(
+        "programs configured in {}:",
+        af_home_path.join("simpleaf_info.json").display()
+    );
+    for name in SIMPLEAF_PROGS.iter().map(|p| p.name) {
+        match rp.get(name) {
+            Some(p) => {
+                println!("  {:<11} {} ({})", name, p.version, p.exe_path.display());
+                if !p.capabilities.is_empty() {
+                    println!("  {:<11} supports {}", "", p.capabilities.join(" "));
+                }
+            }
+            None => println!("  {:<11} not configured", name),
+        }
+    }
+    Ok(())
+}
+
+/// Probe every configured program again, reporting programs that are
+/// missing, no longer supported, or at a different version than the one
+/// recorded. An error is returned if any program is not usable.
+pub fn paths_check(af_home_path: &Path) -> Result<()> {
+    let rp = get_required_progs_from_info(af_home_path)?;
+    let mut num_problems = 0usize;
+    for prog in SIMPLEAF_PROGS.iter() {
+        let name = prog.name;
+        let recorded = match rp.get(name) {
+            Some(p) => p,
+            None if prog.optional => {
+                println!("  -        {} is not configured (optional)", name);
+                continue;
+            }
+            None => {
+                num_problems += 1;
+                println!("  PROBLEM  {} is not configured", name);
+                continue;
+            }
+        };
+        match probe_prog(name, recorded.exe_path.clone()) {
+            Ok(current) => {
+                // a supported version, but maybe not the one recorded
+                let mut changes = Vec::new();
+                if current.version != recorded.version {
+                    changes.push(format!(
+                        "is now version {} (recorded {})",
+                        current.version, recorded.version
+                    ));
+                }
+                if current.capabilities != recorded.capabilities {
+                    changes.push(format!(
+                        "now supports [{}] (recorded [{}])",
+                        current.capabilities.join(" "),
+                        recorded.capabilities.join(" ")
+                    ));
+                }
+                if changes.is_empty() {
+                    println!(
+                        "  ok       {} {} ({})",
+                        name,
+                        current.version,
+                        current.exe_path.display()
+                    );
+                } else {
+                    println!(
+                        "  changed  {} at {} {}; run `simpleaf paths refresh {}` to record it",
+                        name,
+                        current.exe_path.display(),
+                        changes.join(" and "),
+                        name
+                    );
+                }
+            }
+            Err(e) => {
+                num_problems += 1;
+                println!("  PROBLEM  {:#}", e);
+            }
+        }
+    }
+    if num_problems > 0 {
+        bail!(
+            "{} of the configured programs are not usable; see `simpleaf paths refresh`",
+            num_problems
+        );
+    }
+    Ok(())
+}
+
+/// Update the recorded programs. The programs given a path use that path,
+/// and those named in `tools` are looked up in the PATH again; if neither
+/// names any program, all of them are looked up. The other programs keep
+/// their recorded paths.
+pub fn paths_refresh(
+    af_home_path: &Path,
+    tools: &[String],
+    salmon: Option<PathBuf>,
+    alevin_fry: Option<PathBuf>,
+    pyroe: Option<PathBuf>,
+    piscem: Option<PathBuf>,
+) -> Result<()> {
+    // start from the recorded programs, if there are any; a record that
+    // cannot be read is left for the user to fix rather than replaced
+    let info_file = af_home_path.join("simpleaf_info.json");
+    let mut rp = match std::fs::metadata(&info_file) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => ReqProgs::default(),
+        _ => get_required_progs_from_info(af_home_path).with_context(|| {
+            format!(
+                "could not read the recorded programs; fix or remove {} before refreshing them",
+                info_file.display()
+            )
+        })?,
+    };
+
+    let given = [salmon, alevin_fry, pyroe, piscem];
+    let refresh_all = tools.is_empty() && given.iter().all(Option::is_none);
+    for (prog, path) in SIMPLEAF_PROGS.iter().zip(given) {
+        let name = prog.name;
+        let exe = match path {
+            Some(p) => p,
+            None if tools.iter().any(|t| t == name) => get_which_executable(name)?,
+            None if refresh_all => match get_which_executable(name) {
+                Ok(p) => p,
+                Err(e) if prog.optional => {
+                    println!("{}; leaving it as it is", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            },
+            None => continue,
+        };
+        let info = match probe_prog(name, exe) {
+            Ok(info) => info,
+            Err(e) if refresh_all && prog.optional => {
+                warn!("{:#}; leaving it as it is", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match rp.get(name) {
+            Some(old)
+                if old.exe_path == info.exe_path
+                    && old.version == info.version
+                    && old.capabilities == info.capabilities =>
+            {
+                println!("{} is unchanged", name)
+            }
+            Some(old) => println!(
+                "{}: {} ({}) -> {} ({})",
+                name,
+                old.version,
+                old.exe_path.display(),
+                info.version,
+                info.exe_path.display()
+            ),
+            None => println!("{}: {} ({})", name, info.version, info.exe_path.display()),
+        }
+        rp.set(name, info);
+    }
+
+    write_required_progs(af_home_path, &rp)
+}
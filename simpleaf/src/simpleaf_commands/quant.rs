@@ -0,0 +1,658 @@
+use anyhow::{bail, Context, Result};
+use cmd_lib::run_fun;
+use serde::Serialize;
+use serde_json::json;
+use std::path::PathBuf;
+use time::Duration;
+
+use crate::simpleaf_commands::features::*;
+use crate::simpleaf_commands::guides::*;
+use crate::simpleaf_commands::spatial::*;
+use crate::utils::af_utils::*;
+use crate::utils::args_utils::*;
+use crate::utils::compat_utils::*;
+use crate::utils::exec_utils::*;
+use crate::utils::index_utils::*;
+use crate::utils::mtx_utils::*;
+use crate::utils::prog_utils::*;
+use crate::utils::resource_utils::*;
+use crate::utils::script_utils::*;
+
+/// Everything needed to quantify a single sample
+/// against an existing index.
+#[derive(Debug, Clone)]
+pub struct QuantOpts {
+    pub index: PathBuf,
+    pub reads1: Vec<PathBuf>,
+    pub reads2: Vec<PathBuf>,
+    pub threads: QuantThreads,
+    pub filter_meth: CellFilterMethod,
+    pub resolution: String,
+    pub chemistry: String,
+    pub t2g_map: PathBuf,
+    pub output: PathBuf,
+    pub compat_check: CompatCheck,
+    pub mapper: Mapper,
+    pub mapping: MappingOpts,
+    pub extra_args: QuantExtraArgs,
+    pub feature_type: FeatureType,
+    /// the GEX quantification whose cells the features are quantified
+    /// for, and the translation of its barcodes to the feature barcodes
+    pub gex_quant: Option<PathBuf>,
+    pub bc_translation: Option<PathBuf>,
+    /// how guides are assigned to cells with `FeatureType::Crispr`
+    pub guide_calling: GuideCalling,
+    /// the positions of the spots of a Visium slide, whose barcodes are
+    /// the permit list and which the counts are filtered to
+    pub tissue_positions: Option<PathBuf>,
+    /// the resources available to the run, which the peak memory of
+    /// each step is checked against
+    pub limits: ResourceLimits,
+    /// the name of the sample when it is one of a batch, used
+    /// to tell the output of concurrent samples apart
+    pub sample: Option<String>,
+}
+
+impl QuantOpts {
+    /// How the output of the step `step` is reported; it is
+    /// also written to `<output>/logs/<step>.log`.
+    fn step_echo(&self, step: &str) -> StepEcho {
+        StepEcho {
+            label: match &self.sample {
+                Some(s) => format!("{}:{}", s, step),
+                None => step.to_string(),
+            },
+            log_file: Some(self.output.join("logs").join(format!("{}.log", step))),
+        }
+    }
+}
+
+/// The number of threads given to each multithreaded step of `quant`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QuantThreads {
+    pub map: u32,
+    pub collate: u32,
+    pub quant: u32,
+}
+
+/// How salmon maps the reads to the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MappingMode {
+    /// pseudoalignment with structural constraints (`--sketch`)
+    #[default]
+    Sketch,
+    /// selective alignment, scoring every mapping (`--rad`)
+    SelectiveAlignment,
+}
+
+impl MappingMode {
+    pub fn from_name(name: &str) -> MappingMode {
+        match name {
+            "selective-alignment" => MappingMode::SelectiveAlignment,
+            _ => MappingMode::Sketch,
+        }
+    }
+}
+
+/// The mapping mode and the scoring options of selective alignment.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MappingOpts {
+    pub mode: MappingMode,
+    pub min_score_fraction: Option<f64>,
+    pub softclip: bool,
+    pub softclip_overhangs: bool,
+}
+
+impl MappingOpts {
+    /// The mapping options, refusing scoring options in the sketch
+    /// mode, which does not score the mappings.
+    pub fn new(
+        mode: MappingMode,
+        min_score_fraction: Option<f64>,
+        softclip: bool,
+        softclip_overhangs: bool,
+    ) -> Result<MappingOpts> {
+        if mode == MappingMode::Sketch
+            && (min_score_fraction.is_some() || softclip || softclip_overhangs)
+        {
+            bail!("--min-score-fraction, --softclip and --softclip-overhangs require --mapping-mode selective-alignment");
+        }
+        if let Some(f) = min_score_fraction {
+            if !(f > 0.0 && f <= 1.0) {
+                bail!("--min-score-fraction must be in (0, 1], but is {}", f);
+            }
+        }
+        Ok(MappingOpts {
+            mode,
+            min_score_fraction,
+            softclip,
+            softclip_overhangs,
+        })
+    }
+
+    // the salmon alevin arguments selecting the mapping mode
+    fn salmon_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match self.mode {
+            MappingMode::Sketch => args.push(String::from("--sketch")),
+            MappingMode::SelectiveAlignment => {
+                args.push(String::from("--rad"));
+                if let Some(f) = self.min_score_fraction {
+                    args.push(String::from("--minScoreFraction"));
+                    args.push(format!("{}", f));
+                }
+                if self.softclip {
+                    args.push(String::from("--softclip"));
+                }
+                if self.softclip_overhangs {
+                    args.push(String::from("--softclipOverhangs"));
+                }
+            }
+        }
+        args
+    }
+}
+
+/// Per-step thread counts requested on the command line, which
+/// take precedence over the overall thread count.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadOverrides {
+    pub map: Option<u32>,
+    pub collate: Option<u32>,
+    pub quant: Option<u32>,
+}
+
+impl ThreadOverrides {
+    /// The requested per-step thread counts, each limited to the available CPUs.
+    pub fn new(
+        map: Option<u32>,
+        collate: Option<u32>,
+        quant: Option<u32>,
+        limits: &ResourceLimits,
+    ) -> ThreadOverrides {
+        ThreadOverrides {
+            map: map.map(|t| limits.cap_threads("mapping", t)),
+            collate: collate.map(|t| limits.cap_threads("collate", t)),
+            quant: quant.map(|t| limits.cap_threads("quant", t)),
+        }
+    }
+
+    /// The overrides of each of `num_jobs` samples quantified at once,
+    /// which divide the requested threads of each step between them.
+    pub fn shared(&self, num_jobs: usize) -> ThreadOverrides {
+        let share = |t: Option<u32>| t.map(|t| (t / num_jobs.max(1) as u32).max(1));
+        ThreadOverrides {
+            map: share(self.map),
+            collate: share(self.collate),
+            quant: share(self.quant),
+        }
+    }
+
+    /// The threads for each step, using `threads` for any step without an override.
+    pub fn resolve(&self, threads: u32) -> QuantThreads {
+        QuantThreads {
+            map: self.map.unwrap_or(threads),
+            collate: self.collate.unwrap_or(threads),
+            quant: self.quant.unwrap_or(threads),
+        }
+    }
+}
+
+/// Resources used by each step of a `quant` run.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuantResources {
+    pub map: StepResources,
+    pub gpl: StepResources,
+    pub collate: StepResources,
+    pub quant: StepResources,
+}
+
+/// Wall-clock time taken by each step of a `quant` run,
+/// along with the other resources they used.
+#[derive(Debug, Clone)]
+pub struct QuantTimes {
+    pub map_time: Duration,
+    pub gpl_time: Duration,
+    pub collate_time: Duration,
+    pub quant_time: Duration,
+    pub resources: QuantResources,
+}
+
+/// The external commands run by `quant`, in the order they are run.
+pub struct QuantCommands {
+    pub map: std::process::Command,
+    pub gpl: std::process::Command,
+    pub collate: std::process::Command,
+    pub quant: std::process::Command,
+}
+
+/// Construct (without running) the mapping, permit list generation,
+/// collation and quantification commands for the sample described by `opts`.
+pub fn build_quant_commands(rp: &ReqProgs, opts: &QuantOpts) -> Result<QuantCommands> {
+    let output = &opts.output;
+
+    let alevin_fry = match &rp.alevin_fry {
+        Some(p) => &p.exe_path,
+        None => bail!("no alevin-fry executable is registered; please run the set-paths command"),
+    };
+
+    // location of the reads
+    let r1_str = opts
+        .reads1
+        .iter()
+        .map(|x| format!("{}", x.display()))
+        .collect::<Vec<String>>()
+        .join(",");
+    let r2_str = opts
+        .reads2
+        .iter()
+        .map(|x| format!("{}", x.display()))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let chem = Chemistry::from_name(&opts.chemistry);
+    match (&chem, &opts.tissue_positions) {
+        (Chemistry::Visium, None) => {
+            bail!("the visium chemistry needs the positions of the spots (--tissue-positions)")
+        }
+        (Chemistry::Visium, Some(_)) | (_, None) => {}
+        (_, Some(_)) => bail!(
+            "--tissue-positions requires the visium chemistry, but the chemistry is {}",
+            chem.name()
+        ),
+    }
+    let map_output = output.join("af_map");
+    let mapper_info = opts.mapper.prog(rp)?;
+    let mapper = &mapper_info.exe_path;
+    let mut map_cmd = std::process::Command::new(format!("{}", mapper.display()));
+    match opts.mapper {
+        Mapper::Salmon => {
+            // feature barcodes are found with the geometry recorded in
+            // their index rather than with a chemistry flag
+            let chem_args = if opts.feature_type.is_feature_barcode() {
+                let (bc, umi) = barcode_umi_geometry(&chem)?;
+                vec![
+                    String::from("--read-geometry"),
+                    index_feature_geometry(&opts.index)?,
+                    String::from("--bc-geometry"),
+                    bc.to_string(),
+                    String::from("--umi-geometry"),
+                    umi.to_string(),
+                ]
+            } else {
+                chem.salmon_args()
+            };
+            let mapping_args = opts.mapping.salmon_args();
+            for flag in mapping_args.iter().chain(chem_args.iter()) {
+                if flag.starts_with("--") && mapper_info.lacks("salmon", flag) {
+                    bail!(
+                        "salmon {} at {} does not support the {} option needed by this run",
+                        mapper_info.version,
+                        mapper.display(),
+                        flag
+                    );
+                }
+            }
+
+            // set the input index and library type
+            let index_path = format!("{}", opts.index.display());
+            map_cmd
+                .arg("alevin")
+                .arg("--index")
+                .arg(index_path)
+                .arg("-l")
+                .arg("A");
+
+            map_cmd.arg("-1").arg(r1_str).arg("-2").arg(r2_str);
+
+            // location of outptu directory, number of threads
+            map_cmd
+                .arg("--threads")
+                .arg(format!("{}", opts.threads.map))
+                .arg("-o")
+                .arg(&map_output);
+            map_cmd.args(mapping_args);
+
+            // setting the technology / chemistry
+            map_cmd.args(chem_args);
+            add_extra_args(&mut map_cmd, ExtraArgsFor::SalmonMap, &opts.extra_args.map)?;
+        }
+        Mapper::Piscem => {
+            if opts.feature_type.is_feature_barcode() {
+                bail!(
+                    "--feature-type {} is only supported by the salmon mapper",
+                    opts.feature_type.name()
+                );
+            }
+            if opts.mapping.mode != MappingMode::Sketch {
+                bail!("piscem only supports --mapping-mode sketch");
+            }
+            if !opts.extra_args.map.is_empty() {
+                bail!("--salmon-map-args cannot be used with the piscem mapper");
+            }
+            // piscem is given the prefix of the index files
+            let index_prefix = match IndexDir::locate(&opts.index) {
+                Ok(idx) => idx.index.join(PISCEM_INDEX_PREFIX),
+                Err(_) => opts.index.join(PISCEM_INDEX_PREFIX),
+            };
+            map_cmd
+                .arg("map-sc")
+                .arg("-i")
+                .arg(index_prefix)
+                .arg("-g")
+                .arg(chem.piscem_geometry()?)
+                .arg("-1")
+                .arg(r1_str)
+                .arg("-2")
+                .arg(r2_str)
+                .arg("-t")
+                .arg(format!("{}", opts.threads.map))
+                .arg("-o")
+                .arg(&map_output);
+        }
+    }
+
+    // alevin-fry generate permit list
+    let mut alevin_gpl_cmd = std::process::Command::new(format!("{}", alevin_fry.display()));
+
+    alevin_gpl_cmd.arg("generate-permit-list");
+    alevin_gpl_cmd.arg("-i").arg(&map_output);
+    alevin_gpl_cmd.arg("-d").arg(chem.expected_ori());
+
+    // add the filter mode
+    add_to_args(&opts.filter_meth, &mut alevin_gpl_cmd);
+
+    let gpl_output = output.join("af_quant");
+    alevin_gpl_cmd.arg("-o").arg(&gpl_output);
+    add_extra_args(&mut alevin_gpl_cmd, ExtraArgsFor::Gpl, &opts.extra_args.gpl)?;
+
+    //
+    // collate
+    //
+    let mut alevin_collate_cmd = std::process::Command::new(format!("{}", alevin_fry.display()));
+
+    alevin_collate_cmd.arg("collate");
+    alevin_collate_cmd.arg("-i").arg(&gpl_output);
+    alevin_collate_cmd.arg("-r").arg(&map_output);
+    alevin_collate_cmd
+        .arg("-t")
+        .arg(format!("{}", opts.threads.collate));
+    add_extra_args(
+        &mut alevin_collate_cmd,
+        ExtraArgsFor::Collate,
+        &opts.extra_args.collate,
+    )?;
+
+    //
+    // quant
+    //
+    let mut alevin_quant_cmd = std::process::Command::new(format!("{}", alevin_fry.display()));
+
+    alevin_quant_cmd
+        .arg("quant")
+        .arg("-i")
+        .arg(&gpl_output)
+        .arg("-o")
+        .arg(&gpl_output);
+    alevin_quant_cmd
+        .arg("-t")
+        .arg(format!("{}", opts.threads.quant));
+    alevin_quant_cmd.arg("-m").arg(&opts.t2g_map);
+    alevin_quant_cmd.arg("-r").arg(&opts.resolution);
+    add_extra_args(
+        &mut alevin_quant_cmd,
+        ExtraArgsFor::FryQuant,
+        &opts.extra_args.quant,
+    )?;
+
+    Ok(QuantCommands {
+        map: map_cmd,
+        gpl: alevin_gpl_cmd,
+        collate: alevin_collate_cmd,
+        quant: alevin_quant_cmd,
+    })
+}
+
+/// Map, generate the permit list, collate and quantify the sample
+/// described by `opts`, writing all results (and the
+/// `simpleaf_quant_log.json` file) below `opts.output`.
+pub fn run_quant(rp: &ReqProgs, opts: &QuantOpts) -> Result<QuantTimes> {
+    let output = &opts.output;
+    check_index_compatibility(
+        rp,
+        &opts.index,
+        &opts.t2g_map,
+        &opts.reads2,
+        opts.mapper,
+        opts.feature_type,
+        opts.compat_check,
+    )?;
+    check_read1_len(&opts.chemistry, &opts.reads1)?;
+    run_fun!(mkdir -p $output)?;
+
+    let QuantCommands {
+        map: mut map_cmd,
+        gpl: mut alevin_gpl_cmd,
+        collate: mut alevin_collate_cmd,
+        quant: mut alevin_quant_cmd,
+    } = build_quant_commands(rp, opts)?;
+
+    let mut pipeline = PipelineRun::new(output, "simpleaf_quant_log.json", &opts.limits);
+
+    // the features are only counted in the cells of the GEX run
+    if let Some(gex) = &opts.gex_quant {
+        write_gex_permit_list(gex, opts.bc_translation.as_deref(), output)?;
+        pipeline.check_cancelled("gex_permit_list")?;
+    }
+    if let Some(positions) = &opts.tissue_positions {
+        write_spot_permit_list(positions, output)?;
+        pipeline.check_cancelled("spot_permit_list")?;
+    }
+
+    let map_res = pipeline.run_step(
+        &mut map_cmd,
+        "map",
+        &opts.step_echo("map"),
+        &format!("failed to execute {} [mapping phase]", opts.mapper.name()),
+        "mapping",
+    )?;
+    let map_duration = Duration::seconds_f64(map_res.wall_time_s);
+
+    let gpl_res = pipeline.run_step(
+        &mut alevin_gpl_cmd,
+        "gpl",
+        &opts.step_echo("gpl"),
+        "could not execute [generate permit list]",
+        "generate-permit-list",
+    )?;
+    let gpl_duration = Duration::seconds_f64(gpl_res.wall_time_s);
+
+    let collate_res = pipeline.run_step(
+        &mut alevin_collate_cmd,
+        "collate",
+        &opts.step_echo("collate"),
+        "could not execute [collate]",
+        "collate",
+    )?;
+    let collate_duration = Duration::seconds_f64(collate_res.wall_time_s);
+
+    let quant_res = pipeline.run_step(
+        &mut alevin_quant_cmd,
+        "quant",
+        &opts.step_echo("quant"),
+        "could not execute [quant]",
+        "quant",
+    )?;
+    let quant_duration = Duration::seconds_f64(quant_res.wall_time_s);
+
+    let gex_aligned = match &opts.gex_quant {
+        Some(gex) => {
+            let dir = align_to_gex(output, gex, opts.bc_translation.as_deref())?;
+            pipeline.check_cancelled("gex_aligned")?;
+            Some(dir)
+        }
+        None => None,
+    };
+
+    let in_tissue = match &opts.tissue_positions {
+        Some(positions) => {
+            let summary = filter_to_tissue(output, positions)?;
+            pipeline.check_cancelled("in_tissue")?;
+            Some(summary)
+        }
+        None => None,
+    };
+
+    // the guides are assigned to the GEX cells if the counts were aligned to them
+    let guide_calls = if opts.feature_type == FeatureType::Crispr {
+        let dest = output.join("guide_calls.tsv");
+        let summary = match &gex_aligned {
+            Some(dir) => call_guides(
+                &dir.join("quants_mat.mtx"),
+                &read_lines(&dir.join("quants_mat_rows.txt"))?,
+                &read_lines(&dir.join("quants_mat_cols.txt"))?,
+                opts.guide_calling,
+                &dest,
+            )?,
+            None => {
+                let qdir = QuantDir::locate(output)?;
+                call_guides(
+                    &qdir.matrix(),
+                    &qdir.read_rows()?,
+                    &qdir.read_cols()?,
+                    opts.guide_calling,
+                    &dest,
+                )?
+            }
+        };
+        pipeline.check_cancelled("guide_calls")?;
+        Some(summary)
+    } else {
+        None
+    };
+
+    // the log of a complete run is only written if it was not interrupted
+    pipeline.check_cancelled("log")?;
+    let af_quant_info_file = output.join("simpleaf_quant_log.json");
+    let af_quant_info = json!({
+        "mapper" : opts.mapper.name(),
+        "mapping" : opts.mapping,
+        "extra_args" : opts.extra_args,
+        "feature_type" : opts.feature_type.name(),
+        "gex_aligned" : gex_aligned,
+        "guide_calls" : guide_calls,
+        "in_tissue" : in_tissue,
+        "threads" : opts.threads,
+        "time_info" : {
+        "map_time" : map_duration,
+        "gpl_time" : gpl_duration,
+        "collate_time" : collate_duration,
+        "quant_time" : quant_duration
+        },
+        "resource_info" : {
+            "map" : map_res,
+            "gpl" : gpl_res,
+            "collate" : collate_res,
+            "quant" : quant_res
+        },
+        "resource_limits" : opts.limits
+    });
+
+    std::fs::write(
+        &af_quant_info_file,
+        serde_json::to_string_pretty(&af_quant_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", af_quant_info_file.display()))?;
+
+    Ok(QuantTimes {
+        map_time: map_duration,
+        gpl_time: gpl_duration,
+        collate_time: collate_duration,
+        quant_time: quant_duration,
+        resources: QuantResources {
+            map: map_res,
+            gpl: gpl_res,
+            collate: collate_res,
+            quant: quant_res,
+        },
+    })
+}
+
+/// Add the commands that `run_quant` would run for `opts` to `script`,
+/// including the download of the permit list if it is not yet present.
+/// Runs with steps that simpleaf carries out itself (`--gex-quant`,
+/// `--tissue-positions` and guide calling) cannot be written as a script
+/// and are refused.
+pub fn add_quant_to_script(
+    rp: &ReqProgs,
+    opts: &QuantOpts,
+    script: &mut ShellScript,
+) -> Result<()> {
+    if let Some(loc) = permit_list_to_download(opts)? {
+        add_permit_list_download_to_script(&loc, script)?;
+    }
+    add_quant_steps_to_script(rp, opts, script)
+}
+
+/// The permit list that has to be downloaded before `opts` can run, if any.
+pub fn permit_list_to_download(opts: &QuantOpts) -> Result<Option<PermitListLocation>> {
+    if let CellFilterMethod::UnfilteredExternalList(pl, _) = &opts.filter_meth {
+        let chem = Chemistry::from_name(&opts.chemistry);
+        if let Some(loc) = get_permit_list_location(&chem)? {
+            if !loc.path.exists() && loc.path.as_os_str() == pl.as_str() {
+                return Ok(Some(loc));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Add the download of the permit list `loc` to `script`.
+pub fn add_permit_list_download_to_script(
+    loc: &PermitListLocation,
+    script: &mut ShellScript,
+) -> Result<()> {
+    let dl_cmd = permit_list_download_cmd(loc)?;
+    script.comment("download the permit list");
+    // the location is always inside of $ALEVIN_FRY_HOME/plist
+    script.mkdir(loc.path.parent().unwrap());
+    script.command(&dl_cmd);
+    Ok(())
+}
+
+/// Like `add_quant_to_script`, but without the download of the permit list.
+pub fn add_quant_steps_to_script(
+    rp: &ReqProgs,
+    opts: &QuantOpts,
+    script: &mut ShellScript,
+) -> Result<()> {
+    let internal_step = if opts.gex_quant.is_some() {
+        Some("--gex-quant restricts the features to the cells of the GEX quantification")
+    } else if opts.tissue_positions.is_some() {
+        Some("--tissue-positions restricts the counts to the spots under the tissue")
+    } else if opts.feature_type == FeatureType::Crispr {
+        Some("--feature-type crispr assigns guides to the cells")
+    } else {
+        None
+    };
+    if let Some(what) = internal_step {
+        bail!(
+            "--dry-run cannot describe this run: {} within simpleaf rather than with an external command; run it without --dry-run",
+            what
+        );
+    }
+
+    let cmds = build_quant_commands(rp, opts)?;
+
+    script.mkdir(&opts.output);
+    script.comment("map");
+    script.command(&cmds.map);
+    script.comment("generate permit list");
+    script.command(&cmds.gpl);
+    script.comment("collate");
+    script.command(&cmds.collate);
+    script.comment("quant");
+    script.command(&cmds.quant);
+    Ok(())
+}
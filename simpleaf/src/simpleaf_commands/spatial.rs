@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::utils::af_utils::*;
+use crate::utils::mtx_utils::*;
+
+/// A spot of a Visium slide, as listed in the `tissue_positions.csv`
+/// file of Space Ranger.
+#[derive(Debug, Clone)]
+pub struct Spot {
+    /// the barcode of the spot as it appears in the reads (without the
+    /// `-1` suffix of Space Ranger)
+    pub barcode: String,
+    pub in_tissue: bool,
+    /// `array_row`, `array_col`, `pxl_row_in_fullres` and `pxl_col_in_fullres`
+    pub coords: [String; 4],
+}
+
+const TISSUE_POSITIONS_HEADER: &str =
+    "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres";
+
+/// Parse the tissue positions `p`, either the `tissue_positions.csv` of
+/// Space Ranger 2 (with a header) or the `tissue_positions_list.csv` of
+/// earlier versions (without one).
+pub fn parse_tissue_positions(p: &Path) -> Result<Vec<Spot>> {
+    let mut spots = Vec::new();
+    for (i, l) in read_lines(p)?.iter().enumerate() {
+        if l.trim().is_empty() || (i == 0 && l.starts_with("barcode,")) {
+            continue;
+        }
+        let fields: Vec<&str> = l.split(',').map(str::trim).collect();
+        if fields.len() != 6 {
+            bail!(
+                "line {} of {} has {} columns rather than the 6 of {}",
+                i + 1,
+                p.display(),
+                fields.len(),
+                TISSUE_POSITIONS_HEADER
+            );
+        }
+        let in_tissue = match fields[1] {
+            "1" => true,
+            "0" => false,
+            v => bail!(
+                "line {} of {} has in_tissue {}, which is neither 0 nor 1",
+                i + 1,
+                p.display(),
+                v
+            ),
+        };
+        for f in &fields[2..] {
+            if f.parse::<f64>().is_err() {
+                bail!(
+                    "line {} of {} has the coordinate {}, which is not a number",
+                    i + 1,
+                    p.display(),
+                    f
+                );
+            }
+        }
+        let barcode = match fields[0].split_once('-') {
+            Some((b, _)) => b,
+            None => fields[0],
+        };
+        spots.push(Spot {
+            barcode: barcode.to_string(),
+            in_tissue,
+            coords: [
+                fields[2].to_string(),
+                fields[3].to_string(),
+                fields[4].to_string(),
+                fields[5].to_string(),
+            ],
+        });
+    }
+    if !spots.iter().any(|s| s.in_tissue) {
+        bail!("{} lists no spots under the tissue", p.display());
+    }
+    Ok(spots)
+}
+
+/// The permit list of a Visium quantification into `output`: the
+/// barcodes of all the spots of the slide.
+pub fn spot_permit_list_path(output: &Path) -> PathBuf {
+    output.join("spot_barcodes.txt")
+}
+
+/// The filtering of a Visium quantification into `output`, which corrects
+/// the barcodes to those of the spots of the slide. Every spot with reads
+/// is kept, as the spots are only filtered to those under the tissue
+/// afterwards.
+pub fn spot_filter_method(output: &Path) -> CellFilterMethod {
+    CellFilterMethod::UnfilteredExternalList(
+        spot_permit_list_path(output).to_string_lossy().into_owned(),
+        1,
+    )
+}
+
+/// Write the barcodes of the spots in `tissue_positions` to the permit
+/// list of the quantification into `output`.
+pub fn write_spot_permit_list(tissue_positions: &Path, output: &Path) -> Result<()> {
+    let spots = parse_tissue_positions(tissue_positions)?;
+    let barcodes: Vec<&str> = spots.iter().map(|s| s.barcode.as_str()).collect();
+    write_lines(&spot_permit_list_path(output), &barcodes)
+}
+
+/// The spots of a Visium quantification that are under the tissue.
+#[derive(Debug, Clone, Serialize)]
+pub struct InTissueSummary {
+    pub dir: PathBuf,
+    pub num_spots: usize,
+    pub num_in_tissue: usize,
+    /// the spots under the tissue with any counts
+    pub num_in_tissue_detected: usize,
+}
+
+/// Write the count matrix of the Visium quantification in `output`,
+/// restricted to the spots under the tissue in `tissue_positions`, to
+/// `<output>/in_tissue`, along with `spot_positions.csv`, which holds the
+/// position of the spot of each row of the matrix in the columns of
+/// `tissue_positions.csv`. Spots without counts get empty rows.
+pub fn filter_to_tissue(output: &Path, tissue_positions: &Path) -> Result<InTissueSummary> {
+    let spots = parse_tissue_positions(tissue_positions)?;
+    let in_tissue: Vec<&Spot> = spots.iter().filter(|s| s.in_tissue).collect();
+    let spot_index: HashMap<&str, usize> = in_tissue
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (s.barcode.as_str(), i))
+        .collect();
+
+    let quant = QuantDir::locate(output)?;
+    let row_map: Vec<Option<usize>> = quant
+        .read_rows()?
+        .iter()
+        .map(|b| spot_index.get(b.as_str()).copied())
+        .collect();
+
+    let dest = output.join("in_tissue");
+    std::fs::create_dir_all(&dest)
+        .with_context(|| format!("could not create {}", dest.display()))?;
+    let cols = quant.read_cols()?;
+    let mut writer = MtxWriter::new(&dest.join("quants_mat.mtx"))?;
+    for_each_mtx_entry(
+        &quant.matrix(),
+        row_map.len(),
+        cols.len(),
+        |r, c, v| match row_map[r] {
+            Some(sr) => writer.push(sr, c, v),
+            None => Ok(()),
+        },
+    )?;
+    writer.finish(in_tissue.len(), cols.len())?;
+    let barcodes: Vec<&str> = in_tissue.iter().map(|s| s.barcode.as_str()).collect();
+    write_lines(&dest.join("quants_mat_rows.txt"), &barcodes)?;
+    write_lines(&dest.join("quants_mat_cols.txt"), &cols)?;
+
+    let mut positions = vec![String::from(TISSUE_POSITIONS_HEADER)];
+    positions.extend(
+        in_tissue
+            .iter()
+            .map(|s| format!("{},1,{}", s.barcode, s.coords.join(","))),
+    );
+    write_lines(&dest.join("spot_positions.csv"), &positions)?;
+
+    let detected = row_map.iter().filter(|r| r.is_some()).count();
+    info!(
+        "{} of the {} spots under the tissue have counts; their counts and positions are in {}",
+        detected,
+        in_tissue.len(),
+        dest.display()
+    );
+    Ok(InTissueSummary {
+        dir: dest,
+        num_spots: spots.len(),
+        num_in_tissue: in_tissue.len(),
+        num_in_tissue_detected: detected,
+    })
+}
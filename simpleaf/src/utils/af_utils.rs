@@ -1,3 +1,365 @@
+use anyhow::{anyhow, bail, Result};
+use cmd_lib::run_fun;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::utils::prog_utils::*;
+
+pub enum Chemistry {
+    TenxV2,
+    TenxV3,
+    /// 10x v3.1 (Next GEM), laid out as 10x v3
+    TenxV3_1,
+    /// 10x 5' v2, whose barcodes and UMIs are laid out as those of
+    /// 10x v2, but whose reads are reverse complementary to the transcripts
+    Tenx5V2,
+    /// 10x 5' v3, laid out as 10x v3 but with its own permit list
+    Tenx5V3,
+    /// 10x Visium spatial gene expression, whose spot barcodes and
+    /// UMIs are laid out as those of 10x v3
+    Visium,
+    DropSeq,
+    IndropV2,
+    /// inDrops v3, whose two barcode reads (8 bp, and 8 bp + 6 bp UMI)
+    /// are concatenated into read 1
+    IndropV3,
+    CelSeq2,
+    SciSeq3,
+    SplitSeqV1,
+    SplitSeqV2,
+    Other(String),
+}
+
+/// The W1 adapter between the two parts of the cell barcode of inDrops v2.
+const INDROP_W1: &str = "GAGTGATTGCTTGTGACGCCTT";
+
+impl Chemistry {
+    pub fn from_name(chem: &str) -> Chemistry {
+        match chem {
+            "10xv2" => Chemistry::TenxV2,
+            "10xv3" => Chemistry::TenxV3,
+            "10xv3.1" => Chemistry::TenxV3_1,
+            "10x5v2" => Chemistry::Tenx5V2,
+            "10x5v3" => Chemistry::Tenx5V3,
+            "visium" => Chemistry::Visium,
+            // these were passed on as salmon flags (e.g. `indropV2`) before
+            // they were registered, so their case does not matter
+            s => match s.to_ascii_lowercase().as_str() {
+                "dropseq" => Chemistry::DropSeq,
+                "indropv2" => Chemistry::IndropV2,
+                "indropv3" => Chemistry::IndropV3,
+                "celseq2" => Chemistry::CelSeq2,
+                "sciseq3" => Chemistry::SciSeq3,
+                "splitseqv1" => Chemistry::SplitSeqV1,
+                "splitseqv2" => Chemistry::SplitSeqV2,
+                _ => Chemistry::Other(s.to_string()),
+            },
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Chemistry::TenxV2 => "10xv2",
+            Chemistry::TenxV3 => "10xv3",
+            Chemistry::TenxV3_1 => "10xv3.1",
+            Chemistry::Tenx5V2 => "10x5v2",
+            Chemistry::Tenx5V3 => "10x5v3",
+            Chemistry::Visium => "visium",
+            Chemistry::DropSeq => "dropseq",
+            Chemistry::IndropV2 => "indropv2",
+            Chemistry::IndropV3 => "indropv3",
+            Chemistry::CelSeq2 => "celseq2",
+            Chemistry::SciSeq3 => "sciseq3",
+            Chemistry::SplitSeqV1 => "splitseqv1",
+            Chemistry::SplitSeqV2 => "splitseqv2",
+            Chemistry::Other(s) => s,
+        }
+    }
+
+    // the arguments that tell `salmon alevin`
+    // which technology / chemistry to expect
+    pub fn salmon_args(&self) -> Vec<String> {
+        let args: &[&str] = match self {
+            Chemistry::TenxV2 | Chemistry::Tenx5V2 => &["--chromium"],
+            Chemistry::TenxV3 | Chemistry::TenxV3_1 | Chemistry::Tenx5V3 | Chemistry::Visium => {
+                &["--chromiumV3"]
+            }
+            Chemistry::DropSeq => &["--dropseq"],
+            Chemistry::IndropV2 => &["--indropV2", "--w1", INDROP_W1],
+            Chemistry::IndropV3 => &[
+                "--bc-geometry",
+                "1[1-16]",
+                "--umi-geometry",
+                "1[17-22]",
+                "--read-geometry",
+                "2[1-end]",
+            ],
+            Chemistry::CelSeq2 => &["--celseq2"],
+            Chemistry::SciSeq3 => &["--sciseq3"],
+            Chemistry::SplitSeqV1 => &["--splitseqV1"],
+            Chemistry::SplitSeqV2 => &["--splitseqV2"],
+            Chemistry::Other(s) => return vec![format!("--{}", s)],
+        };
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    // the `--geometry` of `piscem map-sc` for this chemistry
+    pub fn piscem_geometry(&self) -> Result<&'static str> {
+        match self {
+            Chemistry::TenxV2 | Chemistry::Tenx5V2 => Ok("chromium_v2"),
+            Chemistry::TenxV3 | Chemistry::TenxV3_1 | Chemistry::Tenx5V3 | Chemistry::Visium => {
+                Ok("chromium_v3")
+            }
+            Chemistry::DropSeq => Ok("1{b[12]u[8]x:}2{r:}"),
+            Chemistry::IndropV3 => Ok("1{b[16]u[6]x:}2{r:}"),
+            Chemistry::CelSeq2 => Ok("1{u[6]b[6]x:}2{r:}"),
+            Chemistry::IndropV2 => bail!(
+                "piscem does not support the indropv2 chemistry, whose cell barcodes vary in length; map its reads with --mapper salmon"
+            ),
+            Chemistry::SciSeq3 | Chemistry::SplitSeqV1 | Chemistry::SplitSeqV2 => bail!(
+                "piscem does not support the {} chemistry, whose cell barcodes are split by linkers; map its reads with --mapper salmon",
+                self.name()
+            ),
+            Chemistry::Other(s) => bail!("piscem does not support the {} chemistry", s),
+        }
+    }
+
+    /// The orientation of the mapped reads relative to the transcripts
+    /// (the `--expected-ori` of `alevin-fry generate-permit-list`).
+    pub fn expected_ori(&self) -> &'static str {
+        match self {
+            // the 5' libraries read the first strand of the cDNA
+            Chemistry::Tenx5V2 | Chemistry::Tenx5V3 => "rc",
+            // random hexamers prime the reverse transcription as well as oligo-dT
+            Chemistry::SplitSeqV1 | Chemistry::SplitSeqV2 => "both",
+            _ => "fw",
+        }
+    }
+
+    /// The cell filtering used when none is requested, for the chemistries
+    /// that have no permit list to choose between.
+    pub fn default_filter(&self) -> Option<CellFilterMethod> {
+        match self {
+            Chemistry::DropSeq
+            | Chemistry::IndropV2
+            | Chemistry::IndropV3
+            | Chemistry::SciSeq3
+            | Chemistry::SplitSeqV1
+            | Chemistry::SplitSeqV2 => Some(CellFilterMethod::KneeFinding),
+            // the wells of a plate
+            Chemistry::CelSeq2 => Some(CellFilterMethod::ForceCells(96)),
+            Chemistry::TenxV2
+            | Chemistry::TenxV3
+            | Chemistry::TenxV3_1
+            | Chemistry::Tenx5V2
+            | Chemistry::Tenx5V3
+            | Chemistry::Visium
+            | Chemistry::Other(_) => None,
+        }
+    }
+
+    /// The shortest read 1 that holds the barcode and UMI of this
+    /// chemistry, and what it has to hold, for the chemistries whose read 1
+    /// is easily given in the wrong form.
+    pub fn min_read1_len(&self) -> Option<(usize, &'static str)> {
+        match self {
+            Chemistry::IndropV3 => Some((
+                22,
+                "both 8 bp barcodes and the 6 bp UMI, with its two barcode reads concatenated",
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of molecules a library captures: gene expression (GEX),
+/// or one of the feature barcodes of a feature reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureType {
+    Gex,
+    /// antibody-derived tags (CITE-seq)
+    Adt,
+    /// hashtag oligos (cell hashing)
+    Hto,
+    /// guide RNAs (CRISPR screens)
+    Crispr,
+}
+
+impl FeatureType {
+    pub fn from_name(name: &str) -> FeatureType {
+        match name {
+            "adt" => FeatureType::Adt,
+            "hto" => FeatureType::Hto,
+            "crispr" => FeatureType::Crispr,
+            _ => FeatureType::Gex,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeatureType::Gex => "gex",
+            FeatureType::Adt => "adt",
+            FeatureType::Hto => "hto",
+            FeatureType::Crispr => "crispr",
+        }
+    }
+
+    /// Whether the library is quantified against a feature reference.
+    pub fn is_feature_barcode(&self) -> bool {
+        *self != FeatureType::Gex
+    }
+}
+
+/// The prefix of the files of a piscem index, inside the index directory.
+pub const PISCEM_INDEX_PREFIX: &str = "piscem_idx";
+
+/// The program that maps the reads to the index, producing
+/// the RAD file that alevin-fry processes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapper {
+    Salmon,
+    Piscem,
+}
+
+impl Mapper {
+    pub fn from_name(name: &str) -> Mapper {
+        match name {
+            "piscem" => Mapper::Piscem,
+            _ => Mapper::Salmon,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mapper::Salmon => "salmon",
+            Mapper::Piscem => "piscem",
+        }
+    }
+
+    /// The registered executable of the mapper.
+    pub fn prog<'a>(&self, rp: &'a ReqProgs) -> Result<&'a ProgInfo> {
+        match rp.get(self.name()) {
+            Some(p) => Ok(p),
+            None => bail!(
+                "no {} executable is registered; please run the set-paths command",
+                self.name()
+            ),
+        }
+    }
+
+    /// The mapper whose index is in `dir`, if it can be told from its files.
+    pub fn of_index_files(dir: &Path) -> Option<Mapper> {
+        if dir.join(format!("{}.sshash", PISCEM_INDEX_PREFIX)).exists() {
+            Some(Mapper::Piscem)
+        } else if dir.join("versionInfo.json").exists() {
+            Some(Mapper::Salmon)
+        } else {
+            None
+        }
+    }
+}
+
+pub enum PermitListResult {
+    DownloadSuccessful(PathBuf),
+    AlreadyPresent(PathBuf),
+    UnregisteredChemistry,
+}
+
+/// Where the permit list of a registered chemistry is kept
+/// (below `$ALEVIN_FRY_HOME/plist`), where it is downloaded from
+/// and the barcode list of 10x Genomics that it is.
+pub struct PermitListLocation {
+    pub path: PathBuf,
+    /// `None` for the lists that simpleaf cannot download, which
+    /// have to be placed at `path`
+    pub url: Option<&'static str>,
+    /// the gzipped barcode list within a Cell Ranger installation
+    pub source: &'static str,
+}
+
+pub fn get_permit_list_location(chem: &Chemistry) -> Result<Option<PermitListLocation>> {
+    let chem_file;
+    let dl_url;
+    let source;
+    match chem {
+        // the 5' v2 cells are barcoded as those of v2
+        Chemistry::TenxV2 | Chemistry::Tenx5V2 => {
+            chem_file = "10x_v2_permit.txt";
+            dl_url = Some("https://umd.box.com/shared/static/jbs2wszgbj7k4ic2hass9ts6nhqkwq1p");
+            source = "lib/python/cellranger/barcodes/737K-august-2016.txt.gz";
+        }
+        Chemistry::TenxV3 | Chemistry::TenxV3_1 => {
+            chem_file = "10x_v3_permit.txt";
+            dl_url = Some("https://umd.box.com/shared/static/eo0qlkfqf2v24ws6dfnxty6gqk1otf2h");
+            source = "lib/python/cellranger/barcodes/3M-february-2018.txt.gz";
+        }
+        Chemistry::Tenx5V3 => {
+            chem_file = "10x_5v3_permit.txt";
+            dl_url = None;
+            source = "lib/python/cellranger/barcodes/3M-5pgex-jan-2023.txt.gz";
+        }
+        _ => {
+            return Ok(None);
+        }
+    }
+    match env::var("ALEVIN_FRY_HOME") {
+        Ok(p) => Ok(Some(PermitListLocation {
+            path: PathBuf::from(p).join("plist").join(chem_file),
+            url: dl_url,
+            source,
+        })),
+        Err(e) => Err(anyhow!(
+            "could not resolve $ALEVIN_FRY_HOME environment variable : {}",
+            e
+        )),
+    }
+}
+
+/// The `wget` command that downloads the permit list at `loc`, or an
+/// error explaining where to get it if it cannot be downloaded.
+pub fn permit_list_download_cmd(loc: &PermitListLocation) -> Result<std::process::Command> {
+    let url = match loc.url {
+        Some(url) => url,
+        None => bail!(
+            "simpleaf cannot download this permit list, which has to be placed at {}; it is {} in a Cell Ranger installation, and must be gunzipped, e.g. with `gunzip -c <cellranger>/{} > {}`",
+            loc.path.display(),
+            loc.source,
+            loc.source,
+            loc.path.display()
+        ),
+    };
+    let mut dl_cmd = std::process::Command::new("wget");
+    dl_cmd
+        .arg("-v")
+        .arg("-O")
+        .arg(loc.path.to_string_lossy().to_string())
+        .arg("-L")
+        .arg(url);
+    Ok(dl_cmd)
+}
+
+pub fn get_permit_if_absent(chem: &Chemistry) -> Result<PermitListResult> {
+    let loc = match get_permit_list_location(chem)? {
+        Some(loc) => loc,
+        None => {
+            return Ok(PermitListResult::UnregisteredChemistry);
+        }
+    };
+    if loc.path.exists() {
+        Ok(PermitListResult::AlreadyPresent(loc.path))
+    } else {
+        let mut dl_cmd = permit_list_download_cmd(&loc)?;
+        // the location is always inside of $ALEVIN_FRY_HOME/plist
+        let odir = loc.path.parent().unwrap();
+        run_fun!(mkdir -p $odir)?;
+        let r = dl_cmd.output()?;
+        if !r.status.success() {
+            return Err(anyhow!("failed to download permit list {:?}", r.status));
+        }
+        Ok(PermitListResult::DownloadSuccessful(loc.path))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum CellFilterMethod {
     // cut off at this cell in
@@ -20,6 +382,17 @@ pub enum CellFilterMethod {
     KneeFinding,
 }
 
+/// The `quant` option that selects `fm`.
+pub fn filter_method_name(fm: &CellFilterMethod) -> String {
+    match fm {
+        CellFilterMethod::ForceCells(nc) => format!("--forced-cells {}", nc),
+        CellFilterMethod::ExpectCells(nc) => format!("--expect-cells {}", nc),
+        CellFilterMethod::ExplicitList(l) => format!("--explicit-pl {}", l),
+        CellFilterMethod::UnfilteredExternalList(..) => String::from("--unfiltered-pl"),
+        CellFilterMethod::KneeFinding => String::from("--knee"),
+    }
+}
+
 pub fn add_to_args(fm: &CellFilterMethod, cmd: &mut std::process::Command) {
     match fm {
         CellFilterMethod::ForceCells(nc) => {
@@ -42,3 +415,95 @@ pub fn add_to_args(fm: &CellFilterMethod, cmd: &mut std::process::Command) {
         }
     }
 }
+
+/// A cell filtering option of the `quant` command (or of the `filter`
+/// column of a sample sheet), before the permit list of `--unfiltered-pl`
+/// is located.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterChoice {
+    Knee,
+    UnfilteredPl,
+    ExplicitPl(PathBuf),
+    ForcedCells(usize),
+    ExpectCells(usize),
+}
+
+/// Determine the cell filtering method of `chemistry` from the filtering
+/// option `choice`, or from the default of the chemistry if there is
+/// none. If an unfiltered permit list is requested, the permit list for
+/// `chemistry` is fetched into `$ALEVIN_FRY_HOME` if it is not already
+/// present, unless `fetch_pl` is false, in which case only its location
+/// is resolved.
+pub fn get_filter_method(
+    chemistry: &str,
+    fetch_pl: bool,
+    choice: Option<&FilterChoice>,
+) -> Result<CellFilterMethod> {
+    if let Chemistry::Visium = Chemistry::from_name(chemistry) {
+        bail!("the spots of the visium chemistry are filtered with --tissue-positions rather than with a filtering option");
+    }
+    let filter_meth_opt = match choice {
+        Some(FilterChoice::UnfilteredPl) => {
+            // check the chemistry
+            let chem = Chemistry::from_name(chemistry);
+            let pl_path = if fetch_pl {
+                match get_permit_if_absent(&chem)? {
+                    PermitListResult::DownloadSuccessful(p)
+                    | PermitListResult::AlreadyPresent(p) => Some(p),
+                    PermitListResult::UnregisteredChemistry => None,
+                }
+            } else {
+                get_permit_list_location(&chem)?.map(|loc| loc.path)
+            };
+            let min_cells = 10usize;
+            match pl_path {
+                Some(p) => Some(CellFilterMethod::UnfilteredExternalList(
+                    p.to_string_lossy().into_owned(),
+                    min_cells,
+                )),
+                None => match chem {
+                    Chemistry::Other(_) => bail!(
+                        "Cannot use unrecognized chemistry {} with unfiltered permit list.",
+                        chemistry
+                    ),
+                    _ => bail!(
+                        "the cell barcodes of the {} chemistry are not drawn from a known list, so there is no permit list to use with --unfiltered-pl; filter its cells with --knee, --forced-cells, --expect-cells or --explicit-pl instead, or give no filtering option to use its default",
+                        chem.name()
+                    ),
+                },
+            }
+        }
+        Some(FilterChoice::ExplicitPl(filtered_path)) => Some(CellFilterMethod::ExplicitList(
+            filtered_path.to_string_lossy().into_owned(),
+        )),
+        Some(FilterChoice::ForcedCells(num_forced)) => {
+            Some(CellFilterMethod::ForceCells(*num_forced))
+        }
+        Some(FilterChoice::ExpectCells(num_expected)) => {
+            Some(CellFilterMethod::ExpectCells(*num_expected))
+        }
+        Some(FilterChoice::Knee) => Some(CellFilterMethod::KneeFinding),
+        None => None,
+    };
+
+    match filter_meth_opt {
+        Some(m) => Ok(m),
+        None => {
+            let chem = Chemistry::from_name(chemistry);
+            match chem.default_filter() {
+                Some(m) => {
+                    info!(
+                        "filtering the cells of the {} chemistry with its default, {}; give a filtering option to change it",
+                        chem.name(),
+                        filter_method_name(&m)
+                    );
+                    Ok(m)
+                }
+                None => bail!(
+                    "no filtering option was given, and the {} chemistry has no default; use one of --knee, --unfiltered-pl, --explicit-pl, --forced-cells or --expect-cells",
+                    chemistry
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::process::Command;
+
+/// The external commands that accept extra arguments from the command
+/// line, each through its own option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtraArgsFor {
+    Pyroe,
+    SalmonIndex,
+    SalmonMap,
+    Gpl,
+    Collate,
+    FryQuant,
+}
+
+// the mutually exclusive chemistries of salmon alevin
+const SALMON_CHEMISTRIES: &[&str] = &[
+    "--chromium",
+    "--chromiumV3",
+    "--dropseq",
+    "--indropV2",
+    "--citeseq",
+    "--celseq",
+    "--celseq2",
+    "--quartzseq2",
+    "--sciseq3",
+    "--splitseqV1",
+    "--splitseqV2",
+    "--gemcode",
+    "--bc-geometry",
+    "--umi-geometry",
+    "--read-geometry",
+];
+
+impl ExtraArgsFor {
+    /// The option that passes the extra arguments.
+    pub fn option(&self) -> &'static str {
+        match self {
+            ExtraArgsFor::Pyroe => "--pyroe-args",
+            ExtraArgsFor::SalmonIndex => "--salmon-index-args",
+            ExtraArgsFor::SalmonMap => "--salmon-map-args",
+            ExtraArgsFor::Gpl => "--gpl-args",
+            ExtraArgsFor::Collate => "--collate-args",
+            ExtraArgsFor::FryQuant => "--fry-quant-args",
+        }
+    }
+
+    // groups of flags of the command that set the same thing, either as
+    // short and long forms of one flag or as mutually exclusive flags
+    fn synonyms(&self) -> &'static [&'static [&'static str]] {
+        match self {
+            ExtraArgsFor::Pyroe => &[],
+            ExtraArgsFor::SalmonIndex => &[
+                &["-i", "--index"],
+                &["-t", "--transcripts"],
+                &["-k", "--kmerLen"],
+                &["-p", "--threads"],
+            ],
+            ExtraArgsFor::SalmonMap => &[
+                &["-i", "--index"],
+                &["-l", "--libType"],
+                &["-1", "--mates1"],
+                &["-2", "--mates2"],
+                &["-p", "--threads"],
+                &["-o", "--output"],
+                &["--sketch", "--rad"],
+                SALMON_CHEMISTRIES,
+            ],
+            ExtraArgsFor::Gpl => &[
+                &["-i", "--input"],
+                &["-d", "--expected-ori"],
+                &["-o", "--output-dir"],
+                &[
+                    "-k",
+                    "--knee",
+                    "--knee-distance",
+                    "-u",
+                    "--unfiltered-pl",
+                    "-b",
+                    "--valid-bc",
+                    "-f",
+                    "--force",
+                    "--force-cells",
+                    "-e",
+                    "--expect-cells",
+                ],
+            ],
+            ExtraArgsFor::Collate => &[
+                &["-i", "--input-dir"],
+                &["-r", "--rad-dir"],
+                &["-t", "--threads"],
+            ],
+            ExtraArgsFor::FryQuant => &[
+                &["-i", "--input-dir"],
+                &["-o", "--output-dir"],
+                &["-t", "--threads"],
+                &["-m", "--tg-map"],
+                &["-r", "--resolution"],
+            ],
+        }
+    }
+
+    // flags that simpleaf relies on being left at their defaults,
+    // such as those that change the names of the files it reads
+    fn reserved(&self) -> &'static [&'static str] {
+        match self {
+            ExtraArgsFor::Pyroe => &["--flank-trim-length", "--filename-prefix"],
+            _ => &[],
+        }
+    }
+
+    // the first flag of the group `flag` belongs to, or `flag` itself
+    fn canonical<'a>(&self, flag: &'a str) -> &'a str {
+        self.synonyms()
+            .iter()
+            .find(|g| g.contains(&flag))
+            .map(|g| g[0])
+            .unwrap_or(flag)
+    }
+}
+
+/// Split the extra arguments `args` given with `what.option()` as a
+/// shell would.
+pub fn split_extra_args(what: ExtraArgsFor, args: Option<&str>) -> Result<Vec<String>> {
+    match args {
+        None => Ok(Vec::new()),
+        Some(a) => match shlex::split(a) {
+            Some(v) => Ok(v),
+            None => bail!(
+                "could not split the {} `{}` into arguments",
+                what.option(),
+                a
+            ),
+        },
+    }
+}
+
+// the name of the flag in the argument `arg`, if it is one (the mates of
+// salmon and piscem are given with `-1` and `-2`, so negative numbers
+// are taken to be flags as well)
+fn flag_name(arg: &str) -> Option<&str> {
+    if arg.starts_with('-') && arg.len() > 1 {
+        arg.split('=').next()
+    } else {
+        None
+    }
+}
+
+/// Append the extra arguments `extra` to `cmd`, refusing any flag that
+/// sets something `cmd` already sets or that simpleaf relies on.
+pub fn add_extra_args(cmd: &mut Command, what: ExtraArgsFor, extra: &[String]) -> Result<()> {
+    let set_flags: Vec<String> = cmd
+        .get_args()
+        .filter_map(|a| a.to_str().and_then(flag_name).map(String::from))
+        .collect();
+    for flag in extra.iter().filter_map(|a| flag_name(a)) {
+        if what.reserved().contains(&flag) {
+            bail!(
+                "{} cannot contain {}, as simpleaf relies on its default",
+                what.option(),
+                flag
+            );
+        }
+        let canonical = what.canonical(flag);
+        if let Some(set) = set_flags.iter().find(|s| what.canonical(s) == canonical) {
+            bail!(
+                "{} cannot contain {}, as it conflicts with the {} option that simpleaf sets",
+                what.option(),
+                flag,
+                set
+            );
+        }
+    }
+    cmd.args(extra);
+    Ok(())
+}
+
+/// The extra arguments of each step of `quant`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QuantExtraArgs {
+    pub map: Vec<String>,
+    pub gpl: Vec<String>,
+    pub collate: Vec<String>,
+    pub quant: Vec<String>,
+}
@@ -0,0 +1,241 @@
+use anyhow::{bail, Context, Result};
+use semver::Version;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::utils::af_utils::*;
+use crate::utils::index_utils::*;
+use crate::utils::prog_utils::*;
+
+/// What to do when the index does not suit the current tools or reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatCheck {
+    /// refuse to quantify
+    Error,
+    /// warn, but quantify anyway
+    Warn,
+    /// do not check
+    Off,
+}
+
+impl CompatCheck {
+    pub fn from_name(name: &str) -> CompatCheck {
+        match name {
+            "error" => CompatCheck::Error,
+            "off" => CompatCheck::Off,
+            _ => CompatCheck::Warn,
+        }
+    }
+}
+
+/// Reads whose median length is below this fraction of the read
+/// length the index was built for are considered too short.
+const MIN_READ_LEN_FRAC: f64 = 0.8;
+
+/// The number of reads whose length is sampled.
+const READ_LEN_SAMPLE: usize = 1000;
+
+/// Open `p`, decompressing it if it is gzipped.
+fn open_maybe_gz(p: &Path) -> Result<Box<dyn BufRead>> {
+    let mut f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut magic = [0u8; 2];
+    let n = f.read(&mut magic)?;
+    let f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    if n == 2 && magic == [0x1f, 0x8b] {
+        Ok(Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(
+            f,
+        ))))
+    } else {
+        Ok(Box::new(BufReader::new(f)))
+    }
+}
+
+/// The median length of (up to) the first `READ_LEN_SAMPLE` reads of the
+/// FASTQ file `p`, or `None` if it holds no reads.
+fn median_read_len(p: &Path) -> Result<Option<usize>> {
+    let reader = open_maybe_gz(p)?;
+    let mut lens = Vec::with_capacity(READ_LEN_SAMPLE);
+    // the sequence is the second line of each 4-line record
+    for (i, l) in reader.lines().enumerate() {
+        let l = l.with_context(|| format!("could not read {}", p.display()))?;
+        if i % 4 == 1 {
+            lens.push(l.trim_end().len());
+            if lens.len() == READ_LEN_SAMPLE {
+                break;
+            }
+        }
+    }
+    if lens.is_empty() {
+        return Ok(None);
+    }
+    lens.sort_unstable();
+    Ok(Some(lens[lens.len() / 2]))
+}
+
+fn major_version(v: &str) -> Option<u64> {
+    Version::parse(v).ok().map(|v| v.major)
+}
+
+/// The ways in which the index in `index` does not suit the current
+/// tools `rp`, the mapper `mapper`, the transcript to gene map `t2g_map`,
+/// the biological reads `reads` and the library type `feature_type`.
+/// Nothing is reported for indices not built by simpleaf.
+pub fn index_compatibility_issues(
+    rp: &ReqProgs,
+    index: &Path,
+    t2g_map: &Path,
+    reads: &[PathBuf],
+    mapper: Mapper,
+    feature_type: FeatureType,
+) -> Result<Vec<String>> {
+    // an unrecognizable index is reported by the mapper itself
+    let idx = match IndexDir::locate(index) {
+        Ok(idx) => idx,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let info = match idx.read_index_info()? {
+        Some(info) => info,
+        None => {
+            info!(
+                "{} was not built by simpleaf index; skipping the compatibility checks",
+                index.display()
+            );
+            return Ok(Vec::new());
+        }
+    };
+    let mut issues = Vec::new();
+
+    // an index is only readable by the mapper that built it
+    let index_mapper = idx.mapper().unwrap_or(Mapper::Salmon);
+    if index_mapper != mapper {
+        issues.push(format!(
+            "the index was built with {}, but the reads are to be mapped with {}",
+            index_mapper.name(),
+            mapper.name()
+        ));
+    }
+
+    // and only by the major version of it that built the index
+    let name = index_mapper.name();
+    let index_version = info["version_info"][name]["version"].as_str();
+    let current_version = rp.get(name).map(|p| p.version.as_str());
+    if let (Some(built), Some(current)) = (index_version, current_version) {
+        if major_version(built) != major_version(current) {
+            issues.push(format!(
+                "the index was built with {} {}, but {} {} is configured",
+                name, built, name, current
+            ));
+        }
+    }
+
+    // a feature reference index only holds feature barcodes
+    let feature_index = info.get("feature_geometry").is_some();
+    if feature_index != feature_type.is_feature_barcode() {
+        issues.push(format!(
+            "the index was built from {}, but --feature-type is {}",
+            if feature_index {
+                "a feature reference"
+            } else {
+                "a splici reference"
+            },
+            feature_type.name()
+        ));
+    }
+
+    // a splici index is quantified with a 3-column (USA mode) map, and
+    // a feature reference index with its 2-column identity map
+    let (want_cols, reference) = if feature_index {
+        (2, "feature")
+    } else {
+        (3, "splici")
+    };
+    if let Some(first) = open_maybe_gz(t2g_map)?.lines().next() {
+        let first = first.with_context(|| format!("could not read {}", t2g_map.display()))?;
+        let ncols = first.split('\t').count();
+        if ncols != want_cols {
+            issues.push(format!(
+                "the index is a {} reference, which needs a {}-column transcript to gene map, but {} has {} columns",
+                reference,
+                want_cols,
+                t2g_map.display(),
+                ncols
+            ));
+        }
+    }
+
+    // the splici flanks are sized for reads of length `rlen`
+    if let (Some(rlen), Some(r)) = (info["args"]["rlen"].as_u64(), reads.first()) {
+        if let Some(len) = median_read_len(r)? {
+            if (len as f64) < MIN_READ_LEN_FRAC * rlen as f64 {
+                issues.push(format!(
+                    "the index was built for reads of length {}, but the median length of the reads in {} is {}",
+                    rlen,
+                    r.display(),
+                    len
+                ));
+            }
+        }
+    }
+    Ok(issues)
+}
+
+/// Check that each file of `reads1` is long enough to hold the barcode and
+/// UMI of `chemistry` (see `Chemistry::min_read1_len`), judging by the
+/// median length of its first reads.
+pub fn check_read1_len(chemistry: &str, reads1: &[PathBuf]) -> Result<()> {
+    let chem = Chemistry::from_name(chemistry);
+    let (min_len, what) = match chem.min_read1_len() {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    for r in reads1 {
+        if let Some(len) = median_read_len(r)? {
+            if len < min_len {
+                bail!(
+                    "read 1 of the {} chemistry holds {} ({} bp), but the median length of the reads in {} is {}",
+                    chem.name(),
+                    what,
+                    min_len,
+                    r.display(),
+                    len
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the index suits the current tools and reads (see
+/// `index_compatibility_issues`), warning about or failing on any issue
+/// according to `mode`.
+pub fn check_index_compatibility(
+    rp: &ReqProgs,
+    index: &Path,
+    t2g_map: &Path,
+    reads: &[PathBuf],
+    mapper: Mapper,
+    feature_type: FeatureType,
+    mode: CompatCheck,
+) -> Result<()> {
+    if mode == CompatCheck::Off {
+        return Ok(());
+    }
+    let issues = index_compatibility_issues(rp, index, t2g_map, reads, mapper, feature_type)?;
+    if issues.is_empty() {
+        return Ok(());
+    }
+    match mode {
+        CompatCheck::Error => bail!(
+            "the index {} is not compatible with this run ({}); pass --compat-check warn to quantify anyway",
+            index.display(),
+            issues.join("; ")
+        ),
+        _ => {
+            for i in issues {
+                warn!("{}", i);
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// The subcommands that can be described by a run configuration file.
+pub const CONFIGURABLE_COMMANDS: [&str; 2] = ["index", "quant"];
+
+/// Read the run configuration file `config`, which must contain a single
+/// top-level table named after the command it configures (`[index]` or
+/// `[quant]`), and return the command name along with its table.
+pub fn read_run_config(config: &Path) -> Result<(String, toml::value::Table)> {
+    let contents = std::fs::read_to_string(config)
+        .with_context(|| format!("could not read configuration file {}", config.display()))?;
+    let doc: toml::Value = toml::from_str(&contents)
+        .with_context(|| format!("could not parse configuration file {}", config.display()))?;
+
+    let top = match doc {
+        toml::Value::Table(t) => t,
+        _ => bail!("{} is not a TOML table", config.display()),
+    };
+    if top.len() != 1 {
+        bail!(
+            "configuration file {} must contain exactly one top-level table, one of [{}]",
+            config.display(),
+            CONFIGURABLE_COMMANDS.join("], [")
+        );
+    }
+    // there is exactly one entry
+    let (name, fields) = top.into_iter().next().unwrap();
+    if !CONFIGURABLE_COMMANDS.contains(&name.as_str()) {
+        bail!(
+            "configuration file {} describes the `{}` command, but only the {} commands can be configured",
+            config.display(),
+            name,
+            CONFIGURABLE_COMMANDS.join(" and ")
+        );
+    }
+    match fields {
+        toml::Value::Table(t) => Ok((name, t)),
+        _ => bail!("[{}] in {} is not a table", name, config.display()),
+    }
+}
+
+fn find_arg<'a, 'help>(cmd: &'a clap::Command<'help>, id: &str) -> Option<&'a clap::Arg<'help>> {
+    cmd.get_arguments().find(|a| a.get_id() == id)
+}
+
+// whether `arg` is an option that takes a value rather than a flag
+fn takes_value(arg: &clap::Arg) -> bool {
+    !matches!(
+        arg.get_action(),
+        clap::ArgAction::SetTrue | clap::ArgAction::SetFalse | clap::ArgAction::Count
+    ) && arg.is_takes_value_set()
+}
+
+/// The ids of the arguments of `cmd` that appear in the command line
+/// fragment `args`.
+fn given_arg_ids(cmd: &clap::Command, args: &[String]) -> HashSet<String> {
+    let mut ids = HashSet::new();
+    for a in args {
+        if let Some(long) = a.strip_prefix("--") {
+            let long = long.split('=').next().unwrap_or(long);
+            if let Some(arg) = cmd.get_arguments().find(|x| x.get_long() == Some(long)) {
+                ids.insert(arg.get_id().to_string());
+            }
+        } else if let Some(shorts) = a.strip_prefix('-') {
+            for c in shorts.chars() {
+                match cmd.get_arguments().find(|x| x.get_short() == Some(c)) {
+                    Some(arg) => {
+                        ids.insert(arg.get_id().to_string());
+                        // the rest of the token is this option's value
+                        if takes_value(arg) {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    ids
+}
+
+fn toml_scalar_to_arg(key: &str, v: &toml::Value) -> Result<String> {
+    match v {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(format!("{}", i)),
+        toml::Value::Float(f) => Ok(format!("{}", f)),
+        toml::Value::Boolean(b) => Ok(format!("{}", b)),
+        _ => Err(anyhow!(
+            "the value of `{}` must be a string, number or boolean",
+            key
+        )),
+    }
+}
+
+/// Build the command line of the subcommand `name` of `cli` from the
+/// configuration table `fields`, whose keys are the names of the fields
+/// of the corresponding `Commands` variant. Relative paths in `fields`
+/// are resolved against `base`, the directory of the configuration file.
+/// Every argument given in
+/// `overrides` (a command line fragment for the same subcommand) replaces
+/// the value from the configuration, and configured arguments that
+/// conflict with an override are dropped. The returned vector starts with the program
+/// and subcommand names, and can be handed directly to the clap parser.
+pub fn config_to_args(
+    cli: &clap::Command,
+    name: &str,
+    fields: &toml::value::Table,
+    base: &Path,
+    overrides: &[String],
+) -> Result<Vec<String>> {
+    let cmd = cli
+        .find_subcommand(name)
+        .ok_or_else(|| anyhow!("unknown command `{}`", name))?;
+
+    let overridden = given_arg_ids(cmd, overrides);
+
+    let mut args = vec![cli.get_name().to_string(), name.to_string()];
+    for (key, val) in fields.iter() {
+        let id = key.replace('_', "-");
+        let arg = find_arg(cmd, &id)
+            .filter(|a| a.get_id() != "help" && a.get_id() != "version")
+            .ok_or_else(|| anyhow!("`{}` is not a field of the `{}` command", key, name))?;
+        if overridden.contains(&id) {
+            continue;
+        }
+        let key_args = arg_tokens(arg, key, val, base)?;
+
+        // a configured argument that conflicts with an override (e.g. a
+        // different filtering method) is dropped in favor of the override.
+        // Group conflicts cannot be inspected through clap, so they are
+        // detected by parsing the argument together with the overrides.
+        if !overrides.is_empty() && !key_args.is_empty() {
+            let trial = args[..2]
+                .iter()
+                .chain(key_args.iter())
+                .chain(overrides.iter());
+            if let Err(e) = cli.clone().try_get_matches_from(trial) {
+                if e.kind() == clap::ErrorKind::ArgumentConflict {
+                    continue;
+                }
+            }
+        }
+        args.extend(key_args);
+    }
+    args.extend(overrides.iter().cloned());
+    Ok(args)
+}
+
+// the command line tokens setting `arg` to the configured value `val`,
+// with relative paths resolved against `base`
+fn arg_tokens(arg: &clap::Arg, key: &str, val: &toml::Value, base: &Path) -> Result<Vec<String>> {
+    let flag = match (arg.get_long(), arg.get_short()) {
+        (Some(l), _) => format!("--{}", l),
+        (None, Some(s)) => format!("-{}", s),
+        (None, None) => bail!("`{}` cannot be set from a configuration file", key),
+    };
+
+    let mut tokens = Vec::new();
+    if !takes_value(arg) {
+        match val {
+            toml::Value::Boolean(true) => tokens.push(flag),
+            toml::Value::Boolean(false) => {}
+            _ => bail!("the value of `{}` must be true or false", key),
+        }
+        return Ok(tokens);
+    }
+    let is_path = arg.get_value_parser().type_id() == clap::value_parser!(PathBuf).type_id();
+    let value = |v: &toml::Value| -> Result<String> {
+        let v = toml_scalar_to_arg(key, v)?;
+        if is_path && Path::new(&v).is_relative() {
+            Ok(base.join(v).to_string_lossy().into_owned())
+        } else {
+            Ok(v)
+        }
+    };
+    match val {
+        toml::Value::Array(vals) => {
+            for v in vals {
+                tokens.push(flag.clone());
+                tokens.push(value(v)?);
+            }
+        }
+        v => {
+            tokens.push(flag);
+            tokens.push(value(v)?);
+        }
+    }
+    Ok(tokens)
+}
+
+fn json_to_toml(v: serde_json::Value) -> Option<toml::Value> {
+    match v {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(b) => Some(toml::Value::Boolean(b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(toml::Value::Integer(i)),
+            None => n.as_f64().map(toml::Value::Float),
+        },
+        serde_json::Value::String(s) => Some(toml::Value::String(s)),
+        serde_json::Value::Array(a) => Some(toml::Value::Array(
+            a.into_iter().filter_map(json_to_toml).collect(),
+        )),
+        serde_json::Value::Object(o) => Some(toml::Value::Table(
+            o.into_iter()
+                .filter_map(|(k, v)| json_to_toml(v).map(|v| (k, v)))
+                .collect(),
+        )),
+    }
+}
+
+/// Render a parsed command as a run configuration file that `simpleaf run`
+/// reads back into the same command. Unset optional arguments are omitted.
+pub fn command_to_config<T: Serialize>(command: &T) -> Result<String> {
+    // TOML cannot directly represent enum variants with fields, so the
+    // command goes through its JSON representation (`{"quant": {...}}`).
+    let v = serde_json::to_value(command)?;
+    let t = json_to_toml(v).ok_or_else(|| anyhow!("cannot represent an empty command"))?;
+    Ok(toml::to_string_pretty(&t)?)
+}
@@ -0,0 +1,541 @@
+use anyhow::{anyhow, bail, Context};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::utils::resource_utils::*;
+
+/// The resources used by one external command of a pipeline.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepResources {
+    /// wall-clock time, in seconds
+    pub wall_time_s: f64,
+    /// user CPU time, in seconds
+    pub user_time_s: Option<f64>,
+    /// system CPU time, in seconds
+    pub sys_time_s: Option<f64>,
+    /// peak resident set size, in kilobytes
+    pub max_rss_kb: Option<u64>,
+    pub exit_code: Option<i32>,
+    /// the signal that terminated the command, if any
+    pub signal: Option<i32>,
+}
+
+impl StepResources {
+    fn from_status(status: &ExitStatus, wall_time: Duration) -> StepResources {
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            status.signal()
+        };
+        #[cfg(not(unix))]
+        let signal = None;
+
+        StepResources {
+            wall_time_s: wall_time.as_secs_f64(),
+            user_time_s: None,
+            sys_time_s: None,
+            max_rss_kb: None,
+            exit_code: status.code(),
+            signal,
+        }
+    }
+}
+
+/// How the output of a pipeline step is reported while it runs.
+pub struct StepEcho {
+    /// the name of the step (e.g. `map`), which prefixes every echoed line
+    pub label: String,
+    /// a file that receives a copy of everything the step writes
+    pub log_file: Option<PathBuf>,
+}
+
+// the steps currently running, shown on the progress line
+static ACTIVE_STEPS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+// serializes writes to the terminal so that lines are not interleaved
+static TERMINAL: Mutex<()> = Mutex::new(());
+// whether the progress ticker is running; it stops once no step is running
+static TICKER_RUNNING: Mutex<bool> = Mutex::new(false);
+
+// how often the progress is logged when stderr is not a terminal
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+fn format_elapsed(d: Duration) -> String {
+    let s = d.as_secs();
+    format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+}
+
+fn progress_line() -> Option<String> {
+    let active = ACTIVE_STEPS.lock().unwrap();
+    if active.is_empty() {
+        return None;
+    }
+    let steps = active
+        .iter()
+        .map(|(label, start)| format!("{} {}", label, format_elapsed(start.elapsed())))
+        .collect::<Vec<String>>()
+        .join(" | ");
+    Some(format!("[simpleaf] running {}", steps))
+}
+
+// redraw the progress line; the caller must hold the TERMINAL lock
+fn redraw_progress(err: &mut std::io::StderrLock) {
+    let _ = write!(err, "\r\x1b[K");
+    if let Some(l) = progress_line() {
+        let _ = write!(err, "{}", l);
+    }
+    let _ = err.flush();
+}
+
+// print `line` from the step `label` above the progress line
+fn echo_line(label: &str, line: &[u8]) {
+    let _guard = TERMINAL.lock().unwrap();
+    let mut err = std::io::stderr().lock();
+    let tty = std::io::stderr().is_terminal();
+    if tty {
+        let _ = write!(err, "\r\x1b[K");
+    }
+    let _ = writeln!(err, "[{}] {}", label, String::from_utf8_lossy(line));
+    if tty {
+        redraw_progress(&mut err);
+    }
+}
+
+/// Start the thread that keeps the progress line up to date, unless it is
+/// already running. On a terminal the line is redrawn every second,
+/// otherwise it is logged every few minutes. The thread exits once no step
+/// is running.
+fn start_progress_ticker() {
+    let mut running = TICKER_RUNNING.lock().unwrap();
+    if *running {
+        return;
+    }
+    *running = true;
+    std::thread::spawn(|| {
+        let tty = std::io::stderr().is_terminal();
+        let mut last_log = Instant::now();
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+            // the steps are checked under the TERMINAL lock, so that the
+            // line is never redrawn after the last step has cleared it
+            let guard = TERMINAL.lock().unwrap();
+            let mut running = TICKER_RUNNING.lock().unwrap();
+            if ACTIVE_STEPS.lock().unwrap().is_empty() {
+                *running = false;
+                return;
+            }
+            drop(running);
+            if tty {
+                redraw_progress(&mut std::io::stderr().lock());
+            } else if last_log.elapsed() >= PROGRESS_LOG_INTERVAL {
+                last_log = Instant::now();
+                let line = progress_line();
+                // the logger takes the TERMINAL lock itself
+                drop(guard);
+                if let Some(l) = line {
+                    info!("{}", l);
+                }
+            }
+        }
+    });
+}
+
+/// A writer for log messages that clears the progress line before each
+/// message and redraws it after, so that the two do not get mixed up.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _guard = TERMINAL.lock().unwrap();
+        let mut err = std::io::stderr().lock();
+        let tty = std::io::stderr().is_terminal();
+        if tty {
+            write!(err, "\r\x1b[K")?;
+        }
+        err.write_all(buf)?;
+        if tty && buf.ends_with(b"\n") {
+            redraw_progress(&mut err);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Copy everything read from `src` to `log` and echo it, line by line, with
+/// the prefix `label`. Carriage returns (used by progress meters) also end
+/// a line. Everything read is returned, like the fields of `Output`.
+fn stream_output<R: Read>(
+    mut src: R,
+    label: &str,
+    log: Option<Arc<Mutex<File>>>,
+) -> std::io::Result<Vec<u8>> {
+    let mut all = Vec::new();
+    let mut line = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let chunk = &buf[..n];
+        all.extend_from_slice(chunk);
+        if let Some(f) = &log {
+            f.lock().unwrap().write_all(chunk)?;
+        }
+        for &b in chunk {
+            if b == b'\n' || b == b'\r' {
+                if !line.is_empty() {
+                    echo_line(label, &line);
+                    line.clear();
+                }
+            } else {
+                line.push(b);
+            }
+        }
+    }
+    if !line.is_empty() {
+        echo_line(label, &line);
+    }
+    Ok(all)
+}
+
+/// Run `cmd` to completion and measure the resources it used. Its stdout
+/// and stderr are echoed live to the terminal with the prefix `[<label>]`,
+/// copied to `echo.log_file` if given, and returned like `Command::output()`
+/// does. While the step runs, a progress line shows how long it has been
+/// running. On unix, the child is reaped with `wait4` so that its CPU time
+/// and peak memory usage are available.
+pub fn run_measured(
+    cmd: &mut Command,
+    echo: &StepEcho,
+) -> std::io::Result<(Output, StepResources)> {
+    let log = match &echo.log_file {
+        Some(p) => {
+            if let Some(parent) = p.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            Some(Arc::new(Mutex::new(File::create(p)?)))
+        }
+        None => None,
+    };
+
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // run the step in its own process group, so that a cancellation
+    // reaches every process it starts
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let start = Instant::now();
+    let mut child = cmd.spawn()?;
+    ACTIVE_STEPS
+        .lock()
+        .unwrap()
+        .push((echo.label.clone(), start));
+    start_progress_ticker();
+
+    // drain both pipes while the child runs so that it never blocks on a full pipe
+    let child_out = child.stdout.take().unwrap();
+    let child_err = child.stderr.take().unwrap();
+    let (out_label, out_log) = (echo.label.clone(), log.clone());
+    let out_reader = std::thread::spawn(move || stream_output(child_out, &out_label, out_log));
+    let (err_label, err_log) = (echo.label.clone(), log);
+    let err_reader = std::thread::spawn(move || stream_output(child_err, &err_label, err_log));
+
+    #[cfg(unix)]
+    let (status, resources) = {
+        let (status, ru) = wait4(child.id())?;
+        let mut resources = StepResources::from_status(&status, start.elapsed());
+        let tv_secs = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6;
+        resources.user_time_s = Some(tv_secs(ru.ru_utime));
+        resources.sys_time_s = Some(tv_secs(ru.ru_stime));
+        // ru_maxrss is reported in bytes on macOS and in kilobytes elsewhere
+        #[cfg(target_os = "macos")]
+        let max_rss_kb = ru.ru_maxrss as u64 / 1024;
+        #[cfg(not(target_os = "macos"))]
+        let max_rss_kb = ru.ru_maxrss as u64;
+        resources.max_rss_kb = Some(max_rss_kb);
+        (status, resources)
+    };
+    #[cfg(not(unix))]
+    let (status, resources) = {
+        let status = child.wait()?;
+        let resources = StepResources::from_status(&status, start.elapsed());
+        (status, resources)
+    };
+
+    let stdout = out_reader.join().unwrap();
+    let stderr = err_reader.join().unwrap();
+    {
+        // the step is removed under the TERMINAL lock, so that the ticker
+        // sees it gone before it could draw the line again
+        let _guard = TERMINAL.lock().unwrap();
+        let mut active = ACTIVE_STEPS.lock().unwrap();
+        if let Some(i) = active
+            .iter()
+            .position(|(l, t)| *l == echo.label && *t == start)
+        {
+            active.remove(i);
+        }
+        drop(active);
+        if std::io::stderr().is_terminal() {
+            redraw_progress(&mut std::io::stderr().lock());
+        }
+    }
+    let (stdout, stderr) = (stdout?, stderr?);
+    Ok((
+        Output {
+            status,
+            stdout,
+            stderr,
+        },
+        resources,
+    ))
+}
+
+/// Wait for the child process `pid` and return its exit
+/// status along with its resource usage.
+#[cfg(unix)]
+fn wait4(pid: u32) -> std::io::Result<(ExitStatus, libc::rusage)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = pid as libc::pid_t;
+    let mut status: libc::c_int = 0;
+    // SAFETY: rusage is a plain C struct for which all zeroes is a valid value
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    // when the signal was forwarded to the child's process group
+    let mut forwarded: Option<Instant> = None;
+    let mut killed = false;
+    loop {
+        // SAFETY: `status` and `ru` are valid for writes for the duration of the call
+        let r = unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut ru) };
+        if r == pid {
+            if forwarded.is_some() {
+                // processes the step left behind would keep its output open
+                // SAFETY: kill has no memory safety requirements
+                unsafe { libc::kill(-pid, libc::SIGKILL) };
+            }
+            return Ok((ExitStatus::from_raw(status), ru));
+        }
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+            continue;
+        }
+
+        // the child is still running
+        if let Some(sig) = cancel_signal() {
+            match forwarded {
+                None => {
+                    warn!(
+                        "received {}; stopping the running step (pid {})",
+                        signal_name(sig),
+                        pid
+                    );
+                    // SAFETY: kill has no memory safety requirements
+                    unsafe { libc::kill(-pid, sig) };
+                    forwarded = Some(Instant::now());
+                }
+                Some(t) => {
+                    // a second signal, or a step that ignores the first one, is killed
+                    let repeated = CANCEL_COUNT.load(Ordering::SeqCst) > 1;
+                    if !killed && (repeated || t.elapsed() >= CANCEL_KILL_TIMEOUT) {
+                        warn!("the running step (pid {}) did not stop; killing it", pid);
+                        // SAFETY: kill has no memory safety requirements
+                        unsafe { libc::kill(-pid, libc::SIGKILL) };
+                        killed = true;
+                    }
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// the signal that asked simpleaf to stop (0 if none), and how many were received
+static CANCEL_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static CANCEL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// How long a step may take to exit after a cancellation signal
+/// was forwarded to it before it is killed.
+pub const CANCEL_KILL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[cfg(unix)]
+extern "C" fn on_cancel_signal(sig: libc::c_int) {
+    // only async-signal-safe operations are allowed here
+    CANCEL_SIGNAL.store(sig, Ordering::SeqCst);
+    CANCEL_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Catch SIGINT and SIGTERM, so that simpleaf can forward them to the
+/// running step and record the interrupted run instead of exiting at once.
+pub fn install_cancel_handlers() {
+    #[cfg(unix)]
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only touches atomics
+        unsafe {
+            let mut act: libc::sigaction = std::mem::zeroed();
+            act.sa_sigaction = on_cancel_signal as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut act.sa_mask);
+            libc::sigaction(sig, &act, std::ptr::null_mut());
+        }
+    }
+}
+
+/// The signal that asked simpleaf to stop, if any was received.
+pub fn cancel_signal() -> Option<i32> {
+    match CANCEL_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// The name of the signal `sig`, for messages and logs.
+pub fn signal_name(sig: i32) -> String {
+    match sig {
+        libc::SIGINT => String::from("SIGINT"),
+        libc::SIGTERM => String::from("SIGTERM"),
+        s => format!("signal {}", s),
+    }
+}
+
+/// The name of the marker file left in the output directory of a run
+/// that was interrupted.
+pub const INCOMPLETE_MARKER: &str = "incomplete";
+
+/// Runs the external steps of a pipeline writing below `output`, keeping
+/// track of the steps that completed. If a step fails, a partial `log_name`
+/// log recording the failed step and its resources is written to `output`.
+/// If the run is cancelled, the `incomplete` marker is written as well.
+pub struct PipelineRun {
+    output: PathBuf,
+    log_name: String,
+    limits: ResourceLimits,
+    completed: Vec<(String, StepResources)>,
+}
+
+impl PipelineRun {
+    /// Start a run below `output` within the resources `limits`, removing
+    /// the marker of an earlier interrupted run. A step whose peak memory
+    /// exceeds the memory limit is warned about.
+    pub fn new(output: &Path, log_name: &str, limits: &ResourceLimits) -> PipelineRun {
+        let _ = std::fs::remove_file(output.join(INCOMPLETE_MARKER));
+        PipelineRun {
+            output: output.to_path_buf(),
+            log_name: log_name.to_string(),
+            limits: limits.clone(),
+            completed: Vec::new(),
+        }
+    }
+
+    /// Run `cmd` as the step `step` (see `run_measured`). `context` describes
+    /// a failure to start it, and `what` names it when it fails.
+    pub fn run_step(
+        &mut self,
+        cmd: &mut Command,
+        step: &str,
+        echo: &StepEcho,
+        context: &str,
+        what: &str,
+    ) -> anyhow::Result<StepResources> {
+        self.check_cancelled(step)?;
+        info!("cmd : {:?}", cmd);
+        let (proc_out, res) = run_measured(cmd, echo).with_context(|| context.to_string())?;
+        self.limits.check_memory(step, res.max_rss_kb);
+        if let Some(sig) = cancel_signal() {
+            return Err(self.interrupted(step, Some(&res), sig));
+        }
+        if !proc_out.status.success() {
+            self.write_partial_log(
+                step,
+                Some(&res),
+                json!({ "status" : "failed", "failed_step" : step }),
+            );
+            bail!("{} failed with exit status {:?}", what, proc_out.status);
+        }
+        self.completed.push((step.to_string(), res.clone()));
+        Ok(res)
+    }
+
+    /// Stop the run as interrupted during `step` if a cancellation signal has
+    /// arrived. The steps that simpleaf carries out itself cannot be
+    /// interrupted, so this is checked after each of them.
+    pub fn check_cancelled(&self, step: &str) -> anyhow::Result<()> {
+        match cancel_signal() {
+            Some(sig) => Err(self.interrupted(step, None, sig)),
+            None => Ok(()),
+        }
+    }
+
+    // record that the run was stopped by `sig` during `step`
+    fn interrupted(&self, step: &str, step_res: Option<&StepResources>, sig: i32) -> anyhow::Error {
+        let marker = self.output.join(INCOMPLETE_MARKER);
+        let reason = format!(
+            "interrupted by {} during the {} step\n",
+            signal_name(sig),
+            step
+        );
+        if let Err(e) = std::fs::write(&marker, reason) {
+            warn!("could not write {}: {}", marker.display(), e);
+        }
+        self.write_partial_log(
+            step,
+            step_res,
+            json!({
+                "status" : "incomplete",
+                "interrupted_step" : step,
+                "signal" : signal_name(sig)
+            }),
+        );
+        anyhow!(
+            "interrupted by {} during the {} step; the partial results in {} are marked incomplete",
+            signal_name(sig),
+            step,
+            self.output.display()
+        )
+    }
+
+    // write the partial log, made of `status` and the resources of the
+    // completed steps and of `step` (if it ran)
+    fn write_partial_log(
+        &self,
+        step: &str,
+        step_res: Option<&StepResources>,
+        mut status: serde_json::Value,
+    ) {
+        let partial_log = self.output.join(&self.log_name);
+        let mut resource_info = self
+            .completed
+            .iter()
+            .map(|(s, r)| (s.clone(), json!(r)))
+            .collect::<serde_json::Map<String, serde_json::Value>>();
+        if let Some(r) = step_res {
+            resource_info.insert(step.to_string(), json!(r));
+        }
+        status["completed_steps"] =
+            json!(self.completed.iter().map(|(s, _)| s).collect::<Vec<_>>());
+        status["resource_info"] = json!(resource_info);
+
+        if let Err(e) = std::fs::write(&partial_log, serde_json::to_string_pretty(&status).unwrap())
+        {
+            warn!("could not write {}: {}", partial_log.display(), e);
+        }
+    }
+}
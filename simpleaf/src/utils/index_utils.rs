@@ -0,0 +1,229 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::utils::af_utils::*;
+
+/// The files every salmon (pufferfish) index holds.
+pub const SALMON_INDEX_FILES: [&str; 11] = [
+    "versionInfo.json",
+    "info.json",
+    "complete_ref_lens.bin",
+    "ctable.bin",
+    "ctg_offsets.bin",
+    "mphf.bin",
+    "rank.bin",
+    "refAccumLengths.bin",
+    "reflengths.bin",
+    "refseq.bin",
+    "seq.bin",
+];
+
+/// The positions of the k-mers in a dense salmon index.
+pub const SALMON_DENSE_INDEX_FILES: [&str; 1] = ["pos.bin"];
+
+/// The sampled positions of the k-mers in a sparse salmon index
+/// (`simpleaf index --sparse`).
+pub const SALMON_SPARSE_INDEX_FILES: [&str; 5] = [
+    "sample_pos.bin",
+    "presence.bin",
+    "canonical.bin",
+    "extension.bin",
+    "direction.bin",
+];
+
+/// The files every piscem index holds, after the prefix of the index.
+pub const PISCEM_INDEX_SUFFIXES: [&str; 3] = [".sshash", ".ctab", ".refinfo"];
+
+/// The files making up an index built by `simpleaf index`: the output
+/// directory holding `index_info.json`, and the salmon or piscem index
+/// inside it.
+#[derive(Debug, Clone)]
+pub struct IndexDir {
+    /// the output directory of `simpleaf index`, if the index is inside one
+    pub root: Option<PathBuf>,
+    /// the salmon or piscem index
+    pub index: PathBuf,
+}
+
+impl IndexDir {
+    /// Locate the index given `dir`, which may either be the output directory
+    /// of a `simpleaf index` run or the mapper index (its `index` subdirectory,
+    /// or an index built outside of simpleaf).
+    pub fn locate(dir: &Path) -> Result<IndexDir> {
+        if dir.join("index_info.json").exists() {
+            return Ok(IndexDir {
+                root: Some(dir.to_path_buf()),
+                index: dir.join("index"),
+            });
+        }
+        if Mapper::of_index_files(dir).is_some() {
+            let root = dir
+                .parent()
+                .filter(|p| p.join("index_info.json").exists())
+                .map(Path::to_path_buf);
+            return Ok(IndexDir {
+                root,
+                index: dir.to_path_buf(),
+            });
+        }
+        bail!(
+            "{} does not look like a simpleaf, salmon or piscem index (none of index_info.json, versionInfo.json or {}.sshash found in it)",
+            dir.display(),
+            PISCEM_INDEX_PREFIX
+        )
+    }
+
+    /// The mapper that built the index, as recorded by `simpleaf index`
+    /// or else as told by the files of the index.
+    pub fn mapper(&self) -> Option<Mapper> {
+        if let Ok(Some(info)) = self.read_index_info() {
+            if let Some(m) = info["args"]["mapper"].as_str() {
+                return Some(Mapper::from_name(m));
+            }
+        }
+        Mapper::of_index_files(&self.index)
+    }
+
+    pub fn index_info(&self) -> Option<PathBuf> {
+        self.root.as_ref().map(|r| r.join("index_info.json"))
+    }
+    pub fn index_log(&self) -> Option<PathBuf> {
+        self.root
+            .as_ref()
+            .map(|r| r.join("simpleaf_index_log.json"))
+    }
+    /// The transcript to gene map copied into the index: the 3-column
+    /// map of a splici index, or the identity map of a feature index.
+    pub fn t2g(&self) -> PathBuf {
+        if self.is_feature_index() {
+            self.index.join("t2g.tsv")
+        } else {
+            self.index.join("t2g_3col.tsv")
+        }
+    }
+
+    /// Whether the index was built from a feature reference by
+    /// `simpleaf index --feature-ref`.
+    pub fn is_feature_index(&self) -> bool {
+        matches!(self.read_index_info(), Ok(Some(info)) if info.get("feature_geometry").is_some())
+    }
+
+    /// The parsed `index_info.json`, if the index was built by simpleaf.
+    pub fn read_index_info(&self) -> Result<Option<serde_json::Value>> {
+        match self.index_info() {
+            Some(p) => read_json(&p).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the salmon index samples the positions of its k-mers, as
+    /// told by its `info.json` or else by the arguments of `simpleaf index`.
+    pub fn is_sparse(&self) -> bool {
+        if let Ok(info) = read_json(&self.index.join("info.json")) {
+            if let Some(t) = info["sampling_type"].as_str() {
+                return t == "sparse";
+            }
+        }
+        matches!(self.read_index_info(), Ok(Some(info)) if info["args"]["sparse"] == true)
+    }
+
+    /// The files that the index of `mapper` must hold.
+    pub fn files(&self, mapper: Mapper) -> Vec<String> {
+        match mapper {
+            Mapper::Salmon => {
+                let positions: &[&str] = if self.is_sparse() {
+                    &SALMON_SPARSE_INDEX_FILES
+                } else {
+                    &SALMON_DENSE_INDEX_FILES
+                };
+                SALMON_INDEX_FILES
+                    .iter()
+                    .chain(positions)
+                    .map(|f| f.to_string())
+                    .collect()
+            }
+            Mapper::Piscem => PISCEM_INDEX_SUFFIXES
+                .iter()
+                .map(|s| format!("{}{}", PISCEM_INDEX_PREFIX, s))
+                .collect(),
+        }
+    }
+
+    /// The names of the targets of the index of `mapper`, which both salmon
+    /// (in `ctable.bin`) and piscem (in its `.refinfo` file) store first,
+    /// as a cereal-serialized vector of strings.
+    pub fn target_names(&self, mapper: Mapper) -> Result<Vec<String>> {
+        let p = match mapper {
+            Mapper::Salmon => self.index.join("ctable.bin"),
+            Mapper::Piscem => self.index.join(format!("{}.refinfo", PISCEM_INDEX_PREFIX)),
+        };
+        read_cereal_strings(&p)
+            .with_context(|| format!("could not read the target names in {}", p.display()))
+    }
+}
+
+// a vector of strings serialized by a cereal binary archive: the number of
+// strings, then the length and the bytes of each, with little-endian
+// 64-bit sizes
+fn read_cereal_strings(p: &Path) -> Result<Vec<String>> {
+    let f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let file_len = f.metadata()?.len();
+    let mut r = BufReader::new(f);
+    let read_size = |r: &mut BufReader<File>| -> Result<u64> {
+        let mut b = [0u8; 8];
+        r.read_exact(&mut b).context("the file ends early")?;
+        let n = u64::from_le_bytes(b);
+        // a size beyond the file cannot be that of a vector of strings
+        if n > file_len {
+            bail!("the file does not start with a vector of strings");
+        }
+        Ok(n)
+    };
+    let num_names = read_size(&mut r)?;
+    let mut names = Vec::with_capacity(num_names as usize);
+    for _ in 0..num_names {
+        let len = read_size(&mut r)?;
+        let mut name = vec![0u8; len as usize];
+        r.read_exact(&mut name).context("the file ends early")?;
+        names.push(String::from_utf8(name).context("a target name is not UTF-8")?);
+    }
+    Ok(names)
+}
+
+/// The mapper that built the index given by `dir` (see `IndexDir::locate`),
+/// assuming salmon if it cannot be told.
+pub fn index_mapper(dir: &Path) -> Mapper {
+    IndexDir::locate(dir)
+        .ok()
+        .and_then(|idx| idx.mapper())
+        .unwrap_or(Mapper::Salmon)
+}
+
+pub fn read_json(p: &Path) -> Result<serde_json::Value> {
+    let f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    serde_json::from_reader(BufReader::new(f))
+        .with_context(|| format!("could not parse {}", p.display()))
+}
+
+/// The transcripts listed in the `ncols`-column transcript to gene map `p`,
+/// along with the lines that do not have `ncols` columns.
+pub fn read_t2g_targets(p: &Path, ncols: usize) -> Result<(HashSet<String>, Vec<usize>)> {
+    let f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut targets = HashSet::new();
+    let mut malformed = Vec::new();
+    for (i, l) in BufReader::new(f).lines().enumerate() {
+        let l = l.with_context(|| format!("could not read {}", p.display()))?;
+        if l.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = l.split('\t').collect();
+        if fields.len() != ncols {
+            malformed.push(i + 1);
+        }
+        targets.insert(fields[0].to_string());
+    }
+    Ok((targets, malformed))
+}
@@ -0,0 +1,11 @@
+pub mod af_utils;
+pub mod args_utils;
+pub mod compat_utils;
+pub mod config_utils;
+pub mod exec_utils;
+pub mod index_utils;
+pub mod mtx_utils;
+pub mod output_utils;
+pub mod prog_utils;
+pub mod resource_utils;
+pub mod script_utils;
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The files making up an alevin-fry quantification, i.e. the
+/// directory that holds `quant.json` and the `alevin` subdirectory.
+#[derive(Debug, Clone)]
+pub struct QuantDir {
+    pub root: PathBuf,
+}
+
+impl QuantDir {
+    /// Locate the alevin-fry quantification below `dir`, which may either be
+    /// the quantification directory itself or the output directory of a
+    /// `simpleaf quant` run (in which case the `af_quant` subdirectory is used).
+    pub fn locate(dir: &Path) -> Result<QuantDir> {
+        for cand in [dir.to_path_buf(), dir.join("af_quant")] {
+            if cand.join("quant.json").exists() && cand.join("alevin").is_dir() {
+                return Ok(QuantDir { root: cand });
+            }
+        }
+        bail!(
+            "{} does not look like an alevin-fry quantification directory (no quant.json and alevin/ found in it or in its af_quant subdirectory)",
+            dir.display()
+        )
+    }
+
+    pub fn quant_json(&self) -> PathBuf {
+        self.root.join("quant.json")
+    }
+    pub fn matrix(&self) -> PathBuf {
+        self.root.join("alevin").join("quants_mat.mtx")
+    }
+    pub fn rows(&self) -> PathBuf {
+        self.root.join("alevin").join("quants_mat_rows.txt")
+    }
+    pub fn cols(&self) -> PathBuf {
+        self.root.join("alevin").join("quants_mat_cols.txt")
+    }
+
+    pub fn read_quant_json(&self) -> Result<serde_json::Value> {
+        let f = File::open(self.quant_json())
+            .with_context(|| format!("could not open {}", self.quant_json().display()))?;
+        serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("could not parse {}", self.quant_json().display()))
+    }
+
+    pub fn read_rows(&self) -> Result<Vec<String>> {
+        read_lines(&self.rows())
+    }
+
+    pub fn read_cols(&self) -> Result<Vec<String>> {
+        read_lines(&self.cols())
+    }
+}
+
+pub fn read_lines(p: &Path) -> Result<Vec<String>> {
+    let f = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut lines = Vec::new();
+    for l in BufReader::new(f).lines() {
+        lines.push(l.with_context(|| format!("could not read {}", p.display()))?);
+    }
+    Ok(lines)
+}
+
+pub fn write_lines<S: AsRef<str>>(p: &Path, lines: &[S]) -> Result<()> {
+    let f = File::create(p).with_context(|| format!("could not create {}", p.display()))?;
+    let mut w = BufWriter::new(f);
+    for l in lines {
+        writeln!(w, "{}", l.as_ref())?;
+    }
+    w.flush()
+        .with_context(|| format!("could not write {}", p.display()))
+}
+
+/// The dimensions recorded in the size line of a
+/// coordinate-format Matrix Market file.
+#[derive(Debug, Clone, Copy)]
+pub struct MtxShape {
+    pub nrows: usize,
+    pub ncols: usize,
+    pub nnz: usize,
+}
+
+/// Stream the entries of the coordinate-format Matrix Market file `p`
+/// (as written by alevin-fry), calling `f` with the 0-based row, the
+/// 0-based column and the value of each entry in file order. The matrix
+/// must have the `nrows` rows and `ncols` columns listed alongside it, so
+/// that `f` can index those lists with the rows and columns it is given.
+pub fn for_each_mtx_entry<F>(p: &Path, nrows: usize, ncols: usize, mut f: F) -> Result<MtxShape>
+where
+    F: FnMut(usize, usize, f64) -> Result<()>,
+{
+    let file = File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let mut shape = None;
+    for l in lines.by_ref() {
+        let l = l.with_context(|| format!("could not read {}", p.display()))?;
+        if l.starts_with('%') || l.trim().is_empty() {
+            continue;
+        }
+        let dims = l
+            .split_whitespace()
+            .map(|x| x.parse::<usize>())
+            .collect::<std::result::Result<Vec<usize>, _>>()
+            .with_context(|| format!("invalid size line {:?} in {}", l, p.display()))?;
+        if dims.len() != 3 {
+            bail!("invalid size line {:?} in {}", l, p.display());
+        }
+        shape = Some(MtxShape {
+            nrows: dims[0],
+            ncols: dims[1],
+            nnz: dims[2],
+        });
+        break;
+    }
+    let shape = shape.ok_or_else(|| anyhow!("{} has no size line", p.display()))?;
+    if shape.nrows != nrows || shape.ncols != ncols {
+        bail!(
+            "{} is a {} x {} matrix, but {} rows and {} columns are listed alongside it",
+            p.display(),
+            shape.nrows,
+            shape.ncols,
+            nrows,
+            ncols
+        );
+    }
+
+    let mut seen = 0usize;
+    for l in lines {
+        let l = l.with_context(|| format!("could not read {}", p.display()))?;
+        if l.trim().is_empty() {
+            continue;
+        }
+        let mut toks = l.split_whitespace();
+        let (r, c, v) = match (toks.next(), toks.next(), toks.next()) {
+            (Some(r), Some(c), Some(v)) => (r, c, v),
+            _ => bail!("invalid entry {:?} in {}", l, p.display()),
+        };
+        let r: usize = r
+            .parse()
+            .with_context(|| format!("invalid entry {:?} in {}", l, p.display()))?;
+        let c: usize = c
+            .parse()
+            .with_context(|| format!("invalid entry {:?} in {}", l, p.display()))?;
+        let v: f64 = v
+            .parse()
+            .with_context(|| format!("invalid entry {:?} in {}", l, p.display()))?;
+        if r == 0 || r > shape.nrows || c == 0 || c > shape.ncols {
+            bail!(
+                "entry {:?} is outside of the {} x {} matrix in {}",
+                l,
+                shape.nrows,
+                shape.ncols,
+                p.display()
+            );
+        }
+        f(r - 1, c - 1, v)?;
+        seen += 1;
+    }
+
+    if seen != shape.nnz {
+        bail!(
+            "{} declares {} entries but contains {}",
+            p.display(),
+            shape.nnz,
+            seen
+        );
+    }
+    Ok(shape)
+}
+
+/// Writes a coordinate-format Matrix Market file whose entries are
+/// streamed in before the final size is known. The entries are spooled
+/// to a temporary file next to the destination and the header is
+/// prepended in `finish`.
+pub struct MtxWriter {
+    dest: PathBuf,
+    body_path: PathBuf,
+    body: BufWriter<File>,
+    nnz: usize,
+}
+
+impl MtxWriter {
+    pub fn new(dest: &Path) -> Result<MtxWriter> {
+        let body_path = dest.with_extension("mtx.body");
+        let body = File::create(&body_path)
+            .with_context(|| format!("could not create {}", body_path.display()))?;
+        Ok(MtxWriter {
+            dest: dest.to_path_buf(),
+            body_path,
+            body: BufWriter::new(body),
+            nnz: 0,
+        })
+    }
+
+    /// Add the entry at the 0-based `row` and `col`.
+    pub fn push(&mut self, row: usize, col: usize, val: f64) -> Result<()> {
+        writeln!(self.body, "{} {} {}", row + 1, col + 1, val)?;
+        self.nnz += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self, nrows: usize, ncols: usize) -> Result<MtxShape> {
+        self.body.flush()?;
+        drop(self.body);
+
+        let out = File::create(&self.dest)
+            .with_context(|| format!("could not create {}", self.dest.display()))?;
+        let mut w = BufWriter::new(out);
+        writeln!(w, "%%MatrixMarket matrix coordinate real general")?;
+        writeln!(w, "{} {} {}", nrows, ncols, self.nnz)?;
+        let mut body = File::open(&self.body_path)?;
+        std::io::copy(&mut body, &mut w)?;
+        w.flush()
+            .with_context(|| format!("could not write {}", self.dest.display()))?;
+        std::fs::remove_file(&self.body_path)?;
+
+        Ok(MtxShape {
+            nrows,
+            ncols,
+            nnz: self.nnz,
+        })
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The name of the lock file held in an output directory during a run.
+pub const LOCK_FILE: &str = ".simpleaf.lock";
+
+/// An exclusive lock on an output directory, which is released
+/// (by removing the lock file) when it is dropped.
+#[derive(Debug)]
+pub struct OutputLock {
+    path: PathBuf,
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for writes of its length
+        let r = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if r == 0 {
+            let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..end]).into_owned();
+        }
+    }
+    String::from("unknown")
+}
+
+// whether the process `pid` on this host is still running
+fn pid_is_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: signal 0 only checks that the process exists
+        let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+// the owner recorded in the lock file `path`, as its pid and host
+fn read_lock_owner(path: &Path) -> (Option<u32>, Option<String>) {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|p| p.trim().parse::<u32>().ok());
+    let host = lines.next().map(|h| h.trim().to_string());
+    (pid, host)
+}
+
+/// Take the lock on the output directory `dir`, which must exist. A lock
+/// left behind by a process that is no longer running on this host is
+/// considered stale and taken over.
+pub fn lock_output_dir(dir: &Path) -> Result<OutputLock> {
+    let path = dir.join(LOCK_FILE);
+    let host = hostname();
+    let pid = std::process::id();
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+    {
+        Ok(mut f) => {
+            writeln!(f, "{}\n{}", pid, host)
+                .with_context(|| format!("could not write {}", path.display()))?;
+            Ok(OutputLock { path })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            match read_lock_owner(&path) {
+                (Some(owner), Some(h)) if h == host && !pid_is_alive(owner) => {
+                    warn!(
+                        "taking over the stale lock {} of process {}, which is no longer running",
+                        path.display(),
+                        owner
+                    );
+                    // the stale lock is replaced in a single rename, so that
+                    // of several runs taking it over at once, only the one
+                    // whose lock is read back below proceeds
+                    let tmp = dir.join(format!("{}.{}", LOCK_FILE, pid));
+                    std::fs::write(&tmp, format!("{}\n{}\n", pid, host))
+                        .with_context(|| format!("could not write {}", tmp.display()))?;
+                    if let Err(e) = std::fs::rename(&tmp, &path) {
+                        let _ = std::fs::remove_file(&tmp);
+                        return Err(e)
+                            .with_context(|| format!("could not replace {}", path.display()));
+                    }
+                    if read_lock_owner(&path) != (Some(pid), Some(host)) {
+                        bail!(
+                            "another run took over the stale lock {} at the same time; if no other run is active, remove it by hand",
+                            path.display()
+                        );
+                    }
+                    Ok(OutputLock { path })
+                }
+                (Some(owner), Some(h)) => bail!(
+                    "the output directory {} is in use by process {} on {}; if that run is no longer active, remove {}",
+                    dir.display(),
+                    owner,
+                    h,
+                    path.display()
+                ),
+                _ => bail!(
+                    "the output directory {} is locked by {}, which could not be read; if no other run is active, remove it",
+                    dir.display(),
+                    path.display()
+                ),
+            }
+        }
+        Err(e) => Err(e).with_context(|| format!("could not create {}", path.display())),
+    }
+}
+
+/// Create the output directory `dir` of a run and take its lock. Unless
+/// `overwrite` is set, a directory that already holds files (such as the
+/// results of an earlier run) is refused.
+pub fn prepare_output_dir(dir: &Path, overwrite: bool) -> Result<OutputLock> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("could not create the output directory {}", dir.display()))?;
+
+    let lock = lock_output_dir(dir)?;
+    if !overwrite {
+        let mut entries = std::fs::read_dir(dir)
+            .with_context(|| format!("could not read the output directory {}", dir.display()))?;
+        let non_empty = entries.any(|e| e.map(|e| e.file_name() != LOCK_FILE).unwrap_or(true));
+        if non_empty {
+            bail!(
+                "the output directory {} is not empty; pass --overwrite to write into it anyway",
+                dir.display()
+            );
+        }
+    }
+    Ok(lock)
+}
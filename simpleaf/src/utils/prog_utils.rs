@@ -1,15 +1,22 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use cmd_lib::run_fun;
-use semver::{Version, VersionReq};
+use semver::{BuildMetadata, Prerelease, Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
 use std::env;
-use std::path::PathBuf;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use which::which;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProgInfo {
     pub exe_path: PathBuf,
     pub version: String,
+    /// the optional features (flags or subcommands) the program was found
+    /// to support; empty if they were not detected
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 
 impl Default for ProgInfo {
@@ -17,47 +24,195 @@ impl Default for ProgInfo {
         Self {
             exe_path: PathBuf::from(""),
             version: String::from("0.0.0"),
+            capabilities: Vec::new(),
         }
     }
 }
 
+impl ProgInfo {
+    /// Whether the program `name` is known not to support the feature `cap`
+    /// (e.g. `--sketch`). Features that are not detected, and programs whose
+    /// capabilities were not detected (such as those recorded by older
+    /// versions of simpleaf), are assumed to be supported.
+    pub fn lacks(&self, name: &str, cap: &str) -> bool {
+        let detectable = PROG_CAPABILITIES
+            .iter()
+            .any(|(n, _, known)| *n == name && known.contains(&cap));
+        detectable && !self.capabilities.is_empty() && !self.capabilities.iter().any(|c| c == cap)
+    }
+}
+
 // Holds the paths to the
 // programs we'll need to run
 // the tool.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ReqProgs {
     pub salmon: Option<ProgInfo>,
     pub alevin_fry: Option<ProgInfo>,
     pub pyroe: Option<ProgInfo>,
+    /// only needed when mapping with piscem
+    #[serde(default)]
+    pub piscem: Option<ProgInfo>,
+}
+
+/// Read the program information recorded by the `set-paths`
+/// command from `simpleaf_info.json` in `af_home_path`.
+pub fn get_required_progs_from_info(af_home_path: &Path) -> Result<ReqProgs> {
+    // Open the file in read-only mode with buffer.
+    let af_info_p = af_home_path.join("simpleaf_info.json");
+    let simpleaf_info_file = std::fs::File::open(&af_info_p).with_context({
+        ||
+        format!("Could not open file {}; please run the set-paths command before using `index` or `quant`", af_info_p.display())
+    })?;
+
+    let simpleaf_info_reader = BufReader::new(simpleaf_info_file);
+
+    // Read the JSON contents of the file as an instance of `ReqProgs`.
+    let v: serde_json::Value = serde_json::from_reader(simpleaf_info_reader)
+        .with_context(|| format!("could not parse {}", af_info_p.display()))?;
+    let rp: ReqProgs = serde_json::from_value(v["prog_info"].clone())
+        .with_context(|| format!("invalid prog_info in {}", af_info_p.display()))?;
+    Ok(rp)
+}
+
+/// Find the version in the `--version` output `out` of a program. The
+/// first word that looks like a version (e.g. `1.10.0`, `v0.7.0`,
+/// `1.10.0-dev` or `1.9`) is used, so text around it is ignored.
+pub fn parse_version_output(out: &str) -> Result<Version> {
+    for word in out.split_whitespace() {
+        let word = word
+            .trim_matches(|c: char| !c.is_ascii_alphanumeric())
+            .trim_start_matches('v');
+        if !word.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        if let Ok(v) = Version::parse(word) {
+            return Ok(v);
+        }
+        // versions with fewer than three components, like `1.9`
+        let (core, rest) = match word.find(['-', '+']) {
+            Some(i) => word.split_at(i),
+            None => (word, ""),
+        };
+        let ncomponents = core.split('.').count();
+        if ncomponents < 3 {
+            let padded = format!("{}{}{}", core, ".0".repeat(3 - ncomponents), rest);
+            if let Ok(v) = Version::parse(&padded) {
+                return Ok(v);
+            }
+        }
+    }
+    bail!("no version number found in {:?}", out.trim())
 }
 
 pub fn check_version_constraints<S1: AsRef<str>>(
     req_string: S1,
     prog_output: std::result::Result<String, std::io::Error>,
 ) -> Result<Version> {
-    match prog_output {
-        Ok(vs) => {
-            let x = vs.split_whitespace();
-            if let Some(version) = x.last() {
-                let parsed_version = Version::parse(version).unwrap();
-                let req = VersionReq::parse(req_string.as_ref()).unwrap();
-                if req.matches(&parsed_version) {
-                    return Ok(parsed_version);
-                } else {
-                    return Err(anyhow!(
-                        "parsed version {:?} does not satisfy constraints {:?}",
-                        version,
-                        req
-                    ));
-                }
-            }
+    let vs = prog_output.context("could not run the program to determine its version")?;
+    let parsed_version = parse_version_output(&vs)?;
+    let req = VersionReq::parse(req_string.as_ref())
+        .with_context(|| format!("invalid version requirement {}", req_string.as_ref()))?;
+
+    // pre-release and development builds (e.g. 1.10.0-dev) are
+    // checked as the release they precede
+    let mut release = parsed_version.clone();
+    release.pre = Prerelease::EMPTY;
+    release.build = BuildMetadata::EMPTY;
+    if req.matches(&release) {
+        Ok(parsed_version)
+    } else {
+        Err(anyhow!(
+            "parsed version {} does not satisfy constraints {}",
+            parsed_version,
+            req
+        ))
+    }
+}
+
+/// The optional features of each program that simpleaf relies on, and the
+/// arguments that print the help text in which they are looked up.
+const PROG_CAPABILITIES: [(&str, &[&str], &[&str]); 4] = [
+    (
+        "salmon",
+        &["alevin", "--help"],
+        &[
+            "--sketch",
+            "--rad",
+            "--minScoreFraction",
+            "--softclip",
+            "--softclipOverhangs",
+            "--chromium",
+            "--chromiumV3",
+            "--dropseq",
+            "--indropV2",
+            "--citeseq",
+            "--celseq",
+            "--celseq2",
+            "--quartzseq2",
+            "--sciseq3",
+            "--splitseqV1",
+            "--splitseqV2",
+            "--gemcode",
+        ],
+    ),
+    (
+        "alevin-fry",
+        &["--help"],
+        &[
+            "generate-permit-list",
+            "collate",
+            "quant",
+            "infer",
+            "convert",
+            "view",
+        ],
+    ),
+    (
+        "pyroe",
+        &["--help"],
+        &[
+            "make-splici",
+            "make-spliceu",
+            "id-to-name",
+            "convert",
+            "fetch-quant",
+        ],
+    ),
+    ("piscem", &["--help"], &["build", "map-sc", "map-bulk"]),
+];
+
+/// Detect which of the features simpleaf relies on are supported by the
+/// program `name` at `exe`, from its help text. Nothing is reported if
+/// the help text could not be obtained.
+pub fn detect_capabilities(name: &str, exe: &Path) -> Vec<String> {
+    let (args, known) = match PROG_CAPABILITIES.iter().find(|(n, _, _)| *n == name) {
+        Some((_, args, known)) => (*args, *known),
+        None => return Vec::new(),
+    };
+    let help = match std::process::Command::new(exe)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .output()
+    {
+        Ok(out) => {
+            let mut h = String::from_utf8_lossy(&out.stdout).into_owned();
+            h.push_str(&String::from_utf8_lossy(&out.stderr));
+            h
         }
         Err(e) => {
-            eprintln!("Error running salmon {}", e);
-            return Err(anyhow!("could not parse program output"));
+            warn!("could not detect the capabilities of {}: {}", name, e);
+            return Vec::new();
         }
-    }
-    Err(anyhow!("invalid version string"))
+    };
+    let words: HashSet<&str> = help
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']' || c == '=')
+        .collect();
+    known
+        .iter()
+        .filter(|k| words.contains(**k))
+        .map(|k| k.to_string())
+        .collect()
 }
 
 pub fn get_which_executable(prog_name: &str) -> Result<PathBuf> {
@@ -66,13 +221,11 @@ pub fn get_which_executable(prog_name: &str) -> Result<PathBuf> {
             println!("found `{}` in the PATH at {}", prog_name, p.display());
             Ok(p)
         }
-        Err(e) => {
-            return Err(anyhow!(
-                "could not find `{}` in your path: {}",
-                prog_name,
-                e
-            ));
-        }
+        Err(e) => Err(anyhow!(
+            "could not find `{}` in your path: {}",
+            prog_name,
+            e
+        )),
     }
 }
 
@@ -91,70 +244,131 @@ pub fn search_for_executable(env_key: &str, prog_name: &str) -> Result<PathBuf>
     }
 }
 
+/// A program simpleaf runs.
+pub struct ProgSpec {
+    /// the name of the program, as used on the command line
+    pub name: &'static str,
+    /// the versions of the program simpleaf supports
+    pub req: &'static str,
+    /// whether simpleaf can be used without the program
+    pub optional: bool,
+}
+
+/// The programs simpleaf runs.
+pub const SIMPLEAF_PROGS: [ProgSpec; 4] = [
+    ProgSpec {
+        name: "salmon",
+        req: ">=1.5.1, <2.0.0",
+        optional: false,
+    },
+    ProgSpec {
+        name: "alevin-fry",
+        req: ">=0.4.1, <1.0.0",
+        optional: false,
+    },
+    ProgSpec {
+        name: "pyroe",
+        req: ">=0.6.2, <1.0.0",
+        optional: false,
+    },
+    ProgSpec {
+        name: "piscem",
+        req: ">=0.4.0, <1.0.0",
+        optional: true,
+    },
+];
+
+impl ReqProgs {
+    /// The information of the program `name` (one of `SIMPLEAF_PROGS`).
+    pub fn get(&self, name: &str) -> Option<&ProgInfo> {
+        match name {
+            "salmon" => self.salmon.as_ref(),
+            "alevin-fry" => self.alevin_fry.as_ref(),
+            "pyroe" => self.pyroe.as_ref(),
+            "piscem" => self.piscem.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, info: ProgInfo) {
+        match name {
+            "salmon" => self.salmon = Some(info),
+            "alevin-fry" => self.alevin_fry = Some(info),
+            "pyroe" => self.pyroe = Some(info),
+            "piscem" => self.piscem = Some(info),
+            _ => {}
+        }
+    }
+}
+
+/// Run `exe --version` and check that it is a supported version
+/// of the program `name` (one of `SIMPLEAF_PROGS`).
+pub fn probe_prog(name: &str, exe: PathBuf) -> Result<ProgInfo> {
+    let req = SIMPLEAF_PROGS
+        .iter()
+        .find(|p| p.name == name)
+        .map(|p| p.req)
+        .ok_or_else(|| anyhow!("unknown program {}", name))?;
+    let st = exe.display().to_string();
+    let sr = run_fun!($st --version);
+    let v = check_version_constraints(req, sr)
+        .with_context(|| format!("{} at {} is not usable", name, exe.display()))?;
+    let capabilities = detect_capabilities(name, &exe);
+    Ok(ProgInfo {
+        exe_path: exe,
+        version: format!("{}", v),
+        capabilities,
+    })
+}
+
+/// Record the programs `rp` in `simpleaf_info.json` in `af_home_path`.
+pub fn write_required_progs(af_home_path: &Path, rp: &ReqProgs) -> Result<()> {
+    let simpleaf_info_file = af_home_path.join("simpleaf_info.json");
+    let simpleaf_info = json!({ "prog_info": rp });
+
+    std::fs::write(
+        &simpleaf_info_file,
+        serde_json::to_string_pretty(&simpleaf_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", simpleaf_info_file.display()))
+}
+
 pub fn get_required_progs_from_paths(
     salmon_exe: Option<PathBuf>,
     alevin_fry_exe: Option<PathBuf>,
     pyroe_exe: Option<PathBuf>,
+    piscem_exe: Option<PathBuf>,
 ) -> Result<ReqProgs> {
-    let mut rp = ReqProgs {
-        salmon: None,
-        alevin_fry: None,
-        pyroe: None,
-    };
+    let mut rp = ReqProgs::default();
 
     // use the given path if we have it
     // otherwise, check `which`
-    let salmon = match salmon_exe {
-        Some(p) => p,
-        None => match get_which_executable("salmon") {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(e);
-            }
-        },
-    };
-    let alevin_fry = match alevin_fry_exe {
-        Some(p) => p,
-        None => match get_which_executable("alevin-fry") {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(e);
-            }
-        },
-    };
-    let pyroe = match pyroe_exe {
-        Some(p) => p,
-        None => match get_which_executable("pyroe") {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(e);
+    for (prog, exe) in
+        SIMPLEAF_PROGS
+            .iter()
+            .zip([salmon_exe, alevin_fry_exe, pyroe_exe, piscem_exe])
+    {
+        let (exe, from_path) = match exe {
+            Some(p) => (p, false),
+            None => match get_which_executable(prog.name) {
+                Ok(p) => (p, true),
+                // optional programs are simply left unconfigured
+                Err(e) if prog.optional => {
+                    println!("{}; it will not be available", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        match probe_prog(prog.name, exe) {
+            Ok(info) => rp.set(prog.name, info),
+            // as are unusable ones that happen to be in the PATH
+            Err(e) if from_path && prog.optional => {
+                warn!("{:#}; it will not be available", e);
             }
-        },
-    };
-
-    let st = salmon.display().to_string();
-    let sr = run_fun!($st --version);
-    let v = check_version_constraints(">=1.5.1, <2.0.0", sr)?;
-    rp.salmon = Some(ProgInfo {
-        exe_path: salmon,
-        version: format!("{}", v),
-    });
-
-    let st = alevin_fry.display().to_string();
-    let sr = run_fun!($st --version);
-    let v = check_version_constraints(">=0.4.1, <1.0.0", sr)?;
-    rp.alevin_fry = Some(ProgInfo {
-        exe_path: alevin_fry,
-        version: format!("{}", v),
-    });
-
-    let st = pyroe.display().to_string();
-    let sr = run_fun!($st --version);
-    let v = check_version_constraints(">=0.6.2, <1.0.0", sr)?;
-    rp.pyroe = Some(ProgInfo {
-        exe_path: pyroe,
-        version: format!("{}", v),
-    });
+            Err(e) => return Err(e),
+        }
+    }
 
     Ok(rp)
 }
@@ -166,6 +380,7 @@ pub fn get_required_progs() -> Result<ReqProgs> {
     let salmon_exe = Some(search_for_executable("SALMON", "salmon")?);
     let alevin_fry_exe = Some(search_for_executable("ALEVIN_FRY", "alevin-fry")?);
     let pyroe_exe = Some(search_for_executable("PYROE", "pyroe")?);
+    let piscem_exe = search_for_executable("PISCEM", "piscem").ok();
 
-    get_required_progs_from_paths(salmon_exe, alevin_fry_exe, pyroe_exe)
+    get_required_progs_from_paths(salmon_exe, alevin_fry_exe, pyroe_exe, piscem_exe)
 }
@@ -0,0 +1,236 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// The default number of threads used when none are requested.
+pub const DEFAULT_MAX_THREADS: u32 = 16;
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// The CPU and memory available to this process, taking the
+/// scheduler (SLURM) and cgroup (v1 or v2) limits into account.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceLimits {
+    pub cpus: u32,
+    /// where the CPU limit came from
+    pub cpu_source: String,
+    pub memory_bytes: Option<u64>,
+    /// where the memory limit came from
+    pub memory_source: Option<String>,
+}
+
+impl ResourceLimits {
+    /// Detect the resources available to this process. The most restrictive
+    /// of the host, SLURM and cgroup limits is used.
+    pub fn detect() -> ResourceLimits {
+        // on a tie, the scheduler and cgroup limits are reported as the source
+        let mut cpu_limits: Vec<(u32, String)> = Vec::new();
+        if let Some(n) = env_number("SLURM_CPUS_PER_TASK").and_then(|n| u32::try_from(n).ok()) {
+            cpu_limits.push((n, String::from("SLURM_CPUS_PER_TASK")));
+        }
+        if let Some(c) = cgroup_cpu_limit() {
+            cpu_limits.push(c);
+        }
+        if let Ok(n) = std::thread::available_parallelism() {
+            cpu_limits.push((n.get() as u32, String::from("available parallelism")));
+        }
+        let (cpus, cpu_source) = cpu_limits
+            .into_iter()
+            .filter(|(n, _)| *n > 0)
+            .min_by_key(|(n, _)| *n)
+            .unwrap_or((1, String::from("default")));
+
+        let mut mem_limits: Vec<(Option<u64>, String)> = Vec::new();
+        // SLURM reports memory in megabytes, and 0 for all of the memory of
+        // the node; limits too large to count in bytes are no limits at all
+        if let Some(mb) = env_number("SLURM_MEM_PER_NODE") {
+            mem_limits.push((
+                mb.checked_mul(1024 * 1024),
+                String::from("SLURM_MEM_PER_NODE"),
+            ));
+        } else if let Some(mb) = env_number("SLURM_MEM_PER_CPU") {
+            mem_limits.push((
+                mb.checked_mul(1024 * 1024)
+                    .and_then(|m| m.checked_mul(cpus as u64)),
+                String::from("SLURM_MEM_PER_CPU"),
+            ));
+        }
+        if let Some((m, s)) = cgroup_memory_limit() {
+            mem_limits.push((Some(m), s));
+        }
+        let (memory_bytes, memory_source) = match mem_limits
+            .into_iter()
+            .filter_map(|(m, s)| m.filter(|m| *m > 0).map(|m| (m, s)))
+            .min_by_key(|(m, _)| *m)
+        {
+            Some((m, s)) => (Some(m), Some(s)),
+            None => (None, None),
+        };
+
+        ResourceLimits {
+            cpus,
+            cpu_source,
+            memory_bytes,
+            memory_source,
+        }
+    }
+
+    /// The share of these resources of each of `num_jobs` jobs run at once.
+    pub fn share(&self, num_jobs: usize) -> ResourceLimits {
+        if num_jobs <= 1 {
+            return self.clone();
+        }
+        let shared = |source: &str| format!("{} shared by {} jobs", source, num_jobs);
+        ResourceLimits {
+            cpus: (self.cpus / num_jobs as u32).max(1),
+            cpu_source: shared(&self.cpu_source),
+            memory_bytes: self.memory_bytes.map(|m| m / num_jobs as u64),
+            memory_source: self.memory_source.as_deref().map(shared),
+        }
+    }
+
+    /// Warn if the peak memory `max_rss_kb` of the step `step` was more than
+    /// the memory available to it.
+    pub fn check_memory(&self, step: &str, max_rss_kb: Option<u64>) {
+        if let (Some(limit), Some(rss_kb)) = (self.memory_bytes, max_rss_kb) {
+            let peak = rss_kb.saturating_mul(1024);
+            if peak > limit {
+                warn!(
+                    "the {} step used up to {:.2} GiB of memory, more than the {:.2} GiB available (limited by {}); it may be killed or slowed by swapping, so please request more memory",
+                    step,
+                    peak as f64 / GIB,
+                    limit as f64 / GIB,
+                    self.memory_source.as_deref().unwrap_or("unknown")
+                );
+            }
+        }
+    }
+
+    /// The number of threads to use when none were requested: min(16, num cores).
+    pub fn default_threads(&self) -> u32 {
+        DEFAULT_MAX_THREADS.min(self.cpus)
+    }
+
+    /// The number of threads to use given the `requested` number, if any.
+    pub fn resolve_threads(&self, requested: Option<u32>) -> u32 {
+        match requested {
+            Some(t) => self.cap_threads("", t),
+            None => self.default_threads(),
+        }
+    }
+
+    /// Limit `requested` threads (for the step `what`, if not empty)
+    /// to the number of available CPUs.
+    pub fn cap_threads(&self, what: &str, requested: u32) -> u32 {
+        let requested = requested.max(1);
+        if requested > self.cpus {
+            let step = if what.is_empty() {
+                String::new()
+            } else {
+                format!(" for {}", what)
+            };
+            warn!(
+                "{} threads were requested{}, but only {} CPUs are available (limited by {}); using {} threads",
+                requested, step, self.cpus, self.cpu_source, self.cpus
+            );
+            self.cpus
+        } else {
+            requested
+        }
+    }
+}
+
+fn env_number(var: &str) -> Option<u64> {
+    std::env::var(var).ok().and_then(|v| v.trim().parse().ok())
+}
+
+fn read_trimmed(p: &Path) -> Option<String> {
+    std::fs::read_to_string(p)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The cgroup v2 directory of this process, if it is in a unified hierarchy.
+fn cgroup_v2_dir() -> Option<PathBuf> {
+    let cg = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let rel = cg.lines().find_map(|l| l.strip_prefix("0::"))?;
+    let dir = Path::new(CGROUP_ROOT).join(rel.trim_start_matches('/'));
+    if dir.join("cgroup.controllers").exists() {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+/// The cgroup v1 directory of this process for `controller` (e.g. `cpu`).
+fn cgroup_v1_dir(controller: &str) -> Option<PathBuf> {
+    let cg = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    for l in cg.lines() {
+        // <hierarchy id>:<controller list>:<path>
+        let mut fields = l.splitn(3, ':');
+        let (_, controllers, rel) = (fields.next()?, fields.next()?, fields.next()?);
+        if controllers.split(',').any(|c| c == controller) {
+            let mount = Path::new(CGROUP_ROOT).join(controllers);
+            let mount = if mount.exists() {
+                mount
+            } else {
+                Path::new(CGROUP_ROOT).join(controller)
+            };
+            // inside a container the cgroup is usually mounted at its own root
+            let dir = mount.join(rel.trim_start_matches('/'));
+            return Some(if dir.exists() { dir } else { mount });
+        }
+    }
+    None
+}
+
+/// The number of CPUs allowed by the cgroup CPU quota, rounded up.
+fn cgroup_cpu_limit() -> Option<(u32, String)> {
+    let quota_cpus = |quota: f64, period: f64| -> Option<u32> {
+        if quota > 0.0 && period > 0.0 {
+            Some((quota / period).ceil().max(1.0) as u32)
+        } else {
+            None
+        }
+    };
+
+    if let Some(dir) = cgroup_v2_dir() {
+        // "<quota> <period>", where the quota may be "max"
+        let max = read_trimmed(&dir.join("cpu.max"))?;
+        let mut fields = max.split_whitespace();
+        let quota = fields.next()?.parse::<f64>().ok()?;
+        let period = fields.next()?.parse::<f64>().ok()?;
+        return quota_cpus(quota, period).map(|n| (n, String::from("cgroup v2 cpu.max")));
+    }
+
+    let dir = cgroup_v1_dir("cpu")?;
+    // a quota of -1 means there is no limit
+    let quota = read_trimmed(&dir.join("cpu.cfs_quota_us"))?
+        .parse::<f64>()
+        .ok()?;
+    let period = read_trimmed(&dir.join("cpu.cfs_period_us"))?
+        .parse::<f64>()
+        .ok()?;
+    quota_cpus(quota, period).map(|n| (n, String::from("cgroup v1 cpu.cfs_quota_us")))
+}
+
+/// The memory limit of the cgroup, in bytes.
+fn cgroup_memory_limit() -> Option<(u64, String)> {
+    if let Some(dir) = cgroup_v2_dir() {
+        // "max" means there is no limit
+        let limit = read_trimmed(&dir.join("memory.max"))?.parse::<u64>().ok()?;
+        return Some((limit, String::from("cgroup v2 memory.max")));
+    }
+
+    let dir = cgroup_v1_dir("memory")?;
+    let limit = read_trimmed(&dir.join("memory.limit_in_bytes"))?
+        .parse::<u64>()
+        .ok()?;
+    // an unlimited cgroup v1 reports a huge (page-rounded i64::MAX) value
+    if limit >= (1u64 << 62) {
+        None
+    } else {
+        Some((limit, String::from("cgroup v1 memory.limit_in_bytes")))
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+/// Quote `s` so that a POSIX shell reads it back as a single word.
+pub fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=.,/:@%".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Render `cmd` (its program and arguments) as a shell command line.
+pub fn command_to_shell(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| shell_quote(&a.to_string_lossy()))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// A bash script that reproduces the external commands of a
+/// pipeline, as produced by the `--dry-run` option.
+pub struct ShellScript {
+    lines: Vec<String>,
+}
+
+impl ShellScript {
+    pub fn new(description: &str) -> ShellScript {
+        ShellScript {
+            lines: vec![
+                String::from("#!/usr/bin/env bash"),
+                format!("# {}", description),
+                String::from("# generated by `simpleaf --dry-run`"),
+                String::from("set -euo pipefail"),
+            ],
+        }
+    }
+
+    pub fn blank(&mut self) {
+        self.lines.push(String::new());
+    }
+
+    pub fn comment<S: AsRef<str>>(&mut self, c: S) {
+        for l in c.as_ref().lines() {
+            self.lines.push(format!("# {}", l));
+        }
+    }
+
+    pub fn command(&mut self, cmd: &Command) {
+        self.lines.push(command_to_shell(cmd));
+    }
+
+    pub fn mkdir(&mut self, dir: &Path) {
+        self.args(&[OsStr::new("mkdir"), OsStr::new("-p"), dir.as_os_str()]);
+    }
+
+    /// Add the command line made up of the words in `args`.
+    pub fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) {
+        self.lines.push(
+            args.iter()
+                .map(|a| shell_quote(&a.as_ref().to_string_lossy()))
+                .collect::<Vec<String>>()
+                .join(" "),
+        );
+    }
+
+    pub fn render(&self) -> String {
+        let mut s = self.lines.join("\n");
+        s.push('\n');
+        s
+    }
+
+    /// Write the script to `dest` if given, otherwise print it to stdout.
+    pub fn emit(&self, dest: Option<&Path>) -> Result<()> {
+        match dest {
+            Some(p) => {
+                std::fs::write(p, self.render())
+                    .with_context(|| format!("could not write {}", p.display()))?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(p, std::fs::Permissions::from_mode(0o755))?;
+                }
+                info!("wrote dry-run script to {}", p.display());
+            }
+            None => print!("{}", self.render()),
+        }
+        Ok(())
+    }
+}
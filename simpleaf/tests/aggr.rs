@@ -0,0 +1,156 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+// a gene-level quantification of `genes` in the cells `rows` in
+// `<dir>/af_quant`, with the matrix entries `entries`
+fn write_quant(
+    sb: &Sandbox,
+    dir: &str,
+    rows: &[&str],
+    genes: &[&str],
+    entries: &[(usize, usize, f64)],
+) {
+    sb.write(
+        &format!("{}/af_quant/quant.json", dir),
+        &format!(
+            "{{\"usa_mode\": false, \"num_genes\": {}, \"num_quantified_cells\": {}}}\n",
+            genes.len(),
+            rows.len()
+        ),
+    );
+    let lines = |v: &[&str]| v.iter().map(|l| format!("{}\n", l)).collect::<String>();
+    sb.write(
+        &format!("{}/af_quant/alevin/quants_mat_rows.txt", dir),
+        &lines(rows),
+    );
+    sb.write(
+        &format!("{}/af_quant/alevin/quants_mat_cols.txt", dir),
+        &lines(genes),
+    );
+    let mut mtx = format!(
+        "%%MatrixMarket matrix coordinate real general\n{} {} {}\n",
+        rows.len(),
+        genes.len(),
+        entries.len()
+    );
+    for (r, c, v) in entries {
+        mtx.push_str(&format!("{} {} {}\n", r, c, v));
+    }
+    sb.write(&format!("{}/af_quant/alevin/quants_mat.mtx", dir), &mtx);
+}
+
+fn read(sb: &Sandbox, rel: &str) -> String {
+    std::fs::read_to_string(sb.path(rel)).unwrap()
+}
+
+fn two_samples() -> Sandbox {
+    let sb = Sandbox::new();
+    write_quant(
+        &sb,
+        "s1",
+        &["AAAA", "CCCC"],
+        &["g1", "g2"],
+        &[(1, 1, 2.0), (2, 2, 4.0)],
+    );
+    write_quant(
+        &sb,
+        "s2",
+        &["AAAA"],
+        &["g1", "g2"],
+        &[(1, 1, 100.0), (1, 2, 300.0)],
+    );
+    sb
+}
+
+#[test]
+fn aggr_suffixes_the_barcodes_of_each_sample() {
+    let sb = two_samples();
+    sb.run(&["aggr", "-q", "s1", "s2", "-o", "by_name"])
+        .assert_success();
+    assert_eq!(
+        read(&sb, "by_name/alevin/quants_mat_rows.txt"),
+        "AAAA-s1\nCCCC-s1\nAAAA-s2\n"
+    );
+    assert_eq!(
+        read(&sb, "by_name/alevin/quants_mat.mtx"),
+        "%%MatrixMarket matrix coordinate real general\n3 2 4\n1 1 2\n2 2 4\n3 1 100\n3 2 300\n"
+    );
+    assert_eq!(read(&sb, "by_name/alevin/quants_mat_cols.txt"), "g1\ng2\n");
+    assert_eq!(
+        sb.read_json("by_name/quant.json")["num_quantified_cells"],
+        3
+    );
+
+    sb.run(&[
+        "aggr", "-q", "s1", "s2", "-n", "left", "right", "-b", "index", "-o", "by_index",
+    ])
+    .assert_success();
+    assert_eq!(
+        read(&sb, "by_index/alevin/quants_mat_rows.txt"),
+        "AAAA-1\nCCCC-1\nAAAA-2\n"
+    );
+
+    sb.run(&["aggr", "-q", "s1", "s1", "-o", "twice"])
+        .assert_failure("is used more than once");
+}
+
+#[test]
+fn aggr_requires_the_same_genes() {
+    let sb = two_samples();
+    write_quant(&sb, "s3", &["GGGG"], &["g1", "g3"], &[(1, 1, 1.0)]);
+    sb.run(&["aggr", "-q", "s1", "s3", "-o", "out"])
+        .assert_failure("the gene list of sample s3 differs from that of sample s1 at line 2");
+    write_quant(&sb, "s4", &["GGGG"], &["g1"], &[(1, 1, 1.0)]);
+    sb.run(&["aggr", "-q", "s1", "s4", "-o", "out"])
+        .assert_failure("sample s4 has 1 genes, but sample s1 has 2");
+    assert!(!sb.path("out/alevin/quants_mat.mtx").exists());
+}
+
+#[test]
+fn aggr_writes_the_sample_metadata() {
+    let sb = two_samples();
+    sb.run(&["aggr", "-q", "s1", "s2", "-d", "--seed", "7", "-o", "even"])
+        .assert_success();
+
+    let meta = read(&sb, "even/sample_metadata.tsv");
+    let lines: Vec<Vec<&str>> = meta.lines().map(|l| l.split('\t').collect()).collect();
+    assert_eq!(
+        lines[0],
+        [
+            "sample_index",
+            "sample",
+            "barcode_suffix",
+            "quant_dir",
+            "num_cells",
+            "total_counts",
+            "downsample_fraction",
+            "retained_counts"
+        ]
+    );
+    // s1 has 3 UMIs per cell and s2 400, so s2 keeps 3 / 400 of them
+    assert_eq!(lines[1][..3], ["1", "s1", "s1"]);
+    assert_eq!(lines[1][4..], ["2", "6", "1", "6"]);
+    assert_eq!(lines[2][..3], ["2", "s2", "s2"]);
+    assert_eq!(lines[2][4..7], ["1", "400", "0.0075"]);
+    let retained: f64 = lines[2][7].parse().unwrap();
+    assert!(retained < 20.0, "{}", retained);
+
+    // the same seed thins the counts in the same way
+    sb.run(&["aggr", "-q", "s1", "s2", "-d", "--seed", "7", "-o", "again"])
+        .assert_success();
+    assert_eq!(
+        read(&sb, "again/sample_metadata.tsv").lines().nth(2),
+        meta.lines().nth(2)
+    );
+}
+
+#[test]
+fn aggr_rejects_a_truncated_barcode_list() {
+    let sb = two_samples();
+    sb.write("s2/af_quant/alevin/quants_mat_rows.txt", "");
+    sb.run(&["aggr", "-q", "s1", "s2", "-o", "out"])
+        .assert_failure("could not read the matrix of sample s2");
+}
@@ -0,0 +1,114 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+// a USA-mode quantification of the genes g1 and g2 in the cells AAAA,
+// CCCC and GGGG in `usa/af_quant`
+fn usa_sandbox() -> Sandbox {
+    let sb = Sandbox::new();
+    sb.write(
+        "usa/af_quant/quant.json",
+        "{\"usa_mode\": true, \"num_genes\": 6, \"num_quantified_cells\": 3}\n",
+    );
+    sb.write(
+        "usa/af_quant/alevin/quants_mat_rows.txt",
+        "AAAA\nCCCC\nGGGG\n",
+    );
+    sb.write(
+        "usa/af_quant/alevin/quants_mat_cols.txt",
+        "g1\ng2\ng1\ng2\ng1\ng2\n",
+    );
+    // S, U and A of g1 are columns 1, 3 and 5, those of g2 columns 2, 4 and 6
+    sb.write(
+        "usa/af_quant/alevin/quants_mat.mtx",
+        "%%MatrixMarket matrix coordinate real general\n3 6 6\n\
+         1 1 2\n1 3 1\n1 6 4\n\
+         2 4 5\n\
+         3 5 1\n3 1 3\n",
+    );
+    sb
+}
+
+fn read(sb: &Sandbox, rel: &str) -> String {
+    std::fs::read_to_string(sb.path(rel)).unwrap()
+}
+
+#[test]
+fn collapse_writes_gene_level_matrices() {
+    let sb = usa_sandbox();
+    sb.run(&[
+        "collapse",
+        "-q",
+        "usa",
+        "--counts",
+        "S+A",
+        "unspliced=U",
+        "S+U+A",
+        "-o",
+        "genes",
+    ])
+    .assert_success();
+
+    assert_eq!(
+        read(&sb, "genes/SA/alevin/quants_mat.mtx"),
+        "%%MatrixMarket matrix coordinate real general\n3 2 3\n1 1 2\n1 2 4\n3 1 4\n"
+    );
+    assert_eq!(
+        read(&sb, "genes/unspliced/alevin/quants_mat.mtx"),
+        "%%MatrixMarket matrix coordinate real general\n3 2 2\n1 1 1\n2 2 5\n"
+    );
+    assert_eq!(
+        read(&sb, "genes/SUA/alevin/quants_mat.mtx"),
+        "%%MatrixMarket matrix coordinate real general\n3 2 4\n1 1 3\n1 2 4\n2 2 5\n3 1 4\n"
+    );
+    assert_eq!(read(&sb, "genes/SA/alevin/quants_mat_cols.txt"), "g1\ng2\n");
+    assert_eq!(
+        read(&sb, "genes/SA/alevin/quants_mat_rows.txt"),
+        "AAAA\nCCCC\nGGGG\n"
+    );
+    let qj = sb.read_json("genes/SA/quant.json");
+    assert_eq!(qj["usa_mode"], false);
+    assert_eq!(qj["num_genes"], 2);
+    assert_eq!(qj["num_quantified_cells"], 3);
+    assert_eq!(qj["collapsed_counts"], "S+A");
+
+    // the outputs are quantifications of their own, no longer in USA mode
+    sb.run(&["collapse", "-q", "genes/SA", "-o", "again"])
+        .assert_failure("is not a USA-mode quantification");
+    let log = sb.read_json("genes/simpleaf_collapse_log.json");
+    assert_eq!(log["outputs"][1]["counts"], "U");
+}
+
+#[test]
+fn collapse_rejects_invalid_counts() {
+    let sb = usa_sandbox();
+    sb.run(&["collapse", "-q", "usa", "--counts", "S+X", "-o", "genes"])
+        .assert_failure("expected a `+`-separated subset of S, U and A");
+    sb.run(&["collapse", "-q", "usa", "--counts", "S+S", "-o", "genes"])
+        .assert_failure("more than once");
+    sb.run(&[
+        "collapse", "-q", "usa", "--counts", "S+A", "A+S", "-o", "genes",
+    ])
+    .assert_failure("more than one of the counts is named");
+    assert!(!sb.path("genes").exists());
+}
+
+#[test]
+fn collapse_rejects_a_matrix_larger_than_its_lists() {
+    let sb = usa_sandbox();
+    // the size line of the matrix disagrees with its barcodes and genes
+    sb.write(
+        "usa/af_quant/alevin/quants_mat.mtx",
+        "%%MatrixMarket matrix coordinate real general\n3 9 2\n1 1 2\n1 7 4\n",
+    );
+    sb.run(&["collapse", "-q", "usa", "--counts", "S", "-o", "cols"])
+        .assert_failure("is a 3 x 9 matrix, but 3 rows and 6 columns are listed alongside it");
+    sb.write(
+        "usa/af_quant/alevin/quants_mat.mtx",
+        "%%MatrixMarket matrix coordinate real general\n4 6 2\n1 1 2\n4 1 4\n",
+    );
+    sb.run(&["collapse", "-q", "usa", "--counts", "S", "-o", "rows"])
+        .assert_failure("is a 4 x 6 matrix, but 3 rows and 6 columns are listed alongside it");
+}
@@ -0,0 +1,387 @@
+//! A sandbox for running the `simpleaf` binary against stub versions of
+//! pyroe, salmon, alevin-fry and piscem. The stubs report supported
+//! versions, record their arguments and write the minimal outputs that
+//! simpleaf and the later steps expect.
+
+#![allow(dead_code)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// shared by all stubs: records the invocation, answers `--version` and
+// `--help`, fails on request (`STUB_FAIL="<prog> <subcommand>"`) and
+// hangs until it is killed on request (`STUB_HANG="<prog> <subcommand>"`)
+const STUB_PRELUDE: &str = r#"
+name=$(basename "$0")
+# the value following the flag $1 in the remaining arguments
+opt_val() {
+    local flag=$1; shift
+    while [ $# -gt 0 ]; do
+        if [ "$1" = "$flag" ]; then echo "$2"; return; fi
+        shift
+    done
+}
+# write the names $2... to $1 as cereal does a vector of strings, which is
+# how salmon starts its ctable.bin and piscem its .refinfo
+cereal_names() {
+    local out=$1; shift
+    u64() { printf "\\$(printf '%03o' "$1")\\0\\0\\0\\0\\0\\0\\0"; }
+    { u64 $#; for n in "$@"; do u64 ${#n}; printf '%s' "$n"; done; } > "$out"
+}
+# the names of the sequences of the FASTA file $1
+fasta_names() {
+    sed -n 's/^>\([^ ]*\).*/\1/p' "$1"
+}
+if [ "$1" = "--version" ]; then
+    var="STUB_VERSION_${name//-/_}"
+    echo "$name ${!var:-$DEFAULT_VERSION}"
+    exit 0
+fi
+if [ "$1" = "--help" ] || [ "$2" = "--help" ]; then
+    echo "$HELP"
+    exit 0
+fi
+{ printf '%s' "$name"; printf '\t%s' "$@"; printf '\n'; } >> "$STUB_LOG"
+if [ -n "$STUB_FAIL" ] && [ "$name $1" = "$STUB_FAIL" ]; then
+    echo "$name $1: stub failure" >&2
+    exit 3
+fi
+if [ -n "$STUB_HANG" ] && [ "$name $1" = "$STUB_HANG" ]; then
+    exec sleep 60
+fi
+"#;
+
+const SALMON: &str = r#"
+case "$1" in
+    index)
+        idx=$(opt_val -i "$@")
+        mkdir -p "$idx"
+        echo '{}' > "$idx/versionInfo.json"
+        if [[ " $* " == *" --sparse "* ]]; then
+            sampling=sparse; positions="sample_pos presence canonical extension direction"
+        else
+            sampling=dense; positions=pos
+        fi
+        echo "{\"sampling_type\": \"$sampling\"}" > "$idx/info.json"
+        for f in complete_ref_lens ctg_offsets mphf rank refAccumLengths reflengths refseq seq $positions; do
+            touch "$idx/$f.bin"
+        done
+        # the targets of a feature index are the first column of its table
+        targets=$(opt_val -t "$@")
+        if [[ " $* " == *" --features "* ]]; then
+            cereal_names "$idx/ctable.bin" $(cut -f1 "$targets")
+        else
+            cereal_names "$idx/ctable.bin" $(fasta_names "$targets")
+        fi
+        ;;
+    alevin)
+        out=$(opt_val -o "$@")
+        mkdir -p "$out"
+        touch "$out/map.rad"
+        echo "mapped reads"
+        ;;
+esac
+"#;
+
+const PISCEM: &str = r#"
+case "$1" in
+    build)
+        prefix=$(opt_val -o "$@")
+        for s in .sshash .ctab; do touch "$prefix$s"; done
+        cereal_names "$prefix.refinfo" $(fasta_names "$(opt_val -s "$@")")
+        ;;
+    map-sc)
+        out=$(opt_val -o "$@")
+        mkdir -p "$out"
+        touch "$out/map.rad"
+        ;;
+esac
+"#;
+
+const PYROE: &str = r#"
+if [ "$1" = "make-splici" ]; then
+    # the read length is the first numeric argument, followed by the output
+    args=("$@")
+    for i in "${!args[@]}"; do
+        if [[ "${args[$i]}" =~ ^[0-9]+$ ]]; then
+            rlen=${args[$i]}; out=${args[$((i + 1))]}; break
+        fi
+    done
+    mkdir -p "$out"
+    printf '>t1\nACGT\n>t1-I\nACGT\n' > "$out/splici_fl$((rlen - 5)).fa"
+    printf 't1\tg1\tS\nt1-I\tg1\tU\n' > "$out/splici_fl$((rlen - 5))_t2g_3col.tsv"
+fi
+"#;
+
+const ALEVIN_FRY: &str = r#"
+case "$1" in
+    generate-permit-list)
+        out=$(opt_val -o "$@")
+        mkdir -p "$out"
+        echo '{"num_cells": 2}' > "$out/generate_permit_list.json"
+        ;;
+    quant)
+        out=$(opt_val -o "$@")
+        mkdir -p "$out/alevin"
+        echo '{"num_quantified_cells": 2}' > "$out/quant.json"
+        printf 'g1\n' > "$out/alevin/quants_mat_cols.txt"
+        printf 'AAAA\nCCCC\n' > "$out/alevin/quants_mat_rows.txt"
+        printf '%%%%MatrixMarket matrix coordinate real general\n2 1 2\n1 1 3\n2 1 1\n' > "$out/alevin/quants_mat.mtx"
+        ;;
+esac
+"#;
+
+const SALMON_HELP: &str = "--sketch --rad --minScoreFraction --softclip --softclipOverhangs \
+    --chromium --chromiumV3 --dropseq --indropV2 --celseq2 --sciseq3 --splitseqV1 --splitseqV2";
+
+/// A temporary `ALEVIN_FRY_HOME`, the stub executables and a
+/// scratch directory for inputs and outputs.
+pub struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    /// A sandbox with the stubs installed but not yet registered.
+    pub fn new() -> Sandbox {
+        let dir = tempfile::tempdir().unwrap();
+        let sb = Sandbox { dir };
+        std::fs::create_dir_all(sb.af_home()).unwrap();
+        std::fs::create_dir_all(sb.bin()).unwrap();
+        sb.write_stub("salmon", "1.9.0", SALMON_HELP, SALMON);
+        sb.write_stub(
+            "alevin-fry",
+            "0.7.0",
+            "generate-permit-list collate quant infer convert view",
+            ALEVIN_FRY,
+        );
+        sb.write_stub("pyroe", "0.6.2", "make-splici make-spliceu", PYROE);
+        sb.write_stub("piscem", "0.4.3", "build map-sc map-bulk", PISCEM);
+        sb
+    }
+
+    /// A sandbox whose stubs are registered with `set-paths`.
+    pub fn registered() -> Sandbox {
+        let sb = Sandbox::new();
+        sb.set_paths(&[]).assert_success();
+        sb
+    }
+
+    fn write_stub(&self, name: &str, version: &str, help: &str, body: &str) {
+        let p = self.stub(name);
+        let script = format!(
+            "#!/bin/bash\nDEFAULT_VERSION={}\nHELP='{}'\n{}{}exit 0\n",
+            version, help, STUB_PRELUDE, body
+        );
+        std::fs::write(&p, script).unwrap();
+        std::fs::set_permissions(&p, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+    pub fn af_home(&self) -> PathBuf {
+        self.root().join("af_home")
+    }
+    pub fn bin(&self) -> PathBuf {
+        self.root().join("bin")
+    }
+    pub fn stub(&self, name: &str) -> PathBuf {
+        self.bin().join(name)
+    }
+    pub fn path(&self, rel: &str) -> PathBuf {
+        self.root().join(rel)
+    }
+    fn stub_log(&self) -> PathBuf {
+        self.root().join("calls.log")
+    }
+
+    /// Write `contents` to `rel` inside the sandbox, creating its parents.
+    pub fn write(&self, rel: &str, contents: &str) -> PathBuf {
+        let p = self.path(rel);
+        std::fs::create_dir_all(p.parent().unwrap()).unwrap();
+        std::fs::write(&p, contents).unwrap();
+        p
+    }
+
+    /// A FASTQ file of `n` reads of length `len` at `rel`.
+    pub fn fastq(&self, rel: &str, n: usize, len: usize) -> PathBuf {
+        let mut s = String::new();
+        for i in 0..n {
+            s.push_str(&format!(
+                "@r{}\n{}\n+\n{}\n",
+                i,
+                "A".repeat(len),
+                "I".repeat(len)
+            ));
+        }
+        self.write(rel, &s)
+    }
+
+    /// The command running the simpleaf binary inside the sandbox.
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_simpleaf"));
+        let path = format!(
+            "{}:{}",
+            self.bin().display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        cmd.args(args)
+            .current_dir(self.root())
+            .env("ALEVIN_FRY_HOME", self.af_home())
+            .env("PATH", path)
+            .env("STUB_LOG", self.stub_log())
+            .env("RUST_BACKTRACE", "0")
+            .env_remove("STUB_FAIL")
+            .env_remove("STUB_HANG")
+            .env_remove("SLURM_CPUS_PER_TASK")
+            .env_remove("SLURM_MEM_PER_NODE")
+            .env_remove("SLURM_MEM_PER_CPU");
+        cmd
+    }
+
+    /// Run simpleaf with the stub `hang` (`<prog> <subcommand>`) hanging,
+    /// and send it `signal` (e.g. `TERM`) once the stub has started.
+    pub fn run_interrupted(&self, args: &[&str], hang: &str, signal: &str) -> Run {
+        let child = self
+            .command(args)
+            .env("STUB_HANG", hang)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (prog, sub) = hang.split_once(' ').unwrap();
+        let started = Instant::now();
+        while self.calls_of(prog, sub).is_empty() {
+            assert!(
+                started.elapsed() < Duration::from_secs(30),
+                "{} was never started",
+                hang
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Run(child.wait_with_output().unwrap())
+    }
+
+    pub fn run(&self, args: &[&str]) -> Run {
+        Run(self.command(args).output().unwrap())
+    }
+
+    /// Register the stubs with `set-paths`, with any `extra` arguments.
+    pub fn set_paths(&self, extra: &[&str]) -> Run {
+        let salmon = self.stub("salmon");
+        let fry = self.stub("alevin-fry");
+        let pyroe = self.stub("pyroe");
+        let mut args = vec![
+            "set-paths",
+            "--salmon",
+            salmon.to_str().unwrap(),
+            "--alevin-fry",
+            fry.to_str().unwrap(),
+            "--pyroe",
+            pyroe.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        self.run(&args)
+    }
+
+    /// The arguments of every stub invocation so far, each
+    /// starting with the name of the stub.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        match std::fs::read_to_string(self.stub_log()) {
+            Ok(s) => s
+                .lines()
+                .map(|l| l.split('\t').map(String::from).collect())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// The invocations of `prog` with the subcommand `sub`.
+    pub fn calls_of(&self, prog: &str, sub: &str) -> Vec<Vec<String>> {
+        self.calls()
+            .into_iter()
+            .filter(|c| c[0] == prog && c.get(1).map(String::as_str) == Some(sub))
+            .collect()
+    }
+
+    pub fn read_json(&self, rel: &str) -> serde_json::Value {
+        let p = self.path(rel);
+        let s = std::fs::read_to_string(&p)
+            .unwrap_or_else(|e| panic!("could not read {}: {}", p.display(), e));
+        serde_json::from_str(&s).unwrap()
+    }
+
+    /// Build an index into `index` with the stubs.
+    pub fn build_index(&self, extra: &[&str]) -> Run {
+        let fa = self.write("genome.fa", ">chr1\nACGT\n");
+        let gtf = self.write("genes.gtf", "");
+        let mut args = vec![
+            "index",
+            "-f",
+            fa.to_str().unwrap(),
+            "-g",
+            gtf.to_str().unwrap(),
+            "-r",
+            "91",
+            "-o",
+            "index",
+        ];
+        args.extend_from_slice(extra);
+        self.run(&args)
+    }
+}
+
+/// The outcome of running simpleaf.
+pub struct Run(pub Output);
+
+impl Run {
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.0.stdout).into_owned()
+    }
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.0.stderr).into_owned()
+    }
+    pub fn assert_success(&self) -> &Run {
+        assert!(
+            self.0.status.success(),
+            "simpleaf failed\nstdout:\n{}\nstderr:\n{}",
+            self.stdout(),
+            self.stderr()
+        );
+        self
+    }
+    /// Assert that simpleaf failed with an error mentioning `what`.
+    pub fn assert_failure(&self, what: &str) -> &Run {
+        assert!(
+            !self.0.status.success(),
+            "simpleaf unexpectedly succeeded\nstdout:\n{}\nstderr:\n{}",
+            self.stdout(),
+            self.stderr()
+        );
+        assert!(
+            self.stderr().contains(what),
+            "expected the error to mention `{}`, but stderr was:\n{}",
+            what,
+            self.stderr()
+        );
+        self
+    }
+}
+
+/// Whether `args` contains `flag` immediately followed by `value`.
+pub fn has_arg(args: &[String], flag: &str, value: &str) -> bool {
+    args.windows(2).any(|w| w[0] == flag && w[1] == value)
+}
+
+pub fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag)
+}
@@ -0,0 +1,247 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+const FEATURE_REF: &str = "id,name,read,pattern,sequence,feature_type\n\
+    CD3,CD3_TotalSeqB,R2,5PNNNNNNNNNN(BC),CTCATTGTAACTCCT,Antibody Capture\n\
+    CD4,CD4_TotalSeqB,R2,5PNNNNNNNNNN(BC),TGTTCCCGCTCAACT,Antibody Capture\n";
+
+// a sandbox with an index of `FEATURE_REF` in `adt_index`
+fn feature_sandbox() -> Sandbox {
+    let sb = Sandbox::registered();
+    sb.write("features.csv", FEATURE_REF);
+    sb.run(&[
+        "index",
+        "--feature-ref",
+        "features.csv",
+        "-o",
+        "adt_index",
+        "-t",
+        "1",
+    ])
+    .assert_success();
+    sb.fastq("reads/adt_R1.fq", 10, 28);
+    sb.fastq("reads/adt_R2.fq", 10, 90);
+    sb
+}
+
+fn adt_quant_args<'a>(extra: &[&'a str]) -> Vec<&'a str> {
+    let mut args = vec![
+        "quant",
+        "-i",
+        "adt_index",
+        "-1",
+        "reads/adt_R1.fq",
+        "-2",
+        "reads/adt_R2.fq",
+        "-c",
+        "10xv3",
+        "-r",
+        "cr-like",
+        "-m",
+        "adt_index/index/t2g.tsv",
+        "-o",
+        "adt_quant",
+        "--feature-type",
+        "adt",
+    ];
+    args.extend_from_slice(extra);
+    args
+}
+
+#[test]
+fn feature_index_from_a_feature_reference() {
+    let sb = feature_sandbox();
+
+    assert!(sb.calls_of("pyroe", "make-splici").is_empty());
+    let salmon = &sb.calls_of("salmon", "index")[0];
+    assert!(has_arg(salmon, "-t", "adt_index/ref/features.tsv"));
+    assert!(has_flag(salmon, "--features"));
+    assert!(has_arg(salmon, "-k", "7"));
+
+    let barcodes = std::fs::read_to_string(sb.path("adt_index/ref/features.tsv")).unwrap();
+    assert_eq!(barcodes, "CD3\tCTCATTGTAACTCCT\nCD4\tTGTTCCCGCTCAACT\n");
+    let t2g = std::fs::read_to_string(sb.path("adt_index/index/t2g.tsv")).unwrap();
+    assert_eq!(t2g, "CD3\tCD3\nCD4\tCD4\n");
+    let info = sb.read_json("adt_index/index_info.json");
+    assert_eq!(info["feature_geometry"], "2[11-25]");
+
+    sb.run(&["index-info", "adt_index"]).assert_success();
+}
+
+#[test]
+fn feature_index_rejects_invalid_references() {
+    let sb = Sandbox::registered();
+    sb.write(
+        "mixed.csv",
+        "id,name,read,pattern,sequence,feature_type\n\
+         A,A,R2,^(BC),ACGTACGT,Antibody Capture\n\
+         B,B,R2,^(BC),ACGTACGTAC,Antibody Capture\n",
+    );
+    sb.run(&["index", "--feature-ref", "mixed.csv", "-o", "idx"])
+        .assert_failure("barcodes of different lengths");
+    sb.write(
+        "nocol.csv",
+        "id,name,read,pattern,sequence\nA,A,R2,^(BC),ACGT\n",
+    );
+    sb.run(&["index", "--feature-ref", "nocol.csv", "-o", "idx2"])
+        .assert_failure("no `feature_type` column");
+    sb.run(&[
+        "index",
+        "--feature-ref",
+        "nocol.csv",
+        "-f",
+        "genome.fa",
+        "-o",
+        "idx3",
+    ])
+    .assert_failure("cannot be used with");
+    assert!(sb.calls().is_empty());
+}
+
+#[test]
+fn adt_quant_uses_the_feature_geometry() {
+    let sb = feature_sandbox();
+    sb.run(&adt_quant_args(&["-k", "-t", "1"])).assert_success();
+
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_arg(map, "--read-geometry", "2[11-25]"));
+    assert!(has_arg(map, "--bc-geometry", "1[1-16]"));
+    assert!(has_arg(map, "--umi-geometry", "1[17-28]"));
+    assert!(!has_flag(map, "--chromiumV3"));
+    let quant = &sb.calls_of("alevin-fry", "quant")[0];
+    assert!(has_arg(quant, "-m", "adt_index/index/t2g.tsv"));
+
+    let log = sb.read_json("adt_quant/simpleaf_quant_log.json");
+    assert_eq!(log["feature_type"], "adt");
+    assert!(log["gex_aligned"].is_null());
+}
+
+#[test]
+fn adt_quant_needs_a_feature_index() {
+    let sb = feature_sandbox();
+    sb.build_index(&[]).assert_success();
+    let mut args = adt_quant_args(&["-k"]);
+    args[2] = "index";
+    sb.run(&args)
+        .assert_failure("was not built by simpleaf index --feature-ref");
+}
+
+#[test]
+fn adt_quant_is_aligned_to_the_gex_cells() {
+    let sb = feature_sandbox();
+    // the GEX run found the cells CCCC and GGGG
+    sb.write("gex/af_quant/quant.json", "{}\n");
+    sb.write("gex/af_quant/alevin/quants_mat_rows.txt", "CCCC\nGGGG\n");
+    sb.write("gex/af_quant/alevin/quants_mat_cols.txt", "g1\n");
+    sb.write(
+        "gex/af_quant/alevin/quants_mat.mtx",
+        "%%MatrixMarket matrix coordinate real general\n2 1 1\n1 1 5\n",
+    );
+    sb.run(&adt_quant_args(&["--gex-quant", "gex", "--dry-run"]))
+        .assert_failure("--dry-run cannot describe this run: --gex-quant");
+    sb.run(&adt_quant_args(&["--gex-quant", "gex"]))
+        .assert_success();
+
+    let gpl = &sb.calls_of("alevin-fry", "generate-permit-list")[0];
+    assert!(has_arg(gpl, "--valid-bc", "adt_quant/gex_permit_list.txt"));
+    let permit = std::fs::read_to_string(sb.path("adt_quant/gex_permit_list.txt")).unwrap();
+    assert_eq!(permit, "CCCC\nGGGG\n");
+
+    // the stub quantified AAAA (3 counts) and CCCC (1 count)
+    let rows =
+        std::fs::read_to_string(sb.path("adt_quant/gex_aligned/quants_mat_rows.txt")).unwrap();
+    assert_eq!(rows, "CCCC\nGGGG\n");
+    let mtx = std::fs::read_to_string(sb.path("adt_quant/gex_aligned/quants_mat.mtx")).unwrap();
+    assert!(mtx.ends_with("2 1 1\n1 1 1\n"), "{}", mtx);
+}
+
+const GUIDE_REF: &str = "id,name,read,pattern,sequence,feature_type\n\
+    g1,GENE1-1,R2,(BC)GTTTAAGAGC,GACTCCGGGTACTAAATGTC,CRISPR Guide Capture\n\
+    g2,GENE2-1,R2,(BC)GTTTAAGAGC,AGCAGCGTCAGGCTCACCA,CRISPR Guide Capture\n";
+
+#[test]
+fn crispr_guides_are_mapped_with_their_scaffold() {
+    let sb = Sandbox::registered();
+    sb.write("guides.csv", GUIDE_REF);
+    sb.run(&["index", "--feature-ref", "guides.csv", "-o", "guide_index"])
+        .assert_success();
+
+    let salmon = &sb.calls_of("salmon", "index")[0];
+    assert!(has_arg(salmon, "-k", "19"));
+    let seqs = std::fs::read_to_string(sb.path("guide_index/ref/features.tsv")).unwrap();
+    assert_eq!(
+        seqs,
+        "g1\tGACTCCGGGTACTAAATGTCGTTTAAGAGC\ng2\tAGCAGCGTCAGGCTCACCAGTTTAAGAGC\n"
+    );
+    let info = sb.read_json("guide_index/index_info.json");
+    assert_eq!(info["feature_geometry"], "2[1-end]");
+    assert_eq!(info["feature_types"][0], "CRISPR Guide Capture");
+}
+
+#[test]
+fn crispr_quant_assigns_guides() {
+    let sb = Sandbox::registered();
+    sb.write("guides.csv", GUIDE_REF);
+    sb.run(&["index", "--feature-ref", "guides.csv", "-o", "guide_index"])
+        .assert_success();
+    sb.fastq("reads/crispr_R1.fq", 10, 28);
+    sb.fastq("reads/crispr_R2.fq", 10, 90);
+    let args = |extra: &[&'static str]| {
+        let mut a = vec![
+            "quant",
+            "-i",
+            "guide_index",
+            "-1",
+            "reads/crispr_R1.fq",
+            "-2",
+            "reads/crispr_R2.fq",
+            "-c",
+            "10xv3",
+            "-r",
+            "cr-like",
+            "-m",
+            "guide_index/index/t2g.tsv",
+            "-o",
+            "crispr_quant",
+            "-k",
+            "--overwrite",
+        ];
+        a.extend_from_slice(extra);
+        a
+    };
+
+    sb.run(&args(&["--guide-calling", "umi-threshold"]))
+        .assert_failure("require --feature-type crispr");
+
+    sb.run(&args(&["--feature-type", "crispr", "--dry-run"]))
+        .assert_failure("--dry-run cannot describe this run: --feature-type crispr");
+
+    // the stub quantified 3 UMIs of its only guide in AAAA and 1 in CCCC
+    sb.run(&args(&["--feature-type", "crispr"]))
+        .assert_success();
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_arg(map, "--read-geometry", "2[1-end]"));
+    let calls = std::fs::read_to_string(sb.path("crispr_quant/guide_calls.tsv")).unwrap();
+    assert_eq!(calls, "barcode\tnum_guides\tguides\tumis\nAAAA\t1\tg1\t3\n");
+    let log = sb.read_json("crispr_quant/simpleaf_quant_log.json");
+    assert_eq!(log["guide_calls"]["calling"]["method"], "mixture");
+    assert_eq!(log["guide_calls"]["num_cells_with_guides"], 1);
+
+    sb.run(&args(&[
+        "--feature-type",
+        "crispr",
+        "--guide-calling",
+        "umi-threshold",
+        "--min-guide-umis",
+        "1",
+    ]))
+    .assert_success();
+    let calls = std::fs::read_to_string(sb.path("crispr_quant/guide_calls.tsv")).unwrap();
+    assert_eq!(
+        calls,
+        "barcode\tnum_guides\tguides\tumis\nAAAA\t1\tg1\t3\nCCCC\t1\tg1\t1\n"
+    );
+}
@@ -0,0 +1,168 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+// an HTO quantification of 3 hashtags in `hto/af_quant`: 8 cells of each
+// hashtag, 2 cells with both HTO1 and HTO2 and a cell with none, whose
+// barcodes are `C<i>`
+fn hto_sandbox() -> Sandbox {
+    let sb = Sandbox::new();
+    let mut cells: Vec<[u32; 3]> = Vec::new();
+    for h in 0..3 {
+        for i in 0..8u32 {
+            let mut c = [i % 3, (i + 1) % 4, i % 2];
+            c[h] = 150 + 10 * i;
+            cells.push(c);
+        }
+    }
+    cells.push([180, 160, 2]);
+    cells.push([140, 200, 1]);
+    cells.push([1, 2, 0]);
+
+    let mut rows = String::new();
+    let mut entries = Vec::new();
+    for (i, c) in cells.iter().enumerate() {
+        rows.push_str(&format!("C{}\n", i));
+        for (h, v) in c.iter().enumerate() {
+            if *v > 0 {
+                entries.push(format!("{} {} {}", i + 1, h + 1, v));
+            }
+        }
+    }
+    sb.write("hto/af_quant/quant.json", "{}\n");
+    sb.write("hto/af_quant/alevin/quants_mat_rows.txt", &rows);
+    sb.write(
+        "hto/af_quant/alevin/quants_mat_cols.txt",
+        "HTO1\nHTO2\nHTO3\n",
+    );
+    sb.write(
+        "hto/af_quant/alevin/quants_mat.mtx",
+        &format!(
+            "%%MatrixMarket matrix coordinate real general\n{} 3 {}\n{}\n",
+            cells.len(),
+            entries.len(),
+            entries.join("\n")
+        ),
+    );
+
+    // the GEX cells also include C99, which has no hashtag counts
+    let mut gex: String = (0..cells.len()).map(|i| format!("C{}\n", i)).collect();
+    gex.push_str("C99\n");
+    sb.write("gex_cells.txt", &gex);
+    sb
+}
+
+fn read_calls(sb: &Sandbox) -> Vec<Vec<String>> {
+    std::fs::read_to_string(sb.path("demux/hto_calls.tsv"))
+        .unwrap()
+        .lines()
+        .map(|l| l.split('\t').map(String::from).collect())
+        .collect()
+}
+
+#[test]
+fn hto_demux_calls_singlets_doublets_and_negatives() {
+    let sb = hto_sandbox();
+    sb.write("samples.tsv", "HTO1\tdonorA\nHTO2\tdonorB\n");
+    sb.run(&[
+        "hto-demux",
+        "--hto-quant",
+        "hto",
+        "-c",
+        "gex_cells.txt",
+        "-s",
+        "samples.tsv",
+        "-o",
+        "demux",
+    ])
+    .assert_success();
+
+    let calls = read_calls(&sb);
+    assert_eq!(
+        calls[0],
+        [
+            "barcode",
+            "classification",
+            "call",
+            "hto_max",
+            "hto_second",
+            "count_max",
+            "count_second"
+        ]
+    );
+    let call = |bc: &str| {
+        let row = calls.iter().find(|r| r[0] == bc).unwrap();
+        (row[1].clone(), row[2].clone())
+    };
+    assert_eq!(call("C0"), ("Singlet".into(), "donorA".into()));
+    assert_eq!(call("C9"), ("Singlet".into(), "donorB".into()));
+    // hashtags without a sample keep their id
+    assert_eq!(call("C17"), ("Singlet".into(), "HTO3".into()));
+    assert_eq!(call("C24"), ("Doublet".into(), "Doublet".into()));
+    assert_eq!(call("C25"), ("Doublet".into(), "Doublet".into()));
+    assert_eq!(call("C26"), ("Negative".into(), "Negative".into()));
+    assert_eq!(call("C99"), ("Negative".into(), "Negative".into()));
+    assert_eq!(calls.len(), 29);
+
+    let summary = sb.read_json("demux/hto_demux_summary.json");
+    assert_eq!(summary["classifications"]["Singlet"], 24);
+    assert_eq!(summary["classifications"]["Doublet"], 2);
+    assert_eq!(summary["classifications"]["Negative"], 2);
+    assert_eq!(summary["hashtags"][0]["sample"], "donorA");
+    assert!(summary["hashtags"][0]["threshold"].as_u64().unwrap() < 150);
+}
+
+#[test]
+fn hto_demux_translates_barcodes() {
+    let sb = hto_sandbox();
+    // the GEX barcode of cell C<i> is G<i>
+    let table: String = (0..27).map(|i| format!("G{}\tC{}\n", i, i)).collect();
+    sb.write("translation.tsv", &table);
+    let gex: String = (0..27).map(|i| format!("G{}\n", i)).collect();
+    sb.write("gex_g.txt", &gex);
+
+    sb.run(&[
+        "hto-demux",
+        "--hto-quant",
+        "hto",
+        "-c",
+        "gex_g.txt",
+        "-o",
+        "demux",
+    ])
+    .assert_failure("--bc-translation");
+    sb.run(&[
+        "hto-demux",
+        "--hto-quant",
+        "hto",
+        "-c",
+        "gex_g.txt",
+        "--bc-translation",
+        "translation.tsv",
+        "-o",
+        "demux",
+    ])
+    .assert_success();
+    let calls = read_calls(&sb);
+    let row = calls.iter().find(|r| r[0] == "G0").unwrap();
+    assert_eq!(row[2], "HTO1");
+}
+
+#[test]
+fn hto_demux_rejects_a_truncated_hashtag_list() {
+    let sb = hto_sandbox();
+    // the matrix has 3 columns, but only 2 hashtags are listed
+    sb.write("hto/af_quant/alevin/quants_mat_cols.txt", "HTO1\nHTO2\n");
+    sb.run(&[
+        "hto-demux",
+        "--hto-quant",
+        "hto",
+        "-c",
+        "gex_cells.txt",
+        "-o",
+        "demux",
+    ])
+    .assert_failure("is a 27 x 3 matrix, but 27 rows and 2 columns are listed alongside it");
+}
@@ -0,0 +1,201 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+#[test]
+fn index_runs_pyroe_then_salmon() {
+    let sb = Sandbox::registered();
+    sb.build_index(&["-t", "1"]).assert_success();
+
+    let calls = sb.calls();
+    assert_eq!(calls.len(), 2);
+    let pyroe = &calls[0];
+    assert_eq!(&pyroe[..2], ["pyroe", "make-splici"]);
+    assert!(pyroe.ends_with(&[String::from("91"), String::from("index/ref")]));
+
+    let salmon = &calls[1];
+    assert_eq!(&salmon[..2], ["salmon", "index"]);
+    assert!(has_arg(salmon, "-i", "index/index"));
+    assert!(has_arg(salmon, "-t", "index/ref/splici_fl86.fa"));
+    assert!(has_arg(salmon, "--threads", "1"));
+    assert!(!has_flag(salmon, "--sparse"));
+
+    // the transcript to gene map is copied into the index
+    assert!(sb.path("index/index/t2g_3col.tsv").is_file());
+    let info = sb.read_json("index/index_info.json");
+    assert_eq!(info["args"]["rlen"], 91);
+    assert_eq!(info["args"]["mapper"], "salmon");
+    let log = sb.read_json("index/simpleaf_index_log.json");
+    assert!(log["resource_info"]["index"].is_object());
+    assert!(log["resource_limits"]["cpus"].as_u64().unwrap() >= 1);
+}
+
+#[test]
+fn index_passes_options_through() {
+    let sb = Sandbox::registered();
+    sb.build_index(&[
+        "--sparse",
+        "--dedup",
+        "--pyroe-args",
+        "--no-bt",
+        "--salmon-index-args",
+        "-k 23",
+    ])
+    .assert_success();
+
+    let pyroe = &sb.calls_of("pyroe", "make-splici")[0];
+    assert!(has_flag(pyroe, "--dedup-seqs"));
+    assert!(has_flag(pyroe, "--no-bt"));
+    let salmon = &sb.calls_of("salmon", "index")[0];
+    assert!(has_flag(salmon, "--sparse"));
+    assert!(has_arg(salmon, "-k", "23"));
+}
+
+#[test]
+fn index_rejects_extra_args_simpleaf_sets() {
+    let sb = Sandbox::registered();
+    sb.build_index(&["--salmon-index-args", "-p 4"])
+        .assert_failure("--salmon-index-args cannot contain -p");
+    sb.build_index(&["--pyroe-args", "--flank-trim-length 7"])
+        .assert_failure("--pyroe-args cannot contain --flank-trim-length");
+    assert!(sb.calls().is_empty());
+}
+
+#[test]
+fn index_dry_run_runs_nothing() {
+    let sb = Sandbox::registered();
+    let run = sb.build_index(&["--dry-run"]);
+    run.assert_success();
+    assert!(run.stdout().contains("make-splici"));
+    assert!(run.stdout().contains("/salmon index"));
+    assert!(sb.calls().is_empty());
+    assert!(!sb.path("index").exists());
+}
+
+#[test]
+fn index_refuses_a_non_empty_output_directory() {
+    let sb = Sandbox::registered();
+    sb.write("index/old.txt", "earlier results");
+    sb.build_index(&[]).assert_failure("--overwrite");
+    assert!(sb.calls().is_empty());
+
+    sb.build_index(&["--overwrite"]).assert_success();
+    assert!(!sb.path("index/.simpleaf.lock").exists());
+}
+
+#[test]
+fn index_takes_over_a_stale_lock() {
+    let sb = Sandbox::registered();
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+    // the lock of a live process is respected
+    sb.write(
+        "index/.simpleaf.lock",
+        &format!("{}\n{}", std::process::id(), host),
+    );
+    sb.build_index(&["--overwrite"])
+        .assert_failure("is in use by process");
+    assert!(sb.calls().is_empty());
+
+    // the lock of a process that has exited is taken over
+    let mut done = std::process::Command::new("true").spawn().unwrap();
+    done.wait().unwrap();
+    sb.write("index/.simpleaf.lock", &format!("{}\n{}", done.id(), host));
+    let run = sb.build_index(&["--overwrite"]);
+    run.assert_success();
+    assert!(run.stderr().contains("taking over the stale lock"));
+    assert!(!sb.path("index/.simpleaf.lock").exists());
+    // no temporary lock file is left behind
+    let left = std::fs::read_dir(sb.path("index"))
+        .unwrap()
+        .filter(|e| {
+            let name = e.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with(".simpleaf.lock")
+        })
+        .count();
+    assert_eq!(left, 0);
+}
+
+#[test]
+fn index_reports_failing_steps() {
+    let sb = Sandbox::registered();
+    let out = sb
+        .command(&[
+            "index",
+            "-f",
+            "genome.fa",
+            "-g",
+            "genes.gtf",
+            "-r",
+            "91",
+            "-o",
+            "index",
+        ])
+        .env("STUB_FAIL", "salmon index")
+        .output()
+        .unwrap();
+    Run(out).assert_failure("salmon index failed");
+    // the error output of the step is kept in its log
+    let log = std::fs::read_to_string(sb.path("index/logs/index.log")).unwrap();
+    assert!(log.contains("stub failure"));
+}
+
+#[test]
+fn index_with_piscem() {
+    let sb = Sandbox::registered();
+    sb.build_index(&["--mapper", "piscem", "-t", "1"])
+        .assert_success();
+
+    let build = &sb.calls_of("piscem", "build")[0];
+    assert!(has_arg(build, "-o", "index/index/piscem_idx"));
+    assert!(has_arg(build, "-t", "1"));
+    assert!(sb.calls_of("salmon", "index").is_empty());
+    assert_eq!(
+        sb.read_json("index/index_info.json")["args"]["mapper"],
+        "piscem"
+    );
+
+    sb.run(&["index-info", "index"]).assert_success();
+}
+
+#[test]
+fn index_info_checks_sparse_indices() {
+    let sb = Sandbox::registered();
+    sb.build_index(&["--sparse"]).assert_success();
+    let run = sb.run(&["index-info", "index"]);
+    run.assert_success();
+    assert!(run.stdout().contains("salmon index file sample_pos.bin"));
+    assert!(!run.stdout().contains("pos.bin is missing"));
+
+    std::fs::remove_file(sb.path("index/index/presence.bin")).unwrap();
+    sb.run(&["index-info", "index"])
+        .assert_failure("found 1 problems");
+}
+
+#[test]
+fn index_info_reads_the_targets_from_the_index() {
+    let sb = Sandbox::registered();
+    sb.build_index(&[]).assert_success();
+    // the index is checked without its reference
+    std::fs::remove_dir_all(sb.path("index/ref")).unwrap();
+    let run = sb.run(&["index-info", "index"]);
+    run.assert_success();
+    assert!(run
+        .stdout()
+        .contains("index/index/t2g_3col.tsv covers all 2 targets of the index"));
+
+    sb.write("index/index/t2g_3col.tsv", "t1\tg1\tS\n");
+    let run = sb.run(&["index-info", "index"]);
+    run.assert_failure("found 1 problems");
+    assert!(run
+        .stdout()
+        .contains("1 of the 2 targets of the index are not in"));
+
+    // target names that cannot be read are a problem too
+    sb.write("index/index/t2g_3col.tsv", "t1\tg1\tS\nt1-I\tg1\tU\n");
+    sb.write("index/index/ctable.bin", "");
+    let run = sb.run(&["index-info", "index"]);
+    run.assert_failure("found 1 problems");
+    assert!(run.stdout().contains("could not read the target names"));
+}
@@ -0,0 +1,51 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use simpleaf::*;
+
+#[test]
+fn library_describes_a_batch() {
+    let sb = Sandbox::registered();
+    sb.build_index(&[]).assert_success();
+    sb.fastq("reads/a_R1.fq", 10, 28);
+    sb.fastq("reads/a_R2.fq", 10, 91);
+    sb.write(
+        "samples.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         A\treads/a_R1.fq\treads/a_R2.fq\t10xv3\tforced-cells=50\n",
+    );
+
+    let pipeline = Pipeline::from_af_home(&sb.af_home()).unwrap();
+    let samples = parse_sample_sheet(&sb.path("samples.tsv")).unwrap();
+    assert_eq!(samples[0].filter, Some(FilterChoice::ForcedCells(50)));
+    let cfg = BatchConfig::new(
+        sb.path("index"),
+        samples,
+        sb.path("index/index/t2g_3col.tsv"),
+        sb.path("batch"),
+    );
+    let mut sh = ShellScript::new("batch");
+    pipeline.batch_script(&cfg, &mut sh).unwrap();
+    let script = sh.render();
+    assert!(script.contains("# sample A"), "{}", script);
+    assert!(script.contains("--force 50"), "{}", script);
+    assert!(!sb.path("batch").exists());
+
+    // without a filter, the quantification of a chemistry without a
+    // default filtering cannot be resolved
+    let mut quant_cfg = QuantConfig::new(
+        sb.path("index"),
+        vec![sb.path("reads/a_R1.fq")],
+        vec![sb.path("reads/a_R2.fq")],
+        "10xv2",
+        sb.path("index/index/t2g_3col.tsv"),
+        sb.path("quant"),
+    );
+    quant_cfg.filter = None;
+    let err = pipeline
+        .quant_script(&quant_cfg, &mut ShellScript::new("quant"))
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("has no default"), "{:#}", err);
+}
@@ -0,0 +1,684 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+// a sandbox with a (salmon) index and a sample of reads of length 91
+fn indexed_sandbox(index_args: &[&str]) -> Sandbox {
+    let sb = Sandbox::registered();
+    sb.build_index(index_args).assert_success();
+    sb.fastq("reads/s_R1.fq", 10, 28);
+    sb.fastq("reads/s_R2.fq", 10, 91);
+    sb
+}
+
+fn quant_args<'a>(extra: &[&'a str]) -> Vec<&'a str> {
+    let mut args = vec![
+        "quant",
+        "-i",
+        "index",
+        "-1",
+        "reads/s_R1.fq",
+        "-2",
+        "reads/s_R2.fq",
+        "-c",
+        "10xv3",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "quant",
+    ];
+    args.extend_from_slice(extra);
+    args
+}
+
+#[test]
+fn quant_runs_the_four_steps() {
+    let sb = indexed_sandbox(&[]);
+    let before = sb.calls().len();
+    sb.run(&quant_args(&["-k", "-t", "1"])).assert_success();
+
+    let calls: Vec<Vec<String>> = sb.calls().split_off(before);
+    let steps: Vec<(&str, &str)> = calls
+        .iter()
+        .map(|c| (c[0].as_str(), c[1].as_str()))
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            ("salmon", "alevin"),
+            ("alevin-fry", "generate-permit-list"),
+            ("alevin-fry", "collate"),
+            ("alevin-fry", "quant"),
+        ]
+    );
+
+    let map = &calls[0];
+    assert!(has_arg(map, "--index", "index"));
+    assert!(has_arg(map, "-l", "A"));
+    assert!(has_arg(map, "-1", "reads/s_R1.fq"));
+    assert!(has_arg(map, "-2", "reads/s_R2.fq"));
+    assert!(has_flag(map, "--sketch"));
+    assert!(has_flag(map, "--chromiumV3"));
+    assert!(has_arg(map, "--threads", "1"));
+
+    let gpl = &calls[1];
+    assert!(has_flag(gpl, "--knee"));
+    assert!(has_arg(gpl, "-d", "fw"));
+
+    let quant = &calls[3];
+    assert!(has_arg(quant, "-r", "cr-like"));
+    assert!(has_arg(quant, "-m", "index/index/t2g_3col.tsv"));
+
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["mapper"], "salmon");
+    assert_eq!(log["threads"]["map"], 1);
+    assert_eq!(log["mapping"]["mode"], "sketch");
+    // durations are written as "<seconds>.<nanoseconds>"
+    let map_time = log["time_info"]["map_time"].as_str().unwrap();
+    assert!(map_time.split_once('.').is_some(), "{}", map_time);
+    assert!(sb.path("quant/af_quant/quant.json").is_file());
+}
+
+#[test]
+fn quant_filter_options() {
+    let sb = indexed_sandbox(&[]);
+    sb.run(&quant_args(&["--expect-cells", "3000"]))
+        .assert_success();
+    let gpl = sb.calls_of("alevin-fry", "generate-permit-list");
+    assert!(has_arg(&gpl[0], "--force", "3000"));
+
+    sb.write("pl.txt", "AAAA\n");
+    sb.run(&quant_args(&["-x", "pl.txt", "--overwrite"]))
+        .assert_success();
+    let gpl = sb.calls_of("alevin-fry", "generate-permit-list");
+    assert!(has_arg(&gpl[1], "--valid-bc", "pl.txt"));
+
+    // the filters are mutually exclusive
+    sb.run(&quant_args(&["-k", "--forced-cells", "10", "--overwrite"]))
+        .assert_failure("cannot be used with");
+}
+
+#[test]
+fn quant_selective_alignment_and_extra_args() {
+    let sb = indexed_sandbox(&[]);
+    sb.run(&quant_args(&[
+        "-k",
+        "--mapping-mode",
+        "selective-alignment",
+        "--min-score-fraction",
+        "0.65",
+        "--softclip",
+        "--salmon-map-args",
+        "--maxReadOcc 200",
+        "--collate-args=--max-records 100",
+    ]))
+    .assert_success();
+
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_flag(map, "--rad"));
+    assert!(!has_flag(map, "--sketch"));
+    assert!(has_arg(map, "--minScoreFraction", "0.65"));
+    assert!(has_flag(map, "--softclip"));
+    assert!(has_arg(map, "--maxReadOcc", "200"));
+    let collate = &sb.calls_of("alevin-fry", "collate")[0];
+    assert!(has_arg(collate, "--max-records", "100"));
+
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["mapping"]["mode"], "selective-alignment");
+    assert_eq!(log["extra_args"]["map"][0], "--maxReadOcc");
+}
+
+#[test]
+fn quant_rejects_invalid_options() {
+    let sb = indexed_sandbox(&[]);
+    let before = sb.calls().len();
+    sb.run(&quant_args(&["-k", "--softclip"]))
+        .assert_failure("require --mapping-mode selective-alignment");
+    sb.run(&quant_args(&["-k", "--salmon-map-args", "--chromium"]))
+        .assert_failure("conflicts with the --chromiumV3 option");
+    sb.run(&quant_args(&[
+        "-k",
+        "--fry-quant-args",
+        "--resolution parsimony",
+    ]))
+    .assert_failure("--fry-quant-args cannot contain --resolution");
+    assert_eq!(sb.calls().len(), before);
+}
+
+#[test]
+fn quant_stops_at_the_failing_step() {
+    let sb = indexed_sandbox(&[]);
+    let before = sb.calls().len();
+    let out = sb
+        .command(&quant_args(&["-k"]))
+        .env("STUB_FAIL", "alevin-fry collate")
+        .output()
+        .unwrap();
+    Run(out).assert_failure("collate failed");
+
+    let steps: Vec<String> = sb.calls()[before..].iter().map(|c| c[1].clone()).collect();
+    assert_eq!(steps, ["alevin", "generate-permit-list", "collate"]);
+    // the partial log records the failed step and how it exited
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["status"], "failed");
+    assert_eq!(log["failed_step"], "collate");
+    assert_eq!(log["completed_steps"], serde_json::json!(["map", "gpl"]));
+    assert_eq!(log["resource_info"]["collate"]["exit_code"], 3);
+    assert!(!sb.path("quant/incomplete").exists());
+    assert!(!sb.path("quant/.simpleaf.lock").exists());
+}
+
+#[test]
+fn quant_checks_the_read_length() {
+    let sb = indexed_sandbox(&[]);
+    sb.fastq("reads/short_R2.fq", 10, 50);
+    let mut args = quant_args(&["-k", "--compat-check", "error"]);
+    args[6] = "reads/short_R2.fq";
+    let before = sb.calls().len();
+    sb.run(&args)
+        .assert_failure("the median length of the reads");
+    assert_eq!(sb.calls().len(), before);
+
+    // by default, only a warning
+    let mut args = quant_args(&["-k"]);
+    args[6] = "reads/short_R2.fq";
+    let run = sb.run(&args);
+    run.assert_success();
+    assert!(run.stderr().contains("the median length of the reads"));
+}
+
+#[test]
+fn quant_requires_salmon_capabilities() {
+    let sb = indexed_sandbox(&[]);
+
+    // a salmon without --sketch, as recorded by set-paths
+    let info_file = sb.path("af_home/simpleaf_info.json");
+    let mut info = sb.read_json("af_home/simpleaf_info.json");
+    info["prog_info"]["salmon"]["capabilities"] = serde_json::json!(["--chromiumV3"]);
+    std::fs::write(&info_file, info.to_string()).unwrap();
+
+    sb.run(&quant_args(&["-k"]))
+        .assert_failure("does not support the --sketch option");
+}
+
+#[test]
+fn quant_detects_a_piscem_index() {
+    let sb = indexed_sandbox(&["--mapper", "piscem"]);
+    sb.run(&quant_args(&["-k", "-t", "1"])).assert_success();
+
+    assert!(sb.calls_of("salmon", "alevin").is_empty());
+    let map = &sb.calls_of("piscem", "map-sc")[0];
+    assert!(has_arg(map, "-i", "index/index/piscem_idx"));
+    assert!(has_arg(map, "-g", "chromium_v3"));
+    assert!(has_arg(map, "-o", "quant/af_map"));
+    assert_eq!(
+        sb.read_json("quant/simpleaf_quant_log.json")["mapper"],
+        "piscem"
+    );
+}
+
+#[test]
+fn quant_sample_sheet() {
+    let sb = indexed_sandbox(&[]);
+    sb.fastq("reads/b_R1.fq", 10, 28);
+    sb.fastq("reads/b_R2.fq", 10, 91);
+    sb.write(
+        "samples.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         A\treads/s_R1.fq\treads/s_R2.fq\t10xv3\tknee\n\
+         B\treads/b_R1.fq\treads/b_R2.fq\t10xv2\texpect-cells=100\n",
+    );
+    sb.run(&[
+        "quant",
+        "-i",
+        "index",
+        "--sample-sheet",
+        "samples.tsv",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "batch",
+    ])
+    .assert_success();
+
+    let maps = sb.calls_of("salmon", "alevin");
+    assert_eq!(maps.len(), 2);
+    assert!(maps.iter().any(|m| has_flag(m, "--chromium")));
+    assert!(maps.iter().any(|m| has_flag(m, "--chromiumV3")));
+    assert!(sb.path("batch/A/af_quant/quant.json").is_file());
+    assert!(sb.path("batch/B/af_quant/quant.json").is_file());
+    let summary = sb.read_json("batch/simpleaf_batch_summary.json");
+    assert!(summary.to_string().contains("succeeded"));
+}
+
+#[test]
+fn quant_sample_sheet_dry_run_downloads_a_shared_permit_list_once() {
+    let sb = indexed_sandbox(&[]);
+    sb.fastq("reads/b_R1.fq", 10, 28);
+    sb.fastq("reads/b_R2.fq", 10, 91);
+    sb.write(
+        "samples.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         A\treads/s_R1.fq\treads/s_R2.fq\t10xv3\tunfiltered-pl\n\
+         B\treads/b_R1.fq\treads/b_R2.fq\t10xv3\tunfiltered-pl\n",
+    );
+    let run = sb.run(&[
+        "quant",
+        "-i",
+        "index",
+        "--sample-sheet",
+        "samples.tsv",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "batch",
+        "--dry-run",
+    ]);
+    run.assert_success();
+    let script = run.stdout();
+    assert_eq!(script.matches("download the permit list").count(), 1);
+    let download = script.find("download the permit list").unwrap();
+    assert!(download < script.find("sample A").unwrap());
+    assert_eq!(script.matches("generate-permit-list").count(), 2);
+    assert!(sb.calls().iter().all(|c| c[1] != "alevin"));
+}
+
+#[test]
+fn visium_quant_keeps_the_spots_under_the_tissue() {
+    let sb = indexed_sandbox(&[]);
+    // AAAA and GGGG are under the tissue, CCCC is not
+    sb.write(
+        "spatial/tissue_positions.csv",
+        "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres\n\
+         AAAA-1,1,0,0,100,200\n\
+         CCCC-1,0,0,2,100,300\n\
+         GGGG-1,1,1,1,150,250\n",
+    );
+    let mut args = quant_args(&["--tissue-positions", "spatial/tissue_positions.csv"]);
+    args[8] = "visium";
+
+    sb.run(&[args.as_slice(), &["-k"]].concat())
+        .assert_failure("cannot be used with");
+    let no_positions: Vec<&str> = quant_args(&["-u"])
+        .into_iter()
+        .map(|a| if a == "10xv3" { "visium" } else { a })
+        .collect();
+    sb.run(&no_positions).assert_failure("--tissue-positions");
+    sb.run(&quant_args(&[
+        "--tissue-positions",
+        "spatial/tissue_positions.csv",
+    ]))
+    .assert_failure("requires the visium chemistry");
+
+    sb.run(&[args.as_slice(), &["--dry-run"]].concat())
+        .assert_failure("--dry-run cannot describe this run: --tissue-positions");
+
+    sb.run(&args).assert_success();
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_flag(map, "--chromiumV3"));
+    let gpl = &sb.calls_of("alevin-fry", "generate-permit-list")[0];
+    assert!(has_arg(gpl, "--unfiltered-pl", "quant/spot_barcodes.txt"));
+    let spots = std::fs::read_to_string(sb.path("quant/spot_barcodes.txt")).unwrap();
+    assert_eq!(spots, "AAAA\nCCCC\nGGGG\n");
+
+    let rows = std::fs::read_to_string(sb.path("quant/in_tissue/quants_mat_rows.txt")).unwrap();
+    assert_eq!(rows, "AAAA\nGGGG\n");
+    let mtx = std::fs::read_to_string(sb.path("quant/in_tissue/quants_mat.mtx")).unwrap();
+    assert!(mtx.ends_with("2 1 1\n1 1 3\n"), "{}", mtx);
+    let positions = std::fs::read_to_string(sb.path("quant/in_tissue/spot_positions.csv")).unwrap();
+    assert_eq!(
+        positions,
+        "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres\n\
+         AAAA,1,0,0,100,200\n\
+         GGGG,1,1,1,150,250\n"
+    );
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["in_tissue"]["num_spots"], 3);
+    assert_eq!(log["in_tissue"]["num_in_tissue_detected"], 1);
+}
+
+#[test]
+fn quant_registered_chemistries() {
+    let sb = indexed_sandbox(&[]);
+    let with_chem = |chem: &'static str, extra: &[&'static str]| -> Vec<&'static str> {
+        let mut args = quant_args(&["--overwrite"]);
+        args[8] = chem;
+        args.extend_from_slice(extra);
+        args
+    };
+
+    // the non-10x chemistries have a default filter
+    sb.run(&with_chem("dropseq", &[])).assert_success();
+    let map = sb.calls_of("salmon", "alevin").pop().unwrap();
+    assert!(has_flag(&map, "--dropseq"));
+    let gpl = sb
+        .calls_of("alevin-fry", "generate-permit-list")
+        .pop()
+        .unwrap();
+    assert!(has_flag(&gpl, "--knee"));
+    assert!(has_arg(&gpl, "-d", "fw"));
+
+    sb.run(&with_chem("dropseq", &["-u"]))
+        .assert_failure("not drawn from a known list");
+    sb.run(&with_chem("10xv3", &[]))
+        .assert_failure("the 10xv3 chemistry has no default");
+
+    // the former salmon flag names still select the registered chemistries
+    sb.run(&with_chem("indropV2", &[])).assert_success();
+    let map = sb.calls_of("salmon", "alevin").pop().unwrap();
+    assert!(has_flag(&map, "--indropV2"));
+    assert!(has_arg(&map, "--w1", "GAGTGATTGCTTGTGACGCCTT"));
+
+    sb.run(&with_chem("indropv3", &[])).assert_success();
+    let map = sb.calls_of("salmon", "alevin").pop().unwrap();
+    assert!(has_arg(&map, "--bc-geometry", "1[1-16]"));
+    assert!(has_arg(&map, "--umi-geometry", "1[17-22]"));
+    // the reads of only one of the two barcodes
+    let mut args = with_chem("indropv3", &[]);
+    args[4] = "reads/short_R1.fq";
+    sb.fastq("reads/short_R1.fq", 10, 8);
+    sb.run(&args).assert_failure(
+        "read 1 of the indropv3 chemistry holds both 8 bp barcodes and the 6 bp UMI, with its two barcode reads concatenated (22 bp), but the median length of the reads in reads/short_R1.fq is 8",
+    );
+
+    let run = sb.run(&with_chem("celseq2", &[]));
+    run.assert_success();
+    assert!(run.stderr().contains(
+        "filtering the cells of the celseq2 chemistry with its default, --forced-cells 96"
+    ));
+    let gpl = sb
+        .calls_of("alevin-fry", "generate-permit-list")
+        .pop()
+        .unwrap();
+    assert!(has_arg(&gpl, "--force", "96"));
+
+    sb.run(&with_chem("splitseqv1", &["--expect-cells", "500"]))
+        .assert_success();
+    let map = sb.calls_of("salmon", "alevin").pop().unwrap();
+    assert!(has_flag(&map, "--splitseqV1"));
+    let gpl = sb
+        .calls_of("alevin-fry", "generate-permit-list")
+        .pop()
+        .unwrap();
+    assert!(has_arg(&gpl, "-d", "both"));
+    assert!(has_arg(&gpl, "--force", "500"));
+
+    sb.run(&with_chem("sciseq3", &["--mapper", "piscem"]))
+        .assert_failure("map its reads with --mapper salmon");
+}
+
+#[test]
+fn quant_10x_5prime_and_v3_1_chemistries() {
+    let sb = indexed_sandbox(&[]);
+    let with_chem = |chem: &'static str, extra: &[&'static str]| -> Vec<&'static str> {
+        let mut args = quant_args(&["--overwrite"]);
+        args[8] = chem;
+        args.extend_from_slice(extra);
+        args
+    };
+
+    // 5' reads are reverse complementary to the transcripts
+    sb.run(&with_chem("10x5v2", &["-k"])).assert_success();
+    let map = sb.calls_of("salmon", "alevin").pop().unwrap();
+    assert!(has_flag(&map, "--chromium"));
+    let gpl = sb
+        .calls_of("alevin-fry", "generate-permit-list")
+        .pop()
+        .unwrap();
+    assert!(has_arg(&gpl, "-d", "rc"));
+
+    sb.run(&with_chem("10xv3.1", &["-k"])).assert_success();
+    let map = sb.calls_of("salmon", "alevin").pop().unwrap();
+    assert!(has_flag(&map, "--chromiumV3"));
+    let gpl = sb
+        .calls_of("alevin-fry", "generate-permit-list")
+        .pop()
+        .unwrap();
+    assert!(has_arg(&gpl, "-d", "fw"));
+
+    // the 5' v2 cells share the permit list of v2
+    let run = sb.run(&with_chem("10x5v2", &["-u", "--dry-run"]));
+    run.assert_success();
+    assert!(run.stdout().contains("plist/10x_v2_permit.txt"));
+
+    // the 5' v3 permit list has to be put in place
+    let plist = sb.af_home().join("plist").join("10x_5v3_permit.txt");
+    let run = sb.run(&with_chem("10x5v3", &["-u"]));
+    run.assert_failure(&format!("has to be placed at {}", plist.display()));
+    run.assert_failure("lib/python/cellranger/barcodes/3M-5pgex-jan-2023.txt.gz");
+    run.assert_failure("must be gunzipped");
+    std::fs::create_dir_all(plist.parent().unwrap()).unwrap();
+    std::fs::write(&plist, "AAAA\nCCCC\n").unwrap();
+    sb.run(&with_chem("10x5v3", &["-u"])).assert_success();
+    let gpl = sb
+        .calls_of("alevin-fry", "generate-permit-list")
+        .pop()
+        .unwrap();
+    assert!(has_arg(
+        &gpl,
+        "--unfiltered-pl",
+        &plist.display().to_string()
+    ));
+    assert!(has_arg(&gpl, "-d", "rc"));
+}
+
+#[test]
+fn quant_sample_sheet_paths_are_relative_to_the_sheet() {
+    let sb = indexed_sandbox(&[]);
+    sb.fastq("sheets/reads/c_R1.fq", 10, 28);
+    sb.fastq("sheets/reads/c_R2.fq", 10, 91);
+    sb.write("sheets/pl.txt", "AAAA\n");
+    sb.write(
+        "sheets/samples.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         C\treads/c_R1.fq\treads/c_R2.fq\t10xv3\texplicit-pl=pl.txt\n",
+    );
+    sb.run(&[
+        "quant",
+        "-i",
+        "index",
+        "--sample-sheet",
+        "sheets/samples.tsv",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "batch",
+    ])
+    .assert_success();
+
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_arg(map, "-1", "sheets/reads/c_R1.fq"));
+    let gpl = &sb.calls_of("alevin-fry", "generate-permit-list")[0];
+    assert!(has_arg(gpl, "--valid-bc", "sheets/pl.txt"));
+
+    sb.write(
+        "sheets/bad.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         C\treads/c_R1.fq\treads/c_R2.fq\t10xv3\tforced-cells=many\n",
+    );
+    sb.run(&[
+        "quant",
+        "-i",
+        "index",
+        "--sample-sheet",
+        "sheets/bad.tsv",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "batch2",
+    ])
+    .assert_failure("invalid filter on line 2");
+}
+
+#[test]
+fn quant_respects_slurm_limits() {
+    let sb = indexed_sandbox(&[]);
+    let out = sb
+        .command(&quant_args(&["-k", "-t", "4"]))
+        .env("SLURM_CPUS_PER_TASK", "1")
+        .env("SLURM_MEM_PER_NODE", "1")
+        .output()
+        .unwrap();
+    let run = Run(out);
+    run.assert_success();
+    assert!(run.stderr().contains("limited by SLURM_CPUS_PER_TASK"));
+    // the stubs use more than the 1 MiB granted
+    assert!(run
+        .stderr()
+        .contains("GiB available (limited by SLURM_MEM_PER_NODE)"));
+
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_arg(map, "--threads", "1"));
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["resource_limits"]["cpus"], 1);
+    assert_eq!(log["resource_limits"]["cpu_source"], "SLURM_CPUS_PER_TASK");
+    assert_eq!(log["resource_limits"]["memory_bytes"], 1024 * 1024);
+    assert_eq!(
+        log["resource_limits"]["memory_source"],
+        "SLURM_MEM_PER_NODE"
+    );
+
+    // SLURM grants all of the memory of the node with 0, and a limit too
+    // large to count is none either
+    for (var, mb) in [
+        ("SLURM_MEM_PER_NODE", "0"),
+        ("SLURM_MEM_PER_CPU", "18446744073709551615"),
+    ] {
+        let out = sb
+            .command(&quant_args(&["-k", "--overwrite"]))
+            .env(var, mb)
+            .output()
+            .unwrap();
+        let run = Run(out);
+        run.assert_success();
+        assert!(!run.stderr().contains("GiB available"), "{}", run.stderr());
+        let log = sb.read_json("quant/simpleaf_quant_log.json");
+        assert_ne!(log["resource_limits"]["memory_source"], var);
+    }
+}
+
+#[test]
+fn quant_sample_sheet_divides_the_step_threads() {
+    let sb = indexed_sandbox(&[]);
+    sb.fastq("reads/b_R1.fq", 10, 28);
+    sb.fastq("reads/b_R2.fq", 10, 91);
+    sb.write(
+        "samples.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         A\treads/s_R1.fq\treads/s_R2.fq\t10xv3\tknee\n\
+         B\treads/b_R1.fq\treads/b_R2.fq\t10xv3\tknee\n",
+    );
+    // the CPUs of this machine, which SLURM grants in full
+    let cpus = std::thread::available_parallelism().unwrap().get();
+    let cpus_arg = cpus.to_string();
+    let out = sb
+        .command(&[
+            "quant",
+            "-i",
+            "index",
+            "--sample-sheet",
+            "samples.tsv",
+            "-r",
+            "cr-like",
+            "-m",
+            "index/index/t2g_3col.tsv",
+            "-o",
+            "batch",
+            "--parallel-samples",
+            "2",
+            "--map-threads",
+            &cpus_arg,
+        ])
+        .env("SLURM_CPUS_PER_TASK", &cpus_arg)
+        .output()
+        .unwrap();
+    Run(out).assert_success();
+
+    // each of the two samples mapped at once gets half of the mapping threads
+    let map_threads = (cpus / 2).max(1).to_string();
+    for map in sb.calls_of("salmon", "alevin") {
+        assert!(has_arg(&map, "--threads", &map_threads));
+    }
+    let summary = sb.read_json("batch/simpleaf_batch_summary.json");
+    assert_eq!(
+        summary["resource_limits"]["cpu_source"],
+        "SLURM_CPUS_PER_TASK"
+    );
+    let log = sb.read_json("batch/A/simpleaf_quant_log.json");
+    assert_eq!(
+        log["resource_limits"]["cpu_source"],
+        "SLURM_CPUS_PER_TASK shared by 2 jobs"
+    );
+}
+
+#[test]
+fn quant_records_an_interrupted_run() {
+    let sb = indexed_sandbox(&[]);
+    let run = sb.run_interrupted(&quant_args(&["-k"]), "alevin-fry collate", "TERM");
+    run.assert_failure("interrupted by SIGTERM during the collate step");
+
+    let marker = std::fs::read_to_string(sb.path("quant/incomplete")).unwrap();
+    assert_eq!(marker, "interrupted by SIGTERM during the collate step\n");
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["status"], "incomplete");
+    assert_eq!(log["interrupted_step"], "collate");
+    assert_eq!(log["completed_steps"], serde_json::json!(["map", "gpl"]));
+    // the step was stopped by the forwarded signal
+    assert_eq!(log["resource_info"]["collate"]["signal"], 15);
+    assert!(sb.calls_of("alevin-fry", "quant").is_empty());
+
+    // a later complete run removes the marker
+    sb.run(&quant_args(&["-k", "--overwrite"])).assert_success();
+    assert!(!sb.path("quant/incomplete").exists());
+}
+
+#[test]
+fn quant_sample_sheet_stops_starting_samples_when_interrupted() {
+    let sb = indexed_sandbox(&[]);
+    sb.fastq("reads/b_R1.fq", 10, 28);
+    sb.fastq("reads/b_R2.fq", 10, 91);
+    sb.write(
+        "samples.tsv",
+        "sample\treads1\treads2\tchemistry\tfilter\n\
+         A\treads/s_R1.fq\treads/s_R2.fq\t10xv3\tknee\n\
+         B\treads/b_R1.fq\treads/b_R2.fq\t10xv3\tknee\n",
+    );
+    let run = sb.run_interrupted(
+        &[
+            "quant",
+            "-i",
+            "index",
+            "--sample-sheet",
+            "samples.tsv",
+            "-r",
+            "cr-like",
+            "-m",
+            "index/index/t2g_3col.tsv",
+            "-o",
+            "batch",
+            "--parallel-samples",
+            "1",
+        ],
+        "salmon alevin",
+        "INT",
+    );
+    run.assert_failure("2 of 2 samples failed");
+
+    assert_eq!(sb.calls_of("salmon", "alevin").len(), 1);
+    let summary = sb.read_json("batch/simpleaf_batch_summary.json");
+    assert_eq!(summary["samples"][0]["status"], "incomplete");
+    assert_eq!(summary["samples"][1]["status"], "not started");
+    assert!(sb.path("batch/A/incomplete").is_file());
+}
@@ -0,0 +1,115 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+fn indexed_sandbox() -> Sandbox {
+    let sb = Sandbox::registered();
+    sb.build_index(&[]).assert_success();
+    sb.fastq("reads/s_R1.fq", 10, 28);
+    sb.fastq("reads/s_R2.fq", 10, 91);
+    sb
+}
+
+#[test]
+fn run_resolves_paths_against_the_config() {
+    let sb = indexed_sandbox();
+    sb.write(
+        "configs/quant.toml",
+        "[quant]\n\
+         index = \"../index\"\n\
+         reads1 = [\"../reads/s_R1.fq\"]\n\
+         reads2 = [\"../reads/s_R2.fq\"]\n\
+         chemistry = \"10xv3\"\n\
+         knee = true\n\
+         resolution = \"cr-like\"\n\
+         t2g_map = \"../index/index/t2g_3col.tsv\"\n\
+         output = \"../quant\"\n\
+         threads = 2\n",
+    );
+    // the overrides are relative to the working directory, as usual
+    sb.run(&["run", "configs/quant.toml", "--threads", "1"])
+        .assert_success();
+
+    let map = &sb.calls_of("salmon", "alevin")[0];
+    assert!(has_arg(map, "--index", "configs/../index"));
+    assert!(has_arg(map, "-1", "configs/../reads/s_R1.fq"));
+    assert!(has_arg(map, "--threads", "1"));
+    let log = sb.read_json("quant/simpleaf_quant_log.json");
+    assert_eq!(log["threads"]["map"], 1);
+
+    sb.write("configs/bad.toml", "[quant]\nno_such_option = 1\n");
+    sb.run(&["run", "configs/bad.toml"])
+        .assert_failure("`no_such_option` is not a field of the `quant` command");
+}
+
+#[test]
+fn emit_config_round_trips_through_run() {
+    let sb = indexed_sandbox();
+    // 10xv2 has no default filtering, but dropseq filters with the knee
+    let run = sb.run(&[
+        "quant",
+        "-i",
+        "index",
+        "-1",
+        "reads/s_R1.fq",
+        "-2",
+        "reads/s_R2.fq",
+        "-c",
+        "dropseq",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "quant",
+        "--emit-config",
+    ]);
+    run.assert_success();
+    assert!(sb.calls_of("salmon", "alevin").is_empty());
+
+    let config: toml::Value = toml::from_str(&run.stdout()).unwrap();
+    let quant = &config["quant"];
+    let root = sb.root().to_str().unwrap();
+    assert_eq!(
+        quant["index"].as_str(),
+        Some(format!("{}/index", root).as_str())
+    );
+    assert_eq!(
+        quant["reads1"][0].as_str(),
+        Some(format!("{}/reads/s_R1.fq", root).as_str())
+    );
+    assert_eq!(quant["mapper"].as_str(), Some("salmon"));
+    assert_eq!(quant["knee"].as_bool(), Some(true));
+    assert!(quant["threads"].as_integer().unwrap() >= 1);
+    assert!(quant.get("emit_config").is_none());
+
+    // the configuration runs from anywhere
+    sb.write("elsewhere/quant.toml", &run.stdout());
+    sb.run(&["run", "elsewhere/quant.toml"]).assert_success();
+    let gpl = &sb.calls_of("alevin-fry", "generate-permit-list")[0];
+    assert!(has_flag(gpl, "--knee"));
+    assert!(sb.path("quant/simpleaf_quant_log.json").is_file());
+
+    // a chemistry without a default filter cannot be resolved
+    sb.run(&[
+        "quant",
+        "-i",
+        "index",
+        "-1",
+        "reads/s_R1.fq",
+        "-2",
+        "reads/s_R2.fq",
+        "-c",
+        "10xv2",
+        "-r",
+        "cr-like",
+        "-m",
+        "index/index/t2g_3col.tsv",
+        "-o",
+        "quant2",
+        "--emit-config",
+    ])
+    .assert_failure("has no default");
+}
@@ -0,0 +1,136 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+#[test]
+fn set_paths_records_the_programs_and_their_versions() {
+    let sb = Sandbox::new();
+    sb.set_paths(&[]).assert_success();
+
+    let info = sb.read_json("af_home/simpleaf_info.json");
+    let progs = &info["prog_info"];
+    assert_eq!(progs["salmon"]["version"], "1.9.0");
+    assert_eq!(progs["alevin_fry"]["version"], "0.7.0");
+    assert_eq!(progs["pyroe"]["version"], "0.6.2");
+    assert_eq!(
+        progs["salmon"]["exe_path"],
+        sb.stub("salmon").to_str().unwrap()
+    );
+    // piscem is optional, and found in the PATH
+    assert_eq!(progs["piscem"]["version"], "0.4.3");
+    assert!(progs["salmon"]["capabilities"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|c| c == "--sketch"));
+}
+
+#[test]
+fn set_paths_rejects_unsupported_versions() {
+    let sb = Sandbox::new();
+    let out = sb
+        .command(&["set-paths", "--salmon", sb.stub("salmon").to_str().unwrap()])
+        .env("STUB_VERSION_salmon", "1.4.0")
+        .output()
+        .unwrap();
+    Run(out).assert_failure("salmon");
+    assert!(!sb.path("af_home/simpleaf_info.json").exists());
+}
+
+#[test]
+fn set_paths_accepts_prerelease_versions() {
+    let sb = Sandbox::new();
+    let out = sb
+        .command(&["set-paths", "--salmon", sb.stub("salmon").to_str().unwrap()])
+        .env("STUB_VERSION_salmon", "v1.10.0-rc1")
+        .output()
+        .unwrap();
+    Run(out).assert_success();
+    let info = sb.read_json("af_home/simpleaf_info.json");
+    assert_eq!(info["prog_info"]["salmon"]["version"], "1.10.0-rc1");
+}
+
+#[test]
+fn index_and_quant_require_set_paths() {
+    let sb = Sandbox::new();
+    sb.build_index(&[]).assert_failure("set-paths");
+    assert!(sb.calls().is_empty());
+}
+
+#[test]
+fn paths_check_reports_changed_versions() {
+    let sb = Sandbox::registered();
+    sb.run(&["paths", "check"]).assert_success();
+
+    let out = sb
+        .command(&["paths", "check"])
+        .env("STUB_VERSION_alevin_fry", "0.8.0")
+        .output()
+        .unwrap();
+    let run = Run(out);
+    run.assert_success();
+    assert!(run.stdout().contains("changed  alevin-fry"));
+}
+
+#[test]
+fn set_paths_skips_an_unusable_optional_program_in_the_path() {
+    let sb = Sandbox::new();
+    let out = sb
+        .command(&["set-paths"])
+        .env("STUB_VERSION_piscem", "0.3.0")
+        .output()
+        .unwrap();
+    let run = Run(out);
+    run.assert_success();
+    assert!(run.stderr().contains("piscem at"));
+    let info = sb.read_json("af_home/simpleaf_info.json");
+    assert!(info["prog_info"]["piscem"].is_null());
+    assert_eq!(info["prog_info"]["salmon"]["version"], "1.9.0");
+
+    // but not one that is asked for
+    let out = sb
+        .command(&["set-paths", "--piscem", sb.stub("piscem").to_str().unwrap()])
+        .env("STUB_VERSION_piscem", "0.3.0")
+        .output()
+        .unwrap();
+    Run(out).assert_failure("piscem at");
+
+    // a refresh of every program leaves it as it is
+    sb.set_paths(&[]).assert_success();
+    let out = sb
+        .command(&["paths", "refresh"])
+        .env("STUB_VERSION_piscem", "0.3.0")
+        .output()
+        .unwrap();
+    Run(out).assert_success();
+    let info = sb.read_json("af_home/simpleaf_info.json");
+    assert_eq!(info["prog_info"]["piscem"]["version"], "0.4.3");
+    let out = sb
+        .command(&["paths", "refresh", "piscem"])
+        .env("STUB_VERSION_piscem", "0.3.0")
+        .output()
+        .unwrap();
+    Run(out).assert_failure("piscem at");
+}
+
+#[test]
+fn paths_refresh_keeps_an_unreadable_record() {
+    let sb = Sandbox::new();
+    // without a record, refresh records every program
+    sb.run(&["paths", "refresh"]).assert_success();
+    assert_eq!(
+        sb.read_json("af_home/simpleaf_info.json")["prog_info"]["salmon"]["version"],
+        "1.9.0"
+    );
+
+    sb.write(
+        "af_home/simpleaf_info.json",
+        "{\"prog_info\": {\"salmon\": ",
+    );
+    sb.run(&["paths", "refresh", "salmon"])
+        .assert_failure("fix or remove");
+    let kept = std::fs::read_to_string(sb.path("af_home/simpleaf_info.json")).unwrap();
+    assert_eq!(kept, "{\"prog_info\": {\"salmon\": ");
+}